@@ -0,0 +1,9 @@
+/// compiles the protobuf of the gRPC API, with the vendored protoc so no system install is needed
+fn main () {
+    std::env::set_var ("PROTOC", protoc_bin_vendored::protoc_bin_path ().expect ("no vendored protoc for this platform"));
+    // only the server is served, the generated client needs edition 2021
+    tonic_build::configure ()
+        .build_client (false)
+        .compile_protos (&["proto/values.proto"], &["proto"])
+        .expect ("Could not compile proto/values.proto");
+}
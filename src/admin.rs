@@ -1,39 +1,285 @@
 use rdkafka::admin::AdminClient;
-use rdkafka::client::DefaultClientContext;
 use rdkafka::admin::AdminOptions;
 use std::sync::Arc;
-use rdkafka::config::{ClientConfig, RDKafkaLogLevel};
-use rdkafka::admin::NewTopic;
+use rdkafka::config::RDKafkaLogLevel;
+use rdkafka::admin::{AlterConfig, ConfigResource, ConfigSource, NewPartitions, NewTopic, ResourceSpecifier};
 use rdkafka::admin::TopicReplication;
-use log::info;
+use rdkafka::types::RDKafkaErrorCode;
+use log::{info, warn, error};
+use std::collections::HashMap;
+use std::time::Duration;
+use crate::config::{Config, TopicSpec};
+use crate::context::CustomContext;
+use crate::context;
 
-pub type KafkaAdmin = Arc<AdminClient<DefaultClientContext>>;
+pub type KafkaAdmin = Arc<AdminClient<CustomContext>>;
 
-pub fn init (broker : &str) -> KafkaAdmin {
+/// delay between two provisioning attempts while the brokers are unavailable
+const RETRY_INTERVAL : Duration = Duration::from_secs (5);
 
-    let context = DefaultClientContext;
+pub fn init (config : &Config) -> KafkaAdmin {
 
-    let admin = ClientConfig::new ()
-        .set("bootstrap.servers", broker)
-        .set_log_level(RDKafkaLogLevel::Debug)
+    let context = context::init ("admin", config, None);
+
+    let mut client_config = context::client_config (config);
+    client_config
+        .set_log_level(RDKafkaLogLevel::Debug);
+
+    context::apply_properties (&mut client_config, config, &config.kafka_admin);
+
+    let admin = client_config
         .create_with_context(context)
         .expect ("Admin creation failed");
 
     Arc::new(admin)
 }
 
-pub async fn create_topic (admin: KafkaAdmin, topic_id : &str) {
+/// reconciles the topics with their spec, retrying until the brokers accept it
+pub async fn run (admin: KafkaAdmin, config: Arc<Config>) {
+
+    loop {
+        match provision (admin.clone (), &config).await {
+            Ok (()) => break,
+            Err (why) => {
+                error!("Could not provision topics, retrying in {:?} : {}", RETRY_INTERVAL, why);
+                tokio::time::sleep (RETRY_INTERVAL).await;
+            }
+        }
+    }
+}
+
+/// creates the missing topics and checks the existing ones against their spec,
+/// drift is altered when `KAFKA_TOPIC_DRIFT=alter` and only reported otherwise
+pub async fn provision (admin: KafkaAdmin, config: &Config) -> Result<(), String> {
+
+    let existing = describe_topics (admin.clone (), Duration::from_millis (config.health_broker_timeout_ms)).await?;
+
+    let (present, missing) : (Vec<&TopicSpec>, Vec<&TopicSpec>) = config.topics.iter ()
+        .partition (|spec| existing.contains_key (&spec.name));
+
+    let mut errors = create_topics (&admin, &missing).await?;
+
+    for spec in present {
+        let (partitions, replication_factor) = existing[&spec.name];
+        if let Err (why) = reconcile_topic (&admin, spec, partitions, replication_factor, config.topic_drift == "alter").await {
+            errors.push (why);
+        }
+    }
+
+    match errors.is_empty () {
+        true => Ok (()),
+        false => Err (errors.join (", "))
+    }
+}
+
+/// partition count and replication factor of the topics known to the cluster
+async fn describe_topics (admin: KafkaAdmin, timeout: Duration) -> Result<HashMap<String, (usize, usize)>, String> {
+
+    tokio::task::spawn_blocking (move || {
+        admin.inner ().fetch_metadata (None, timeout)
+            .map (|metadata| metadata.topics ().iter ()
+                  .map (|topic| (String::from (topic.name ()),
+                                 (topic.partitions ().len (),
+                                  topic.partitions ().first ().map (|p| p.replicas ().len ()).unwrap_or (0))))
+                  .collect ())
+            .map_err (|why| why.to_string ())
+    }).await.map_err (|why| why.to_string ())?
+}
+
+/// creates the topics, returns the topics that failed, a topic created concurrently by another instance is not an error
+async fn create_topics (admin: &KafkaAdmin, specs: &[&TopicSpec]) -> Result<Vec<String>, String> {
+
+    if specs.is_empty () {
+        return Ok (Vec::new ());
+    }
+
+    let configs : Vec<Vec<(&str, String)>> = specs.iter ().map (|spec| spec.configs ()).collect ();
+    let topics : Vec<NewTopic> = specs.iter ().zip (&configs)
+        .map (|(spec, configs)| configs.iter ()
+              .fold (NewTopic::new (&spec.name, spec.partitions, TopicReplication::Fixed (spec.replication_factor)),
+                     |topic, (key, value)| topic.set (key, value)))
+        .collect ();
+
+    let results = admin.create_topics (&topics, &AdminOptions::new ()).await
+        .map_err (|why| format!("could not create topics: {}", why))?;
+
+    let mut errors = Vec::new ();
+    for result in results {
+        match result {
+            Ok (topic) => info!("Created topic {}", topic),
+            Err ((topic, RDKafkaErrorCode::TopicAlreadyExists)) => info!("Topic {} already exists", topic),
+            Err ((topic, code)) => errors.push (format!("could not create topic {}: {}", topic, code))
+        }
+    }
+    Ok (errors)
+}
+
+/// compares an existing topic with its spec, `alter` applies the differences that Kafka can change in place
+async fn reconcile_topic (admin: &KafkaAdmin,
+                          spec: &TopicSpec,
+                          partitions: usize,
+                          replication_factor: usize,
+                          alter: bool) -> Result<(), String> {
 
-    let admin = &*admin;
+    let wanted_partitions = spec.partitions as usize;
+    if let Some (wanted_partitions) = added_partitions (spec, partitions, alter) {
+        // NOTE : new partitions change which partition a key is written to, ordering per key only holds for new messages
+        let new_partitions = NewPartitions::new (&spec.name, wanted_partitions);
+        match admin.create_partitions (&[new_partitions], &AdminOptions::new ()).await {
+            Ok (results) => match results.into_iter ().next () {
+                Some (Err ((topic, code))) => return Err (format!("could not add partitions to topic {}: {}", topic, code)),
+                _ => info!("Topic {}: increased partitions from {} to {}", spec.name, partitions, wanted_partitions)
+            },
+            Err (why) => return Err (format!("could not add partitions to topic {}: {}", spec.name, why))
+        }
+    } else if partitions != wanted_partitions {
+        warn!("Topic {}: has {} partitions, the spec wants {}{}", spec.name, partitions, wanted_partitions,
+              if partitions > wanted_partitions { ", partitions cannot be removed" } else { "" });
+    }
 
-    let topic = NewTopic::new (topic_id, 1, TopicReplication::Fixed (3));
+    if replication_factor != spec.replication_factor as usize {
+        warn!("Topic {}: has replication factor {}, the spec wants {}, reassign the partitions to change it",
+              spec.name, replication_factor, spec.replication_factor);
+    }
 
-    match admin.create_topics (
-        vec![&topic],
-        &AdminOptions::new ()
-    ).await {
-        Err (why) => panic!("could not create topic {} {:?}", topic_id, why),
-        Ok (_) => info!("created topic {}", topic_id)
+    let resource = ResourceSpecifier::Topic (&spec.name);
+    let described = admin.describe_configs (&[resource], &AdminOptions::new ()).await
+        .map_err (|why| format!("could not describe topic {}: {}", spec.name, why))?;
+    let current = match described.into_iter ().next () {
+        Some (Ok (current)) => current,
+        Some (Err (code)) => return Err (format!("could not describe topic {}: {}", spec.name, code)),
+        None => return Err (format!("could not describe topic {}: no result", spec.name))
     };
 
+    let configs = spec.configs ();
+    let drift = drift (&configs, &current);
+
+    if drift.is_empty () {
+        info!("Topic {} matches its spec", spec.name);
+        return Ok (());
+    }
+
+    for (key, value) in &drift {
+        let actual = current.get (key).and_then (|entry| entry.value.clone ()).unwrap_or_default ();
+        warn!("Topic {}: {} is {}, the spec wants {}", spec.name, key, actual, value);
+    }
+
+    if !alter {
+        return Ok (());
+    }
+
+    let alter_config = altered_configs (&configs, &current).into_iter ()
+        .fold (AlterConfig::new (resource), |alter_config, (key, value)| alter_config.set (key, value));
+
+    match admin.alter_configs (&[alter_config], &AdminOptions::new ()).await {
+        Ok (results) => match results.into_iter ().next () {
+            Some (Err ((_, code))) => Err (format!("could not alter topic {}: {}", spec.name, code)),
+            _ => {
+                info!("Topic {}: altered {} config(s) to match its spec", spec.name, drift.len ());
+                Ok (())
+            }
+        },
+        Err (why) => Err (format!("could not alter topic {}: {}", spec.name, why))
+    }
+}
+
+/// the partition count to grow the topic to, when it has fewer partitions than its spec and drift is altered
+fn added_partitions (spec: &TopicSpec, partitions: usize, alter: bool) -> Option<usize> {
+    let wanted_partitions = spec.partitions as usize;
+    match partitions < wanted_partitions && alter {
+        true => Some (wanted_partitions),
+        false => None
+    }
+}
+
+/// the configs of the spec that differ from the topic's
+fn drift<'a> (configs: &'a [(&'static str, String)], current: &ConfigResource) -> Vec<&'a (&'static str, String)> {
+    configs.iter ()
+        .filter (|(key, value)| current.get (key).and_then (|entry| entry.value.as_ref ()) != Some (value))
+        .collect ()
+}
+
+/// the configs of the spec, and the dynamic configs of the topic outside of the spec,
+/// since altering replaces all the topic's dynamic configs
+fn altered_configs<'a> (configs: &'a [(&'static str, String)], current: &'a ConfigResource) -> Vec<(&'a str, &'a str)> {
+    let kept = current.entries.iter ()
+        .filter (|entry| entry.source == ConfigSource::DynamicTopic)
+        .filter (|entry| !configs.iter ().any (|(key, _)| *key == entry.name))
+        .filter_map (|entry| entry.value.as_deref ().map (|value| (entry.name.as_str (), value)));
+
+    configs.iter ()
+        .map (|(key, value)| (*key, value.as_str ()))
+        .chain (kept)
+        .collect ()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rdkafka::admin::{ConfigEntry, OwnedResourceSpecifier};
+
+    fn spec () -> TopicSpec {
+        TopicSpec {
+            name: String::from ("events"),
+            partitions: 3,
+            replication_factor: 1,
+            retention_ms: Some (-1),
+            cleanup_policy: Some (String::from ("delete")),
+            min_insync_replicas: None,
+        }
+    }
+
+    /// the configs of a topic, by name, value and whether they were set on the topic
+    fn topic (entries: &[(&str, &str, bool)]) -> ConfigResource {
+        ConfigResource {
+            specifier: OwnedResourceSpecifier::Topic (String::from ("events")),
+            entries: entries.iter ()
+                .map (|(name, value, dynamic)| ConfigEntry {
+                    name: String::from (*name),
+                    value: Some (String::from (*value)),
+                    source: match dynamic {
+                        true => ConfigSource::DynamicTopic,
+                        false => ConfigSource::Default
+                    },
+                    is_read_only: false,
+                    is_default: !dynamic,
+                    is_sensitive: false,
+                })
+                .collect (),
+        }
+    }
+
+    #[test]
+    fn grows_the_partitions_only_when_altering () {
+        assert_eq!(added_partitions (&spec (), 1, true), Some (3));
+        assert_eq!(added_partitions (&spec (), 1, false), None);
+        assert_eq!(added_partitions (&spec (), 3, true), None);
+        assert_eq!(added_partitions (&spec (), 6, true), None);
+    }
+
+    #[test]
+    fn finds_the_configs_that_differ_from_the_spec () {
+        let configs = spec ().configs ();
+        let matching = topic (&[("retention.ms", "-1", true),
+                                ("cleanup.policy", "delete", false)]);
+        assert!(drift (&configs, &matching).is_empty ());
+
+        let drifted = topic (&[("retention.ms", "604800000", false),
+                               ("cleanup.policy", "delete", false)]);
+        assert_eq!(drift (&configs, &drifted), vec![&("retention.ms", String::from ("-1"))]);
+
+        let missing = topic (&[]);
+        assert_eq!(drift (&configs, &missing).len (), 2);
+    }
+
+    #[test]
+    fn keeps_the_dynamic_configs_outside_of_the_spec () {
+        let configs = spec ().configs ();
+        let current = topic (&[("retention.ms", "604800000", true),
+                               ("max.message.bytes", "2097152", true),
+                               ("segment.bytes", "1073741824", false),
+                               ("compression.type", "zstd", false)]);
+        assert_eq!(altered_configs (&configs, &current),
+                   vec![("retention.ms", "-1"), ("cleanup.policy", "delete"), ("max.message.bytes", "2097152")]);
+    }
 }
@@ -0,0 +1,365 @@
+use crate::admin::KafkaAdmin;
+use crate::config::Config;
+use crate::consumer;
+use crate::errors::ApiError;
+use chrono::{DateTime, Utc};
+use log::info;
+use rdkafka::admin::{AdminOptions, ResourceSpecifier};
+use rdkafka::consumer::{BaseConsumer, CommitMode, Consumer};
+use rdkafka::metadata::{Metadata, MetadataPartition};
+use rdkafka::topic_partition_list::{Offset, TopicPartitionList};
+use rdkafka::types::RDKafkaErrorCode;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::Duration;
+use utoipa::ToSchema;
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct TopicSummary {
+    name: String,
+    partitions: usize,
+    replication_factor: usize,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct PartitionDescription {
+    id: i32,
+    leader: i32,
+    replicas: Vec<i32>,
+    isr: Vec<i32>,
+    low_watermark: i64,
+    high_watermark: i64,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct TopicDescription {
+    name: String,
+    partitions: Vec<PartitionDescription>,
+    /// topic level configs, sensitive values are hidden
+    configs: BTreeMap<String, Option<String>>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct PartitionLag {
+    partition: i32,
+    /// `None` when the group has not committed an offset for the partition
+    committed: Option<i64>,
+    high_watermark: i64,
+    lag: i64,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct GroupLag {
+    group_id: String,
+    topic: String,
+    lag: i64,
+    partitions: Vec<PartitionLag>,
+}
+
+/// where to move the group's offsets, e.g. {"to" : "timestamp", "timestamp" : "2021-01-01T00:00:00Z"}
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(tag = "to", rename_all = "lowercase")]
+pub enum OffsetReset {
+    Earliest,
+    Latest,
+    Timestamp { timestamp: DateTime<Utc> },
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct PartitionOffset {
+    topic: String,
+    partition: i32,
+    offset: i64,
+}
+
+type AdminResult<T> = Result<T, ApiError>;
+
+/// GET /admin/topics
+pub async fn list_topics (config: Config, admin: KafkaAdmin) -> Result<impl warp::Reply, warp::Rejection> {
+    let result = blocking (move || {
+        let metadata = admin.inner ().fetch_metadata (None, timeout (&config))?;
+        let topics : Vec<TopicSummary> = metadata.topics ().iter ()
+            .filter (|topic| !topic.name ().starts_with ("__"))
+            .map (|topic| TopicSummary {
+                name: String::from (topic.name ()),
+                partitions: topic.partitions ().len (),
+                replication_factor: topic.partitions ().first ().map (|p| p.replicas ().len ()).unwrap_or (0),
+            })
+            .collect ();
+        Ok (topics)
+    }).await;
+    reply (result)
+}
+
+/// GET /admin/topics/:name
+pub async fn describe_topic (name: String, config: Config, admin: KafkaAdmin) -> Result<impl warp::Reply, warp::Rejection> {
+    let result = describe (name, config, admin).await;
+    reply (result)
+}
+
+async fn describe (name: String, config: Config, admin: KafkaAdmin) -> AdminResult<TopicDescription> {
+
+    let metadata_admin = admin.clone ();
+    let metadata_config = config.clone ();
+    let topic = name.clone ();
+    let partitions = blocking (move || {
+        let timeout = timeout (&metadata_config);
+        let client = metadata_admin.inner ();
+        let metadata = client.fetch_metadata (Some (&topic), timeout)?;
+        partitions_of (&metadata, &topic)?.iter ()
+            .map (|partition| {
+                let (low_watermark, high_watermark) = client.fetch_watermarks (&topic, partition.id (), timeout)?;
+                Ok (PartitionDescription {
+                    id: partition.id (),
+                    leader: partition.leader (),
+                    replicas: partition.replicas ().to_vec (),
+                    isr: partition.isr ().to_vec (),
+                    low_watermark,
+                    high_watermark,
+                })
+            })
+            .collect::<AdminResult<Vec<PartitionDescription>>> ()
+    }).await?;
+
+    let options = AdminOptions::new ().request_timeout (Some (timeout (&config)));
+    let described = admin.describe_configs (&[ResourceSpecifier::Topic (&name)], &options).await?;
+    let configs = match described.into_iter ().next () {
+        Some (Ok (resource)) => resource.entries.into_iter ()
+            .map (|entry| (entry.name, if entry.is_sensitive { None } else { entry.value }))
+            .collect (),
+        Some (Err (code)) => return Err (ApiError::Unavailable (code.to_string ())),
+        None => BTreeMap::new ()
+    };
+
+    Ok (TopicDescription { name, partitions, configs })
+}
+
+/// GET /admin/groups
+pub async fn groups_lag (config: Config) -> Result<impl warp::Reply, warp::Rejection> {
+    let groups = [(config.commands_group_id.clone (), config.commands_topics ()),
+                  (config.events_group_id.clone (), config.events_topics ())];
+    let result = blocking (move || {
+        groups.iter ()
+            .flat_map (|(group_id, topics)| topics.iter ().map (move |topic| (group_id, topic)))
+            .map (|(group_id, topic)| lag (&config, group_id, topic))
+            .collect::<AdminResult<Vec<GroupLag>>> ()
+    }).await;
+    reply (result)
+}
+
+/// GET /admin/groups/:id, one entry per topic of the group
+pub async fn group_lag (group_id: String, config: Config) -> Result<impl warp::Reply, warp::Rejection> {
+    let result = match group_topics (&config, &group_id) {
+        Ok (topics) => blocking (move || {
+            topics.iter ()
+                .map (|topic| lag (&config, &group_id, topic))
+                .collect::<AdminResult<Vec<GroupLag>>> ()
+        }).await,
+        Err (why) => Err (why)
+    };
+    reply (result)
+}
+
+/// POST /admin/groups/:id/offsets {"to" : "earliest" | "latest" | "timestamp", "timestamp" : ... }
+/// commits the new offsets for the group, which only works while none of its consumers is running
+pub async fn reset_offsets (group_id: String, reset: OffsetReset, config: Config) -> Result<impl warp::Reply, warp::Rejection> {
+    let result = match resettable_topics (&config, &group_id) {
+        Ok (topics) => blocking (move || {
+            let mut offsets = Vec::new ();
+            for topic in &topics {
+                offsets.extend (reset_group (&config, &group_id, topic, &reset)?);
+            }
+            Ok (offsets)
+        }).await,
+        Err (why) => Err (why)
+    };
+    reply (result)
+}
+
+/// the topics consumed by one of the application's groups, one per tenant with per tenant topics
+fn group_topics (config: &Config, group_id: &str) -> AdminResult<Vec<String>> {
+    match group_id {
+        id if id == config.commands_group_id => Ok (config.commands_topics ()),
+        id if id == config.events_group_id => Ok (config.events_topics ()),
+        _ => Err (ApiError::NotFound (format!("unknown consumer group {}", group_id)))
+    }
+}
+
+/// the topics of a group whose consumers start from its committed offsets,
+/// the command processors replay their topics from the beginning whatever the group committed
+fn resettable_topics (config: &Config, group_id: &str) -> AdminResult<Vec<String>> {
+    match group_topics (config, group_id)? {
+        _ if group_id == config.commands_group_id =>
+            Err (ApiError::Conflict (format!("group {} replays its topics from the beginning, its offsets cannot be reset", group_id))),
+        topics => Ok (topics)
+    }
+}
+
+fn lag (config: &Config, group_id: &str, topic: &str) -> AdminResult<GroupLag> {
+
+    let timeout = timeout (config);
+    let client = consumer::group_client (config, group_id);
+    let tpl = partition_list (&client, topic, timeout, Offset::Invalid)?;
+    let committed = client.committed_offsets (tpl, timeout)?;
+
+    let partitions = committed.elements ().iter ()
+        .map (|element| {
+            let (low_watermark, high_watermark) = client.fetch_watermarks (topic, element.partition (), timeout)?;
+            let committed = element.offset ().to_raw ().filter (|offset| *offset >= 0);
+            let lag = high_watermark - committed.unwrap_or (low_watermark);
+            Ok (PartitionLag { partition: element.partition (), committed, high_watermark, lag })
+        })
+        .collect::<AdminResult<Vec<PartitionLag>>> ()?;
+
+    Ok (GroupLag {
+        group_id: String::from (group_id),
+        topic: String::from (topic),
+        lag: partitions.iter ().map (|partition| partition.lag).sum (),
+        partitions,
+    })
+}
+
+fn reset_group (config: &Config, group_id: &str, topic: &str, reset: &OffsetReset) -> AdminResult<Vec<PartitionOffset>> {
+
+    let timeout = timeout (config);
+    let client = consumer::group_client (config, group_id);
+
+    let offsets = match reset {
+        OffsetReset::Timestamp { timestamp } => {
+            let tpl = partition_list (&client, topic, timeout, Offset::Offset (timestamp.timestamp_millis ()))?;
+            let found = client.offsets_for_times (tpl, timeout)?;
+            // partitions without a message at or after the timestamp move to their end
+            found.elements ().iter ()
+                .map (|element| match element.offset () {
+                    Offset::Offset (offset) => Ok ((element.partition (), offset)),
+                    _ => Ok ((element.partition (), client.fetch_watermarks (topic, element.partition (), timeout)?.1))
+                })
+                .collect::<AdminResult<Vec<(i32, i64)>>> ()?
+        },
+        OffsetReset::Earliest | OffsetReset::Latest => {
+            let tpl = partition_list (&client, topic, timeout, Offset::Invalid)?;
+            tpl.elements ().iter ()
+                .map (|element| {
+                    let (low, high) = client.fetch_watermarks (topic, element.partition (), timeout)?;
+                    match reset {
+                        OffsetReset::Earliest => Ok ((element.partition (), low)),
+                        _ => Ok ((element.partition (), high))
+                    }
+                })
+                .collect::<AdminResult<Vec<(i32, i64)>>> ()?
+        }
+    };
+
+    let mut tpl = TopicPartitionList::new ();
+    for (partition, offset) in &offsets {
+        tpl.add_partition_offset (topic, *partition, Offset::Offset (*offset))?;
+    }
+
+    match client.commit (&tpl, CommitMode::Sync) {
+        Ok (()) => info!("Reset offsets of group {} on {} to {:?}", group_id, topic, offsets),
+        Err (why) => return match why.rdkafka_error_code () {
+            Some (RDKafkaErrorCode::UnknownMemberId) |
+            Some (RDKafkaErrorCode::IllegalGeneration) |
+            Some (RDKafkaErrorCode::RebalanceInProgress) =>
+                Err (ApiError::Conflict (format!("group {} has active members, stop its consumers before resetting its offsets", group_id))),
+            _ => Err (why.into ())
+        }
+    };
+
+    Ok (offsets.into_iter ().map (|(partition, offset)| PartitionOffset { topic: String::from (topic), partition, offset }).collect ())
+}
+
+/// all the partitions of the topic, with the same offset
+fn partition_list (client: &BaseConsumer<impl rdkafka::consumer::ConsumerContext>,
+                   topic: &str,
+                   timeout: Duration,
+                   offset: Offset) -> AdminResult<TopicPartitionList> {
+    let metadata = client.fetch_metadata (Some (topic), timeout)?;
+    let mut tpl = TopicPartitionList::new ();
+    for partition in partitions_of (&metadata, topic)? {
+        tpl.add_partition_offset (topic, partition.id (), offset)?;
+    }
+    Ok (tpl)
+}
+
+/// the topic's partitions, not found when the cluster does not know the topic
+fn partitions_of<'a> (metadata: &'a Metadata, topic: &str) -> AdminResult<&'a [MetadataPartition]> {
+    match metadata.topics ().iter ().find (|t| t.name () == topic) {
+        Some (t) if t.error ().is_none () => Ok (t.partitions ()),
+        _ => Err (ApiError::NotFound (format!("unknown topic {}", topic)))
+    }
+}
+
+fn timeout (config: &Config) -> Duration {
+    Duration::from_millis (config.admin_timeout_ms)
+}
+
+/// runs the blocking librdkafka calls off the async runtime
+async fn blocking<T, F> (f: F) -> AdminResult<T>
+where F: FnOnce () -> AdminResult<T> + Send + 'static, T: Send + 'static {
+    match tokio::task::spawn_blocking (f).await {
+        Ok (result) => result,
+        Err (why) => Err (ApiError::Internal (why.to_string ()))
+    }
+}
+
+fn reply<T: serde::Serialize> (result: AdminResult<T>) -> Result<warp::reply::Json, warp::Rejection> {
+    result
+        .map (|body| warp::reply::json (&body))
+        .map_err (warp::reject::custom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::tests::config;
+
+    fn settings () -> Config {
+        config (&["KAFKA_COMMANDS_GROUP_ID=commands-processors", "KAFKA_EVENTS_GROUP_ID=events-processors",
+                  "KAFKA_COMMANDS_TOPICS=commands", "KAFKA_EVENTS_TOPICS=events",
+                  "TENANTS=acme,globex", "TENANT_TOPIC_PREFIX=true"])
+    }
+
+    /// the error the handler rejected the request with
+    fn rejection (result: Result<impl warp::Reply, warp::Rejection>) -> ApiError {
+        match result {
+            Ok (_) => panic!("request accepted"),
+            Err (rejection) => rejection.find::<ApiError> ().cloned ().expect ("api error")
+        }
+    }
+
+    #[test]
+    fn knows_the_topics_of_the_groups () {
+        let config = settings ();
+        assert_eq!(group_topics (&config, "commands-processors").unwrap (), vec!["acme.commands", "globex.commands"]);
+        assert_eq!(group_topics (&config, "events-processors").unwrap (), vec!["acme.events", "globex.events"]);
+        assert!(matches!(group_topics (&config, "other"), Err (ApiError::NotFound (_))));
+    }
+
+    #[test]
+    fn resets_only_the_groups_that_resume_from_their_offsets () {
+        let config = settings ();
+        assert_eq!(resettable_topics (&config, "events-processors").unwrap (), vec!["acme.events", "globex.events"]);
+        assert!(matches!(resettable_topics (&config, "commands-processors"), Err (ApiError::Conflict (_))));
+        assert!(matches!(resettable_topics (&config, "other"), Err (ApiError::NotFound (_))));
+    }
+
+    #[tokio::test]
+    async fn refuses_to_reset_the_commands_group () {
+        let result = reset_offsets (String::from ("commands-processors"), OffsetReset::Earliest, settings ()).await;
+        assert!(matches!(rejection (result), ApiError::Conflict (_)));
+        let result = reset_offsets (String::from ("other"), OffsetReset::Latest, settings ()).await;
+        assert!(matches!(rejection (result), ApiError::NotFound (_)));
+    }
+
+    #[test]
+    fn reads_the_offset_resets () {
+        let reset = |body: &str| serde_json::from_str::<OffsetReset> (body).map (|reset| format!("{:?}", reset));
+        assert_eq!(reset (r#"{"to": "earliest"}"#).unwrap (), "Earliest");
+        assert_eq!(reset (r#"{"to": "latest"}"#).unwrap (), "Latest");
+        assert_eq!(reset (r#"{"to": "timestamp", "timestamp": "2021-01-01T00:00:00Z"}"#).unwrap (),
+                   "Timestamp { timestamp: 2021-01-01T00:00:00Z }");
+        assert!(reset (r#"{"to": "timestamp"}"#).is_err ());
+        assert!(reset (r#"{"to": "never"}"#).is_err ());
+    }
+}
@@ -0,0 +1,73 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fmt::Debug;
+use std::future::Future;
+use uuid::Uuid;
+
+/// a command read from the commands topic, addressed to one instance of an aggregate
+pub trait AggregateCommand: Serialize + DeserializeOwned + Debug + Send + Sync {
+    /// command type, used as metrics label
+    fn name (&self) -> &'static str;
+
+    fn id (&self) -> Uuid;
+
+    fn tenant (&self) -> &str;
+
+    /// id of the aggregate instance the command is for
+    fn aggregate_id (&self) -> Uuid;
+}
+
+/// an event written to the events topic by the command processor
+pub trait AggregateEvent: Serialize + DeserializeOwned + Debug + Send + Sync {
+    /// event type, used as metrics label
+    fn name (&self) -> &'static str;
+
+    fn id (&self) -> Uuid;
+
+    /// id of the command that caused the event
+    fn parent (&self) -> Uuid;
+
+    fn tenant (&self) -> &str;
+
+    /// id of the aggregate instance the event happened to
+    fn aggregate_id (&self) -> Uuid;
+}
+
+/// an event sourced aggregate, its state is the fold of its events with `apply`,
+/// the command processor keeps one per tenant and aggregate id, see `command_processor::run`
+pub trait Aggregate: Default + Send + Sync {
+    type Command: AggregateCommand;
+    type Event: AggregateEvent;
+
+    /// the events of the command, or why it is rejected,
+    /// an aggregate that was never applied an event is its `default ()`
+    fn handle (&self, command: &Self::Command) -> Result<Vec<Self::Event>, String>;
+
+    /// the state after the event, called once the event is written to the events topic
+    fn apply (&mut self, event: &Self::Event);
+
+    /// the event recording that `handle` rejected the command, it is written but not applied
+    fn rejected (command: &Self::Command, reason: String) -> Self::Event;
+
+    /// the name the command gives its aggregate, the command processor rejects the command
+    /// when another aggregate of the tenant has that name
+    fn unique_name (_command: &Self::Command) -> Option<&str> {
+        None
+    }
+
+    /// the name of the aggregate, unique among the aggregates of its tenant, see `unique_name`
+    fn name (&self) -> Option<&str> {
+        None
+    }
+}
+
+/// a read model built from the events of an aggregate, see `projection::run`
+pub trait Projection: Send + Sync {
+    type Event: AggregateEvent;
+
+    /// name of the projection's consumer, in the health checks and metrics
+    const NAME: &'static str;
+
+    /// updates the read model with the event
+    fn apply (&self, event: Self::Event) -> impl Future<Output = ()> + Send;
+}
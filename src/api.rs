@@ -1,70 +1,755 @@
+use crate::admin::KafkaAdmin;
+use crate::admin_api::{GroupLag, OffsetReset, PartitionOffset, TopicDescription, TopicSummary};
+use crate::admin_api;
+use crate::auth::{Auth, Principal};
+use crate::auth;
 use crate::commands;
+use crate::commands_schema::Value as ValueBody;
+use crate::errors::Problem;
+use crate::errors;
+use crate::graphql::ApiSchema;
+use crate::graphql;
+use crate::group_view::{Group, Groups};
+use crate::grpc;
+use crate::inputs_schema::{Labels, ScheduleInput, TransferInput, ValueInput, ValueOperationInput};
+use crate::openapi;
 use crate::queries;
+use crate::rate_limit::RateLimiter;
+use crate::rate_limit;
 use crate::config::{Config};
 use crate::db::Db;
+use crate::envelope;
+use crate::health::Health;
+use crate::health;
+use crate::materialized_view::Changes;
+use crate::metrics;
 use crate::producer::Producer;
 use crate::producer;
+use crate::scheduler::{Schedule, Schedules};
+use crate::transfer::{TransferView, Transfers};
+use crate::view_client::ViewClient;
+use crate::view_client;
 use std::convert::Infallible;
+use std::net::IpAddr;
 use std::sync::Arc;
+use std::time::Duration;
+use utoipa::openapi::path::HttpMethod;
 use uuid::Uuid;
-use warp::Filter;
-use crate::admin;
-use crate::admin::KafkaAdmin;
+use warp::filters::BoxedFilter;
+use warp::{Filter, Reply};
+
+pub type Routes = BoxedFilter<(Box<dyn Reply>,)>;
+
+/// a path template served by the routes and its methods, as in the `#[utoipa::path]` of the route
+pub type Operation = (String, Vec<HttpMethod>);
+
+/// the read models of this process, `None` for the ones its role does not run
+#[derive(Clone)]
+pub struct Views {
+    /// the materialized view of the values
+    pub db: Option<Db>,
+    pub changes: Changes,
+    /// the transfers of the process manager
+    pub transfers: Option<Transfers>,
+    /// the schedules of the scheduler
+    pub schedules: Option<Schedules>,
+    /// the totals of the value groups, next to the materialized view
+    pub groups: Option<Groups>,
+}
+
+/// serves the routes until the process stops, and the gRPC service next to them when GRPC_PORT is set
+/// errors are answered with problem details, see `errors`
+pub async fn run (config: Arc<Config>, views: Views, admin: KafkaAdmin, health: Health, auth: Auth) {
+
+    let config = &*config;
+    let commands = commands (config);
+
+    let grpc = grpc::serve (config.clone (), auth.clone (), views.db.clone (), views.changes.clone (), commands.clone ());
+
+    let (routes, operations) = routes (config, views, commands, admin, health, auth);
+    let paths : Arc<Vec<String>> = Arc::new (operations.into_iter ().map (|(path, _)| path).collect ());
+
+    let routes = routes
+        .recover (errors::recover)
+        .with (warp::log::custom (move |info| metrics::record_request (&paths, info)))
+        .with (warp::trace (|info| tracing::info_span!("http_request",
+                                                       method = %info.method (),
+                                                       path = %info.path ())));
+
+    let host : IpAddr = config.http_host.parse ().expect ("HTTP_HOST is not an IP address");
+
+    let http = warp::serve(routes)
+        .run((host, config.http_port));
+
+    tokio::join!(http, grpc);
+}
+
+/// the producer and rate limiter of the command routes, when the role serves commands
+pub fn commands (config: &Config) -> Option<(Producer, RateLimiter)> {
+    match config.role.serves_commands () {
+        true => Some ((producer::init (config), rate_limit::init (config))),
+        false => None
+    }
+}
 
 /// writes to commands topic
 /// enforces light schema validation
-pub async fn run (config: Arc<Config>, db: Db, admin: KafkaAdmin) {
+/// serves queries from the local view when it has one, otherwise from the remote view service
+/// serves GraphQL when it has the local view or serves commands, see `graphql`
+/// serves the transfers when it runs the process manager, and the schedules when it runs the scheduler
+/// every route but the health checks, metrics and API docs requires a scope, see `auth`
+/// every route is documented in `openapi::ApiDoc`, the operations are the ones of the routes served
+pub fn routes (
+    config: &Config,
+    views: Views,
+    commands: Option<(Producer, RateLimiter)>,
+    admin: KafkaAdmin,
+    health: Health,
+    auth: Auth
+) -> (Routes, Vec<Operation>) {
 
-    let config = &*config;
-    let producer = producer::init (&config);
-    let routes = create_value(producer.clone (), config.clone ())
-        .or(update_value(producer.clone (), config.clone ()))
-        .or (query_value (db));
+    let Views { db, changes, transfers, schedules, groups } = views;
+
+    let role = config.role;
+
+    let mut routes = boxed (live (health.clone ())
+                            .or (ready (health, admin.clone (), Duration::from_millis (config.health_broker_timeout_ms)))
+                            .or (metrics ())
+                            .or (openapi_json ())
+                            .or (docs ()));
+    let mut operations = vec![operation::<__path_live> (), operation::<__path_ready> (), operation::<__path_metrics> (),
+                              operation::<__path_openapi_json> (), operation::<__path_docs> ()];
+
+    routes = boxed (admin_routes (auth.clone (), admin, config.clone ()).or (routes));
+    operations.extend ([operation::<__path_list_topics> (), operation::<__path_describe_topic> (), operation::<__path_groups_lag> (),
+                        operation::<__path_group_lag> (), operation::<__path_reset_offsets> ()]);
+
+    if db.is_some () || commands.is_some () {
+        let schema = graphql::schema (config.clone (), db.clone (), changes, commands.clone ());
+        routes = boxed (routes.or (graphql (auth.clone (), schema, config.clone ())));
+        operations.push (operation::<__path_graphql> ());
+    }
+
+    let queries = [operation::<__path_query_value> (), operation::<__path_query_values> (), operation::<__path_query_value_by_name> ()];
+    routes = match (db, role.serves_commands ()) {
+        (Some (db), _) => {
+            operations.extend (queries);
+            boxed (routes.or (query_value (auth.clone (), db.clone ()))
+                   .or (query_values (auth.clone (), db.clone ()))
+                   .or (query_value_by_name (auth.clone (), db)))
+        },
+        // the view service answers the queries of the values and of the groups
+        (None, true) => {
+            operations.extend (queries);
+            operations.push (operation::<__path_query_group> ());
+            boxed (routes.or (remote_query_value (auth.clone (), view_client::init (), config.view_url.clone ())))
+        },
+        (None, false) => routes
+    };
+
+    if let Some (groups) = groups {
+        routes = boxed (routes.or (query_group (auth.clone (), groups)));
+        operations.push (operation::<__path_query_group> ());
+    }
+
+    if let Some (transfers) = transfers {
+        routes = boxed (routes.or (query_transfer (auth.clone (), transfers)));
+        operations.push (operation::<__path_query_transfer> ());
+    }
+
+    if let Some (schedules) = schedules {
+        routes = boxed (routes.or (query_schedules (auth.clone (), schedules)));
+        operations.push (operation::<__path_query_schedules> ());
+    }
 
-    // create commands topic
-     admin::create_topic (admin, &config.commands_topic).await;
+    if let Some ((producer, limiter)) = commands {
+        routes = boxed (create_value(auth.clone (), limiter.clone (), producer.clone (), config.clone ())
+                        .or(update_value(auth.clone (), limiter.clone (), producer.clone (), config.clone ()))
+                        .or(relabel_value(auth.clone (), limiter.clone (), producer.clone (), config.clone ()))
+                        .or(create_transfer(auth.clone (), limiter.clone (), producer.clone (), config.clone ()))
+                        .or(schedule_value(auth.clone (), limiter.clone (), producer.clone (), config.clone ()))
+                        .or(cancel_schedule(auth, limiter, producer, config.clone ()))
+                        .or (routes));
+        operations.extend ([operation::<__path_create_value> (), operation::<__path_update_value> (), operation::<__path_relabel_value> (),
+                            operation::<__path_create_transfer> (), operation::<__path_schedule_value> (), operation::<__path_cancel_schedule> ()]);
+    }
 
-    warp::serve(routes)
-        .run(([127, 0, 0, 1], 3030))
-        .await;
+    (routes, operations)
+}
+
+/// the path and methods of the route `P`, from its `#[utoipa::path]`
+fn operation<P: utoipa::Path> () -> Operation {
+    (P::path (), P::methods ())
+}
+
+/// GET /health/live
+#[utoipa::path(get, path = "/health/live", tag = "health",
+               responses((status = 200, description = "the consumers of the process are running"),
+                         (status = 503, description = "one of the consumers stopped")))]
+fn live(
+    health : Health
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("health" / "live")
+        .and(warp::get())
+        .and(with_health(health))
+        .and_then(health::live)
+}
+
+/// GET /health/ready
+#[utoipa::path(get, path = "/health/ready", tag = "health",
+               responses((status = 200, description = "the brokers are reachable and the consumers caught up"),
+                         (status = 503, description = "the brokers are unreachable or a consumer lags")))]
+fn ready(
+    health : Health,
+    admin : KafkaAdmin,
+    timeout : Duration
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("health" / "ready")
+        .and(warp::get())
+        .and(with_health(health))
+        .and(warp::any().map(move || admin.clone()))
+        .and(warp::any().map(move || timeout))
+        .and_then(health::ready)
+}
+
+/// GET /metrics in prometheus text format
+#[utoipa::path(get, path = "/metrics", tag = "health",
+               responses((status = 200, description = "prometheus metrics", content_type = "text/plain")))]
+fn metrics() -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("metrics")
+        .and(warp::get())
+        .and_then(metrics::gather)
+}
+
+/// /admin routes, require the admin scope
+fn admin_routes(
+    auth : Auth,
+    admin : KafkaAdmin,
+    config : Config
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path("admin")
+        .and(auth::with_scope(auth, auth::ADMIN))
+        .and(list_topics(admin.clone(), config.clone())
+             .or(describe_topic(admin, config.clone()))
+             .or(groups_lag(config.clone()))
+             .or(group_lag(config.clone()))
+             .or(reset_offsets(config)))
+        .map(|_principal: Principal, reply| reply)
+}
+
+/// GET /admin/topics
+#[utoipa::path(get, path = "/admin/topics", tag = "admin",
+               responses((status = 200, description = "topics with their partitions and replication", body = Vec<TopicSummary>),
+                         (status = 401, response = Problem),
+                         (status = 403, response = Problem),
+                         (status = 503, response = Problem)),
+               security(("bearer" = ["admin"]), ("api_key" = ["admin"])))]
+fn list_topics(
+    admin : KafkaAdmin,
+    config : Config
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("topics")
+        .and(warp::get())
+        .and(with_config(config))
+        .and(with_admin(admin))
+        .and_then(admin_api::list_topics)
+}
+
+/// GET /admin/topics/:name
+#[utoipa::path(get, path = "/admin/topics/{name}", tag = "admin",
+               params(("name" = String, Path, description = "name of the topic")),
+               responses((status = 200, description = "partitions, watermarks and configs of the topic", body = TopicDescription),
+                         (status = 401, response = Problem),
+                         (status = 403, response = Problem),
+                         (status = 404, response = Problem),
+                         (status = 503, response = Problem)),
+               security(("bearer" = ["admin"]), ("api_key" = ["admin"])))]
+fn describe_topic(
+    admin : KafkaAdmin,
+    config : Config
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("topics" / String)
+        .and(warp::get())
+        .and(with_config(config))
+        .and(with_admin(admin))
+        .and_then(admin_api::describe_topic)
+}
+
+/// GET /admin/groups
+#[utoipa::path(get, path = "/admin/groups", tag = "admin",
+               responses((status = 200, description = "lag of the commands and events consumer groups, per topic", body = Vec<GroupLag>),
+                         (status = 401, response = Problem),
+                         (status = 403, response = Problem),
+                         (status = 503, response = Problem)),
+               security(("bearer" = ["admin"]), ("api_key" = ["admin"])))]
+fn groups_lag(
+    config : Config
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("groups")
+        .and(warp::get())
+        .and(with_config(config))
+        .and_then(admin_api::groups_lag)
+}
+
+/// GET /admin/groups/:id
+#[utoipa::path(get, path = "/admin/groups/{id}", tag = "admin",
+               params(("id" = String, Path, description = "id of the commands or events consumer group")),
+               responses((status = 200, description = "lag of the group, per topic", body = Vec<GroupLag>),
+                         (status = 401, response = Problem),
+                         (status = 403, response = Problem),
+                         (status = 404, response = Problem),
+                         (status = 503, response = Problem)),
+               security(("bearer" = ["admin"]), ("api_key" = ["admin"])))]
+fn group_lag(
+    config : Config
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("groups" / String)
+        .and(warp::get())
+        .and(with_config(config))
+        .and_then(admin_api::group_lag)
+}
+
+/// POST /admin/groups/:id/offsets {"to" : "earliest"}
+#[utoipa::path(post, path = "/admin/groups/{id}/offsets", tag = "admin",
+               params(("id" = String, Path, description = "id of the events consumer group, the commands group replays from the beginning and is refused with 409")),
+               request_body = OffsetReset,
+               responses((status = 200, description = "the committed offsets", body = Vec<PartitionOffset>),
+                         (status = 400, response = Problem),
+                         (status = 401, response = Problem),
+                         (status = 403, response = Problem),
+                         (status = 404, response = Problem),
+                         (status = 409, response = Problem),
+                         (status = 413, response = Problem),
+                         (status = 415, response = Problem),
+                         (status = 503, response = Problem)),
+               security(("bearer" = ["admin"]), ("api_key" = ["admin"])))]
+fn reset_offsets(
+    config : Config
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("groups" / String / "offsets")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(config.http_max_body_bytes))
+        .and(warp::body::json())
+        .and(with_config(config))
+        .and_then(admin_api::reset_offsets)
 }
 
 /// POST /values {"value" : 2 }
+#[utoipa::path(post, path = "/values", tag = "values",
+               request_body = ValueInput,
+               params(("x-correlation-id" = Option<String>, Header, description = "correlation id of the command, generated when missing")),
+               responses((status = 202, description = "id of the value to create", body = String, content_type = "application/json"),
+                         (status = 400, response = Problem),
+                         (status = 401, response = Problem),
+                         (status = 403, response = Problem),
+                         (status = 413, response = Problem),
+                         (status = 415, response = Problem),
+                         (status = 422, response = Problem),
+                         (status = 429, response = Problem),
+                         (status = 503, response = Problem)),
+               security(("bearer" = ["commands:write"]), ("api_key" = ["commands:write"])))]
 fn create_value(
+    auth : Auth,
+    limiter : RateLimiter,
     producer : Producer,
     config: Config
-) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-    warp::path("values")
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("values")
         .and(warp::post())
+        .and(rate_limit::limited(auth::with_scope(auth, auth::COMMANDS_WRITE), limiter, "/values"))
+        .and(warp::body::content_length_limit(config.http_max_body_bytes))
         .and(warp::body::json())
+        .and(with_correlation_id())
         .and(with_producer(producer))
         .and(with_config(config))
         .and_then(commands::create_value)
 }
 
 /// PUT /values/:id {"operation" : "add", "value" : 2 }
+#[utoipa::path(put, path = "/values/{id}", tag = "values",
+               request_body = ValueOperationInput,
+               params(("id" = String, Path, format = Uuid, description = "id of the value"),
+                      ("x-correlation-id" = Option<String>, Header, description = "correlation id of the command, generated when missing")),
+               responses((status = 202, description = "the update is accepted, it fails later when the value does not exist"),
+                         (status = 400, response = Problem),
+                         (status = 401, response = Problem),
+                         (status = 403, response = Problem),
+                         (status = 413, response = Problem),
+                         (status = 415, response = Problem),
+                         (status = 422, response = Problem),
+                         (status = 429, response = Problem),
+                         (status = 503, response = Problem)),
+               security(("bearer" = ["commands:write"]), ("api_key" = ["commands:write"])))]
 fn update_value(
+    auth : Auth,
+    limiter : RateLimiter,
     producer : Producer,
     config: Config
-) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
     warp::path!("values" / Uuid)
         .and(warp::put())
+        .and(rate_limit::limited(auth::with_scope(auth, auth::COMMANDS_WRITE), limiter, "/values/:id"))
+        .and(warp::body::content_length_limit(config.http_max_body_bytes))
         .and(warp::body::json())
+        .and(with_correlation_id())
         .and(with_producer(producer))
         .and(with_config(config))
         .and_then(commands::update_value)
 }
 
+/// PUT /values/:id/labels {"name" : "savings", "tags" : {"team" : "core"} }
+#[utoipa::path(put, path = "/values/{id}/labels", tag = "values",
+               request_body = Labels,
+               params(("id" = String, Path, format = Uuid, description = "id of the value"),
+                      ("x-correlation-id" = Option<String>, Header, description = "correlation id of the command, generated when missing")),
+               responses((status = 202, description = "the labels replace all the labels of the value, the command fails later when the value does not exist or another value has the name"),
+                         (status = 400, response = Problem),
+                         (status = 401, response = Problem),
+                         (status = 403, response = Problem),
+                         (status = 413, response = Problem),
+                         (status = 415, response = Problem),
+                         (status = 422, response = Problem),
+                         (status = 429, response = Problem),
+                         (status = 503, response = Problem)),
+               security(("bearer" = ["commands:write"]), ("api_key" = ["commands:write"])))]
+fn relabel_value(
+    auth : Auth,
+    limiter : RateLimiter,
+    producer : Producer,
+    config: Config
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("values" / Uuid / "labels")
+        .and(warp::put())
+        .and(rate_limit::limited(auth::with_scope(auth, auth::COMMANDS_WRITE), limiter, "/values/:id/labels"))
+        .and(warp::body::content_length_limit(config.http_max_body_bytes))
+        .and(warp::body::json())
+        .and(with_correlation_id())
+        .and(with_producer(producer))
+        .and(with_config(config))
+        .and_then(commands::relabel_value)
+}
+
+/// POST /transfers {"from" : "..", "to" : "..", "amount" : 10 }
+#[utoipa::path(post, path = "/transfers", tag = "transfers",
+               request_body = TransferInput,
+               params(("x-correlation-id" = Option<String>, Header, description = "correlation id of the transfer's commands, generated when missing")),
+               responses((status = 202, description = "id of the transfer, run by the process manager", body = String, content_type = "application/json"),
+                         (status = 400, response = Problem),
+                         (status = 401, response = Problem),
+                         (status = 403, response = Problem),
+                         (status = 413, response = Problem),
+                         (status = 415, response = Problem),
+                         (status = 422, response = Problem),
+                         (status = 429, response = Problem),
+                         (status = 503, response = Problem)),
+               security(("bearer" = ["commands:write"]), ("api_key" = ["commands:write"])))]
+fn create_transfer(
+    auth : Auth,
+    limiter : RateLimiter,
+    producer : Producer,
+    config: Config
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("transfers")
+        .and(warp::post())
+        .and(rate_limit::limited(auth::with_scope(auth, auth::COMMANDS_WRITE), limiter, "/transfers"))
+        .and(warp::body::content_length_limit(config.http_max_body_bytes))
+        .and(warp::body::json())
+        .and(with_correlation_id())
+        .and(with_producer(producer))
+        .and(with_config(config))
+        .and_then(commands::create_transfer)
+}
+
+/// GET /transfers/:id
+#[utoipa::path(get, path = "/transfers/{id}", tag = "transfers",
+               params(("id" = String, Path, format = Uuid, description = "id of the transfer")),
+               responses((status = 200, description = "the transfer, as of its last step", body = TransferView),
+                         (status = 401, response = Problem),
+                         (status = 403, response = Problem),
+                         (status = 404, response = Problem)),
+               security(("bearer" = ["values:read"]), ("api_key" = ["values:read"])))]
+fn query_transfer(
+    auth : Auth,
+    transfers : Transfers
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("transfers" / Uuid)
+        .and(warp::get())
+        .and(auth::with_scope(auth, auth::VALUES_READ))
+        .and(warp::any().map(move || transfers.clone()))
+        .and_then(queries::get_transfer)
+}
+
+/// POST /values/:id/schedule {"operation" : "MULTIPLY", "value" : 1.0001, "cron" : "0 0 * * *" }
+#[utoipa::path(post, path = "/values/{id}/schedule", tag = "schedules",
+               request_body = ScheduleInput,
+               params(("id" = String, Path, format = Uuid, description = "id of the value"),
+                      ("x-correlation-id" = Option<String>, Header, description = "correlation id of the scheduled commands, generated when missing")),
+               responses((status = 202, description = "id of the schedule, run by the scheduler", body = String, content_type = "application/json"),
+                         (status = 400, response = Problem),
+                         (status = 401, response = Problem),
+                         (status = 403, response = Problem),
+                         (status = 413, response = Problem),
+                         (status = 415, response = Problem),
+                         (status = 422, response = Problem),
+                         (status = 429, response = Problem),
+                         (status = 503, response = Problem)),
+               security(("bearer" = ["commands:write"]), ("api_key" = ["commands:write"])))]
+fn schedule_value(
+    auth : Auth,
+    limiter : RateLimiter,
+    producer : Producer,
+    config: Config
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("values" / Uuid / "schedule")
+        .and(warp::post())
+        .and(rate_limit::limited(auth::with_scope(auth, auth::COMMANDS_WRITE), limiter, "/values/:id/schedule"))
+        .and(warp::body::content_length_limit(config.http_max_body_bytes))
+        .and(warp::body::json())
+        .and(with_correlation_id())
+        .and(with_producer(producer))
+        .and(with_config(config))
+        .and_then(commands::schedule_value)
+}
+
+/// DELETE /schedules/:id
+#[utoipa::path(delete, path = "/schedules/{id}", tag = "schedules",
+               params(("id" = String, Path, format = Uuid, description = "id of the schedule"),
+                      ("x-correlation-id" = Option<String>, Header, description = "correlation id of the cancellation, generated when missing")),
+               responses((status = 202, description = "the cancellation is accepted, it is ignored when the schedule does not exist"),
+                         (status = 401, response = Problem),
+                         (status = 403, response = Problem),
+                         (status = 429, response = Problem),
+                         (status = 503, response = Problem)),
+               security(("bearer" = ["commands:write"]), ("api_key" = ["commands:write"])))]
+fn cancel_schedule(
+    auth : Auth,
+    limiter : RateLimiter,
+    producer : Producer,
+    config: Config
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("schedules" / Uuid)
+        .and(warp::delete())
+        .and(rate_limit::limited(auth::with_scope(auth, auth::COMMANDS_WRITE), limiter, "/schedules/:id"))
+        .and(with_correlation_id())
+        .and(with_producer(producer))
+        .and(with_config(config))
+        .and_then(commands::cancel_schedule)
+}
+
+/// GET /schedules?value_id=..
+#[utoipa::path(get, path = "/schedules", tag = "schedules",
+               params(("value_id" = Option<String>, Query, format = Uuid, description = "only the schedules of this value")),
+               responses((status = 200, description = "the tenant's schedules, including the done and cancelled ones", body = [Schedule]),
+                         (status = 400, response = Problem),
+                         (status = 401, response = Problem),
+                         (status = 403, response = Problem)),
+               security(("bearer" = ["values:read"]), ("api_key" = ["values:read"])))]
+fn query_schedules(
+    auth : Auth,
+    schedules : Schedules
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("schedules")
+        .and(warp::get())
+        .and(auth::with_scope(auth, auth::VALUES_READ))
+        .and(warp::query::<queries::ScheduleQuery>())
+        .and(warp::any().map(move || schedules.clone()))
+        .and_then(queries::get_schedules)
+}
+
 /// GET /values/:id { "value" : 2 }
+#[utoipa::path(get, path = "/values/{id}", tag = "values",
+               params(("id" = String, Path, format = Uuid, description = "id of the value")),
+               responses((status = 200, description = "the value, as of the last event applied to the view", body = ValueBody),
+                         (status = 401, response = Problem),
+                         (status = 403, response = Problem),
+                         (status = 404, response = Problem),
+                         (status = 502, response = Problem)),
+               security(("bearer" = ["values:read"]), ("api_key" = ["values:read"])))]
 fn query_value(
+    auth : Auth,
     db : Db
-) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
     warp::path!("values" / Uuid)
         .and(warp::get())
+        .and(auth::with_scope(auth, auth::VALUES_READ))
         .and(with_db(db))
         .and_then(queries::get_value)
 }
 
+/// GET /values?tag=team:core
+#[utoipa::path(get, path = "/values", tag = "values",
+               params(("tag" = Option<String>, Query, description = "only the values with this tag, as `key:value`")),
+               responses((status = 200, description = "the tenant's values ordered by id, as of the last event applied to the view", body = [ValueBody]),
+                         (status = 400, response = Problem),
+                         (status = 401, response = Problem),
+                         (status = 403, response = Problem),
+                         (status = 502, response = Problem)),
+               security(("bearer" = ["values:read"]), ("api_key" = ["values:read"])))]
+fn query_values(
+    auth : Auth,
+    db : Db
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("values")
+        .and(warp::get())
+        .and(auth::with_scope(auth, auth::VALUES_READ))
+        .and(warp::query::<queries::ValueQuery>())
+        .and(with_db(db))
+        .and_then(queries::get_values)
+}
+
+/// GET /values/by-name/:name
+#[utoipa::path(get, path = "/values/by-name/{name}", tag = "values",
+               params(("name" = String, Path, description = "name of the value")),
+               responses((status = 200, description = "the value, as of the last event applied to the view", body = ValueBody),
+                         (status = 401, response = Problem),
+                         (status = 403, response = Problem),
+                         (status = 404, response = Problem),
+                         (status = 502, response = Problem)),
+               security(("bearer" = ["values:read"]), ("api_key" = ["values:read"])))]
+fn query_value_by_name(
+    auth : Auth,
+    db : Db
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("values" / "by-name" / String)
+        .and(warp::get())
+        .and(auth::with_scope(auth, auth::VALUES_READ))
+        .and(with_db(db))
+        .and_then(queries::get_value_by_name)
+}
+
+/// GET /groups/:id
+#[utoipa::path(get, path = "/groups/{id}", tag = "values",
+               params(("id" = String, Path, description = "id of the group, as given in the `groups` of its values")),
+               responses((status = 200, description = "the totals of the group's values, as of the last event applied to the view", body = Group),
+                         (status = 401, response = Problem),
+                         (status = 403, response = Problem),
+                         (status = 404, response = Problem),
+                         (status = 502, response = Problem)),
+               security(("bearer" = ["values:read"]), ("api_key" = ["values:read"])))]
+fn query_group(
+    auth : Auth,
+    groups : Groups
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("groups" / String)
+        .and(warp::get())
+        .and(auth::with_scope(auth, auth::VALUES_READ))
+        .and(warp::any().map(move || groups.clone()))
+        .and_then(queries::get_group)
+}
+
+/// GET /values/** and GET /groups/** forwarded to the materialized view service, with the request's credentials
+fn remote_query_value(
+    auth : Auth,
+    client : ViewClient,
+    view_url : String
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path("values").or(warp::path("groups")).unify()
+        .and(warp::get())
+        .and(auth::with_scope(auth, auth::VALUES_READ))
+        .and(warp::path::full())
+        .and(raw_query())
+        .and(warp::header::headers_cloned())
+        .and(warp::any().map(move || client.clone()))
+        .and(warp::any().map(move || view_url.clone()))
+        .and_then(queries::get_remote)
+}
+
+/// POST /graphql {"query" : "{ value(id: \"...\") { value } }"}, GET /graphql for queries and websocket subscriptions
+#[utoipa::path(method(get, post), path = "/graphql", tag = "graphql",
+               params(("query" = Option<String>, Query, description = "GraphQL query of a GET"),
+                      ("variables" = Option<String>, Query, description = "JSON variables of a GET")),
+               request_body(content = Object, description = "GraphQL request of a POST: `query`, `variables` and `operationName`"),
+               responses((status = 101, description = "websocket of the subscriptions, `graphql-transport-ws` or `graphql-ws` protocol"),
+                         (status = 200, description = "GraphQL response, errors carry the problem `code` and `status` in their extensions"),
+                         (status = 400, response = Problem),
+                         (status = 401, response = Problem),
+                         (status = 403, response = Problem),
+                         (status = 413, response = Problem)),
+               security(("bearer" = ["values:read", "commands:write"]), ("api_key" = ["values:read", "commands:write"])))]
+fn graphql(
+    auth : Auth,
+    schema : ApiSchema,
+    config : Config
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    let subscriptions = warp::path!("graphql")
+        .and(warp::ws())
+        .and(async_graphql_warp::graphql_protocol())
+        .and(warp::header::headers_cloned())
+        .and(with_auth(auth.clone()))
+        .and(with_schema(schema.clone()))
+        .map(graphql::subscribe);
+
+    let requests = warp::path!("graphql")
+        .and(warp::get()
+             .or(warp::post().and(warp::body::content_length_limit(config.http_max_body_bytes)))
+             .unify())
+        .and(auth::authenticated(auth))
+        .and(with_correlation_id())
+        .and(warp::addr::remote())
+        .and(async_graphql_warp::graphql(schema))
+        .and_then(graphql::execute);
+
+    subscriptions.or(requests)
+}
+
+/// GET /openapi.json
+#[utoipa::path(get, path = "/openapi.json", tag = "docs",
+               responses((status = 200, description = "this document")))]
+fn openapi_json() -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    let spec = openapi::spec();
+    warp::path!("openapi.json")
+        .and(warp::get())
+        .map(move || warp::reply::json(&spec))
+}
+
+/// GET /docs/** Swagger UI of /openapi.json, bundled in the binary
+#[utoipa::path(get, path = "/docs/", tag = "docs",
+               responses((status = 200, description = "the API docs", content_type = "text/html")))]
+fn docs() -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    let ui_config = Arc::new(utoipa_swagger_ui::Config::from("/openapi.json"));
+    warp::path("docs")
+        .and(warp::get())
+        .and(warp::path::full())
+        .and(warp::path::tail())
+        .and(warp::any().map(move || ui_config.clone()))
+        .and_then(openapi::serve_docs)
+}
+
+fn boxed<F, R> (filter: F) -> Routes
+where
+    F: Filter<Extract = (R,), Error = warp::Rejection> + Clone + Send + Sync + 'static,
+    R: Reply + 'static
+{
+    filter.map(|reply| Box::new(reply) as Box<dyn Reply>).boxed()
+}
+
+/// raw query string, empty when the request has none
+fn raw_query() -> impl Filter<Extract = (String,), Error = Infallible> + Clone {
+    warp::query::raw()
+        .or(warp::any().map(String::new))
+        .unify()
+}
+
+/// correlation id given by the client in `X-Correlation-Id`, or a new one when it is missing or invalid
+fn with_correlation_id() -> impl Filter<Extract = (String,), Error = warp::Rejection> + Clone {
+    warp::header::optional::<String>("x-correlation-id")
+        .map(|id: Option<String>| envelope::correlation_id(id.as_deref()))
+}
+
+fn with_auth(auth: Auth) -> impl Filter<Extract = (Auth,), Error = Infallible> + Clone {
+    warp::any().map(move || auth.clone())
+}
+
+fn with_schema(schema: ApiSchema) -> impl Filter<Extract = (ApiSchema,), Error = Infallible> + Clone {
+    warp::any().map(move || schema.clone())
+}
+
+fn with_admin(
+    admin : KafkaAdmin
+) -> impl Filter<Extract = (KafkaAdmin,), Error = Infallible> + Clone {
+    warp::any().map(move || admin.clone())
+}
+
 fn with_producer(producer: Producer) -> impl Filter<Extract = (Producer,), Error = Infallible> + Clone {
     warp::any().map(move || producer.clone())
 }
@@ -76,3 +761,7 @@ fn with_config(config: Config) -> impl Filter<Extract = (Config,), Error = Infal
 fn with_db(db: Db) -> impl Filter<Extract = (Db,), Error = Infallible> + Clone {
     warp::any().map(move || db.clone())
 }
+
+fn with_health(health: Health) -> impl Filter<Extract = (Health,), Error = Infallible> + Clone {
+    warp::any().map(move || health.clone())
+}
@@ -0,0 +1,422 @@
+use crate::commands_schema::DEFAULT_TENANT;
+use crate::config::Config;
+use crate::config;
+use crate::errors::ApiError;
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use jsonwebtoken::jwk::JwkSet;
+use futures::future;
+use log::{debug, info, warn};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::sync::Arc;
+use warp::Filter;
+
+pub const COMMANDS_WRITE: &str = "commands:write";
+pub const VALUES_READ: &str = "values:read";
+pub const ADMIN: &str = "admin";
+/// lets credentials without a tenant pick one with `X-Tenant-Id`
+pub const TENANTS_ANY: &str = "tenants:any";
+
+/// the authenticated caller of a request
+#[derive(Clone, Debug, PartialEq)]
+pub struct Principal {
+    /// user or client id, recorded as `user_id` in the commands and events
+    pub subject: String,
+    pub scopes: HashSet<String>,
+    /// tenant the credentials are bound to, or the tenant the request asked for
+    pub tenant: Option<String>,
+}
+
+impl Principal {
+    /// caller of the requests when no authenticator is configured, allowed everything but the admin routes
+    pub fn anonymous () -> Principal {
+        Principal {
+            subject: String::from ("anonymous"),
+            scopes: hashset (&[COMMANDS_WRITE, VALUES_READ]),
+            tenant: None,
+        }
+    }
+
+    /// tenant whose values the request reads and writes
+    pub fn tenant (&self) -> &str {
+        self.tenant.as_deref ().unwrap_or (DEFAULT_TENANT)
+    }
+
+    pub fn has_scope (&self, scope: &str) -> bool {
+        self.scopes.contains (scope)
+    }
+
+    /// forbidden without the scope
+    pub fn require (&self, scope: &str) -> Result<(), ApiError> {
+        match self.has_scope (scope) {
+            true => Ok (()),
+            false => Err (ApiError::Forbidden (format!("{} lacks the {} scope", self.subject, scope)))
+        }
+    }
+}
+
+/// credentials presented by a request
+#[derive(Debug)]
+pub enum Credentials {
+    /// `Authorization: Bearer <token>`
+    Bearer (String),
+    /// `X-Api-Key: <key>`
+    ApiKey (String),
+}
+
+impl Credentials {
+    /// from the values of the `Authorization` and `X-Api-Key` headers, a bearer token wins
+    pub fn from_headers (authorization: Option<&str>, api_key: Option<&str>) -> Option<Credentials> {
+        match (authorization.and_then (|h| h.strip_prefix ("Bearer ")), api_key) {
+            (Some (token), _) => Some (Credentials::Bearer (String::from (token.trim ()))),
+            (None, Some (key)) => Some (Credentials::ApiKey (String::from (key))),
+            (None, None) => None
+        }
+    }
+}
+
+/// checks credentials, `None` when the credentials are not of the kind it handles
+pub trait Authenticator: Send + Sync {
+    fn authenticate (&self, credentials: &Credentials) -> Option<Result<Principal, String>>;
+}
+
+/// the configured authenticators, tried in order, requests are anonymous when there is none
+#[derive(Clone)]
+pub struct Auth {
+    authenticators: Arc<Vec<Box<dyn Authenticator>>>,
+    /// whether API keys or JWTs are configured, with the admin token alone the value routes stay open
+    values_protected: bool,
+    /// allowed tenants, any when empty
+    tenants: Arc<Vec<String>>,
+}
+
+impl Auth {
+    /// whether the value routes require credentials
+    pub fn is_enabled (&self) -> bool {
+        self.values_protected
+    }
+
+    /// the principal of the credentials, anonymous when the value routes are open and there are none,
+    /// with the admin token alone the admin also has the scopes of the anonymous requests
+    pub fn authenticate (&self, credentials: Option<Credentials>) -> Result<Principal, ApiError> {
+        let credentials = match (credentials, self.authenticators.is_empty (), self.values_protected) {
+            (_, true, _) | (None, false, false) => return Ok (Principal::anonymous ()),
+            (None, false, true) => return Err (ApiError::Unauthorized (String::from ("missing credentials"))),
+            (Some (credentials), false, _) => credentials
+        };
+        let principal = self.authenticators.iter ()
+            .find_map (|authenticator| authenticator.authenticate (&credentials))
+            .unwrap_or_else (|| Err (String::from ("invalid credentials")))
+            .map_err (ApiError::Unauthorized)?;
+        match self.values_protected {
+            true => Ok (principal),
+            false => Ok (Principal { scopes: principal.scopes.union (&Principal::anonymous ().scopes).cloned ().collect (), ..principal })
+        }
+    }
+
+    /// binds the principal to the tenant of the `X-Tenant-Id` header, only the credentials with the
+    /// `tenants:any` or `admin` scope, and the anonymous requests when auth is disabled, can ask for a tenant,
+    /// the others are bound to their own tenant, or to the default one
+    pub fn with_tenant (&self, principal: Principal, requested: Option<String>) -> Result<Principal, ApiError> {
+        let multi_tenant = !self.is_enabled () || principal.has_scope (TENANTS_ANY) || principal.has_scope (ADMIN);
+        let bound = match (&principal.tenant, multi_tenant) {
+            (Some (bound), _) => Some (bound.clone ()),
+            (None, true) => None,
+            (None, false) => Some (String::from (DEFAULT_TENANT))
+        };
+        let tenant = match (bound, requested) {
+            (Some (bound), Some (requested)) if bound != requested =>
+                return Err (ApiError::Forbidden (format!("{} is bound to tenant {}", principal.subject, bound))),
+            (Some (bound), _) => bound,
+            (None, Some (requested)) => requested,
+            (None, None) => String::from (DEFAULT_TENANT)
+        };
+
+        if !config::is_valid_tenant (&tenant) {
+            return Err (ApiError::Forbidden (format!("invalid tenant {}", tenant)));
+        }
+        if !self.tenants.is_empty () && !self.tenants.contains (&tenant) {
+            return Err (ApiError::Forbidden (format!("unknown tenant {}", tenant)));
+        }
+        Ok (Principal { tenant: Some (tenant), ..principal })
+    }
+
+    /// the principal of the credentials, with the tenant it asks for resolved
+    pub fn resolve (&self, credentials: Option<Credentials>, tenant: Option<String>) -> Result<Principal, ApiError> {
+        self.authenticate (credentials).and_then (|principal| self.with_tenant (principal, tenant))
+    }
+}
+
+/// static API keys, read from the TOML file in `AUTH_API_KEYS_FILE`:
+///
+/// ```toml
+/// [alice]
+/// key = "..."
+/// scopes = ["commands:write", "values:read"]
+/// tenant = "acme"  # optional, binds the key to the tenant
+/// ```
+pub struct ApiKeys {
+    keys: Vec<(String, Principal)>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ApiKeyEntry {
+    key: String,
+    scopes: Vec<String>,
+    tenant: Option<String>,
+}
+
+impl ApiKeys {
+    pub fn from_file (path: &str) -> Result<ApiKeys, String> {
+        let content = fs::read_to_string (path)
+            .map_err (|why| format!("AUTH_API_KEYS_FILE: could not read {}: {}", path, why))?;
+        let entries : BTreeMap<String, ApiKeyEntry> = toml::from_str (&content)
+            .map_err (|why| format!("AUTH_API_KEYS_FILE: invalid {}: {}", path, why))?;
+        let keys = entries.into_iter ()
+            .map (|(subject, entry)| (entry.key, Principal { subject, scopes: entry.scopes.into_iter ().collect (), tenant: entry.tenant }))
+            .collect ();
+        Ok (ApiKeys { keys })
+    }
+
+    /// the admin token, a key with the admin scope only
+    pub fn admin (token: &str) -> ApiKeys {
+        let principal = Principal { subject: String::from ("admin"), scopes: hashset (&[ADMIN]), tenant: None };
+        ApiKeys { keys: vec![(String::from (token), principal)] }
+    }
+}
+
+impl Authenticator for ApiKeys {
+    fn authenticate (&self, credentials: &Credentials) -> Option<Result<Principal, String>> {
+        // keys are also accepted as bearer tokens, so the admin token works with `Authorization: Bearer`
+        let given = match credentials {
+            Credentials::ApiKey (key) | Credentials::Bearer (key) => key,
+        };
+        self.keys.iter ()
+            .find (|(key, _)| constant_time_eq (key, given))
+            .map (|(_, principal)| Ok (principal.clone ()))
+    }
+}
+
+/// JWT bearer tokens signed by one of the keys of a local JWKS file
+pub struct Jwt {
+    keys: JwkSet,
+    issuer: Option<String>,
+    audience: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Claims {
+    sub: String,
+    /// space separated scopes, as issued by OAuth 2 servers
+    #[serde(default)]
+    scope: Option<String>,
+    /// scopes as an array, as issued by some identity providers
+    #[serde(default)]
+    scp: Option<Vec<String>>,
+    /// binds the token to a tenant
+    #[serde(default)]
+    tenant: Option<String>,
+}
+
+impl Jwt {
+    pub fn from_file (path: &str, issuer: Option<String>, audience: Option<String>) -> Result<Jwt, String> {
+        let content = fs::read_to_string (path)
+            .map_err (|why| format!("AUTH_JWKS_FILE: could not read {}: {}", path, why))?;
+        let keys : JwkSet = serde_json::from_str (&content)
+            .map_err (|why| format!("AUTH_JWKS_FILE: invalid {}: {}", path, why))?;
+        Ok (Jwt { keys, issuer, audience })
+    }
+
+    fn validate (&self, token: &str) -> Result<Principal, String> {
+        let header = jsonwebtoken::decode_header (token)
+            .map_err (|why| format!("invalid token: {}", why))?;
+
+        // only asymmetric algorithms, the JWKS holds public keys
+        if matches!(header.alg, Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512) {
+            return Err (format!("unsupported algorithm {:?}", header.alg));
+        }
+
+        let jwk = match &header.kid {
+            Some (kid) => self.keys.find (kid),
+            None if self.keys.keys.len () == 1 => self.keys.keys.first (),
+            None => None
+        }.ok_or_else (|| format!("no key matches the token's kid {:?}", header.kid))?;
+        let key = DecodingKey::from_jwk (jwk)
+            .map_err (|why| format!("unusable key {:?}: {}", header.kid, why))?;
+
+        let mut validation = Validation::new (header.alg);
+        if let Some (issuer) = &self.issuer {
+            validation.set_issuer (&[issuer]);
+        }
+        match &self.audience {
+            Some (audience) => validation.set_audience (&[audience]),
+            None => validation.validate_aud = false
+        };
+
+        let claims = jsonwebtoken::decode::<Claims> (token, &key, &validation)
+            .map_err (|why| format!("invalid token: {}", why))?
+            .claims;
+
+        let scopes = claims.scope.iter ()
+            .flat_map (|scope| scope.split_whitespace ().map (String::from))
+            .chain (claims.scp.into_iter ().flatten ())
+            .collect ();
+        Ok (Principal { subject: claims.sub, scopes, tenant: claims.tenant })
+    }
+}
+
+impl Authenticator for Jwt {
+    fn authenticate (&self, credentials: &Credentials) -> Option<Result<Principal, String>> {
+        match credentials {
+            // a bearer token that is not a JWT may still be an API key
+            Credentials::Bearer (token) if token.matches ('.').count () == 2 => Some (self.validate (token)),
+            _ => None
+        }
+    }
+}
+
+/// builds the authenticators from the `AUTH_*` settings and the admin token
+pub fn init (config: &Config) -> Result<Auth, String> {
+
+    let mut authenticators : Vec<Box<dyn Authenticator>> = Vec::new ();
+
+    if let Some (path) = &config.auth_api_keys_file {
+        authenticators.push (Box::new (ApiKeys::from_file (path)?));
+    }
+    if let Some (path) = &config.auth_jwks_file {
+        authenticators.push (Box::new (Jwt::from_file (path, config.auth_jwt_issuer.clone (), config.auth_jwt_audience.clone ())?));
+    }
+    let values_protected = !authenticators.is_empty ();
+    if let Some (token) = &config.admin_token {
+        authenticators.push (Box::new (ApiKeys::admin (&token.0)));
+    }
+
+    match (authenticators.is_empty (), values_protected) {
+        (true, _) => warn!("No authenticator is configured, the API is open to anonymous requests and the /admin routes are disabled"),
+        (false, false) => warn!("Only ADMIN_TOKEN is configured, the /admin routes require it and the other routes are open to anonymous requests"),
+        (false, true) => info!("{} authenticator(s) configured", authenticators.len ())
+    };
+
+    Ok (Auth { authenticators: Arc::new (authenticators), values_protected, tenants: Arc::new (config.tenants.clone ()) })
+}
+
+/// authenticates the request, checks that the principal has the scope and resolves its tenant
+pub fn with_scope (
+    auth: Auth,
+    scope: &'static str
+) -> impl Filter<Extract = (Principal,), Error = warp::Rejection> + Clone {
+    credentials ()
+        .and (warp::header::optional::<String> ("x-tenant-id"))
+        .and_then (move |credentials: Option<Credentials>, tenant: Option<String>| {
+            let result = auth.authenticate (credentials)
+                .and_then (|principal| principal.require (scope).and_then (|_| auth.with_tenant (principal, tenant)))
+                .inspect (|principal| debug!("Authenticated {} of tenant {} for {}", principal.subject, principal.tenant (), scope))
+                .inspect_err (|why| info!("Request denied: {:?}", why))
+                .map_err (warp::reject::custom);
+            future::ready (result)
+        })
+}
+
+/// authenticates the request and resolves its tenant, the scopes are checked by the caller
+pub fn authenticated (auth: Auth) -> impl Filter<Extract = (Principal,), Error = warp::Rejection> + Clone {
+    credentials ()
+        .and (warp::header::optional::<String> ("x-tenant-id"))
+        .and_then (move |credentials: Option<Credentials>, tenant: Option<String>| {
+            let result = auth.resolve (credentials, tenant)
+                .inspect (|principal| debug!("Authenticated {} of tenant {}", principal.subject, principal.tenant ()))
+                .inspect_err (|why| info!("Request denied: {:?}", why))
+                .map_err (warp::reject::custom);
+            future::ready (result)
+        })
+}
+
+/// bearer token or API key of the request
+fn credentials () -> impl Filter<Extract = (Option<Credentials>,), Error = std::convert::Infallible> + Clone {
+    warp::header::optional::<String> ("authorization")
+        .and (warp::header::optional::<String> ("x-api-key"))
+        .map (|authorization: Option<String>, api_key: Option<String>| Credentials::from_headers (authorization.as_deref (), api_key.as_deref ()))
+        .or (warp::any ().map (|| None))
+        .unify ()
+}
+
+fn hashset (scopes: &[&str]) -> HashSet<String> {
+    scopes.iter ().map (|scope| String::from (*scope)).collect ()
+}
+
+fn constant_time_eq (a: &str, b: &str) -> bool {
+    a.len () == b.len () && a.bytes ().zip (b.bytes ()).fold (0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn auth () -> Auth {
+        Auth { authenticators: Arc::new (vec![Box::new (ApiKeys::admin ("token")) as Box<dyn Authenticator>]), values_protected: true, tenants: Arc::new (Vec::new ()) }
+    }
+
+    fn principal (scopes: &[&str], tenant: Option<&str>) -> Principal {
+        Principal { subject: String::from ("ci"), scopes: hashset (scopes), tenant: tenant.map (String::from) }
+    }
+
+    #[test]
+    fn unbound_credentials_are_bound_to_the_default_tenant () {
+        let auth = auth ();
+        let unbound = principal (&[VALUES_READ], None);
+        assert_eq!(auth.with_tenant (unbound.clone (), None).unwrap ().tenant (), DEFAULT_TENANT);
+        assert_eq!(auth.with_tenant (unbound.clone (), Some (String::from (DEFAULT_TENANT))).unwrap ().tenant (), DEFAULT_TENANT);
+        assert!(matches!(auth.with_tenant (unbound, Some (String::from ("acme"))), Err (ApiError::Forbidden (_))));
+    }
+
+    #[test]
+    fn bound_credentials_cannot_ask_for_another_tenant () {
+        let auth = auth ();
+        let bound = principal (&[VALUES_READ, TENANTS_ANY], Some ("acme"));
+        assert_eq!(auth.with_tenant (bound.clone (), None).unwrap ().tenant (), "acme");
+        assert!(matches!(auth.with_tenant (bound, Some (String::from ("globex"))), Err (ApiError::Forbidden (_))));
+    }
+
+    #[test]
+    fn multi_tenant_and_admin_credentials_pick_the_tenant () {
+        let auth = auth ();
+        for scope in [TENANTS_ANY, ADMIN] {
+            let principal = principal (&[VALUES_READ, scope], None);
+            assert_eq!(auth.with_tenant (principal.clone (), Some (String::from ("acme"))).unwrap ().tenant (), "acme");
+            assert_eq!(auth.with_tenant (principal, None).unwrap ().tenant (), DEFAULT_TENANT);
+        }
+    }
+
+    #[test]
+    fn anonymous_requests_pick_the_tenant_when_auth_is_disabled () {
+        let open = Auth { authenticators: Arc::new (Vec::new ()), values_protected: false, tenants: Arc::new (Vec::new ()) };
+        assert_eq!(open.with_tenant (Principal::anonymous (), Some (String::from ("acme"))).unwrap ().tenant (), "acme");
+    }
+
+    #[test]
+    fn tenants_outside_the_list_are_refused () {
+        let auth = Auth { tenants: Arc::new (vec![String::from ("acme")]), ..auth () };
+        let principal = principal (&[TENANTS_ANY], None);
+        assert!(auth.with_tenant (principal.clone (), Some (String::from ("acme"))).is_ok ());
+        assert!(matches!(auth.with_tenant (principal, Some (String::from ("globex"))), Err (ApiError::Forbidden (_))));
+    }
+
+    #[test]
+    fn the_admin_token_alone_keeps_the_value_routes_open () {
+        let auth = Auth { values_protected: false, ..auth () };
+        let anonymous = auth.authenticate (None).unwrap ();
+        assert!(anonymous.has_scope (VALUES_READ) && anonymous.has_scope (COMMANDS_WRITE) && !anonymous.has_scope (ADMIN));
+
+        let admin = auth.authenticate (Some (Credentials::Bearer (String::from ("token")))).unwrap ();
+        assert!(admin.has_scope (ADMIN) && admin.has_scope (VALUES_READ));
+        assert!(matches!(auth.authenticate (Some (Credentials::ApiKey (String::from ("wrong")))), Err (ApiError::Unauthorized (_))));
+    }
+
+    #[test]
+    fn value_credentials_protect_the_value_routes () {
+        let auth = auth ();
+        assert!(matches!(auth.authenticate (None), Err (ApiError::Unauthorized (_))));
+        let admin = auth.authenticate (Some (Credentials::ApiKey (String::from ("token")))).unwrap ();
+        assert!(admin.has_scope (ADMIN) && !admin.has_scope (VALUES_READ));
+    }
+}
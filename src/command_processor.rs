@@ -1,47 +1,76 @@
-use crate::commands_schema::{Command, Value, UpdateOperation};
+use crate::aggregate::{Aggregate, AggregateCommand, AggregateEvent};
 use crate::config::Config;
 use crate::consumer;
-use crate::events_schema::Event;
+use crate::envelope::Envelope;
+use crate::envelope;
+use crate::health::Health;
+use crate::health;
+use crate::metrics;
 use crate::producer::Producer;
 use crate::producer;
+use crate::telemetry;
+use chrono::{DateTime, Utc};
 use log::{debug, info, warn, error};
-use maplit::hashmap;
 use rdkafka::consumer::{CommitMode, Consumer};
-use rdkafka::message::Message;
+use rdkafka::message::{BorrowedMessage, Message};
 use rdkafka::producer::FutureRecord;
 use rdkafka::topic_partition_list::{TopicPartitionList, Offset};
-use serde_json;
 use std::collections::HashMap;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use tracing::Instrument;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
 use uuid::Uuid;
 
-pub async fn run (config : Arc<Config>) {
+/// validates the commands of the aggregate `A` and emits their events,
+/// the commands topics carry the commands of a single aggregate type
+pub async fn run<A: Aggregate> (config : Arc<Config>, health : Health) {
 
-    let Config { broker, commands_group_id, commands_topic, .. } = &*config;
+    let Config { commands_group_id, .. } = &*config;
+    let commands_topics = config.commands_topics ();
 
-    // in-memory state for validating commands
-    let mut state = HashMap::<Uuid, f64>::new();
+    // in-memory state for validating commands, keyed by tenant and aggregate id
+    let mut state = HashMap::<(String, Uuid), A>::new();
+    // the aggregate having each unique name, keyed by tenant and name
+    let mut names = HashMap::<(String, String), Uuid>::new();
     let producer = producer::init (&config);
-    let consumer = consumer::init (String::from (broker), String::from (commands_group_id));
+    let _registration = health::register (&health, "command-processor", None);
+    let consumer = consumer::init (&config, commands_group_id, "command-processor", health.clone ());
 
-    consumer.subscribe(&[&commands_topic])
+    let topics : Vec<&str> = commands_topics.iter ().map (|topic| topic.as_str ()).collect ();
+    consumer.subscribe(&topics)
         .expect("Can't subscribe to the specified topic");
 
     // NOTE : sets offset for replaying all commands on system restart, in a production system you would rather use the offset stored in Kafka
     // and a persistent state for command validation
-    let topic_map = hashmap!{
-        (String::from (commands_topic), 0) => Offset::Beginning
-    };
+    let topic_map : HashMap<(String, i32), Offset> = commands_topics.iter ()
+        .map (|topic| ((topic.clone (), 0), Offset::Beginning))
+        .collect ();
 
     let tpl : TopicPartitionList = TopicPartitionList::from_topic_map (&topic_map).unwrap ();
     consumer.assign (&tpl).expect ("Could not set topic partition list");
 
+    // the commands below the committed offsets were handled before the restart, see `enforced_expiry`
+    let committed : HashMap<(String, i32), i64> = consumer.committed_offsets (tpl.clone (), Duration::from_secs (30))
+        .expect ("Could not read the committed offsets")
+        .elements ()
+        .iter ()
+        .filter_map (|partition| match partition.offset () {
+            Offset::Offset (offset) => Some (((String::from (partition.topic ()), partition.partition ()), offset)),
+            _ => None
+        })
+        .collect ();
+    info!("Committed offsets of the commands topics: {:?}", committed);
+    consumer::confirm_assignment (&consumer, &tpl, &config, &health, "command-processor").await;
+
     loop {
 
         match consumer.recv().await {
-            // NOTE: panics if comands topic does not exist
-            Err(why) => panic!("Failed to read message from {:?} : {}", &tpl, why),
+            // NOTE: librdkafka recovers from broker errors on its own, only fatal errors stop the consumer
+            Err(why) => match consumer.client().fatal_error() {
+                Some ((code, reason)) => panic!("Fatal error reading from {:?} : {:?} {}", &tpl, code, reason),
+                None => warn!("Failed to read message from {:?} : {}", &tpl, why)
+            },
             Ok(m) => {
                 match m.payload_view::<str>() {
                     None => warn!("Empty command payload"),
@@ -50,22 +79,59 @@ pub async fn run (config : Arc<Config>) {
                         debug!("payload: {}", payload);
 
                         // TODO : read avro
-                        match serde_json::from_str::<Command>(payload) {
+                        match serde_json::from_str::<A::Command>(payload) {
                             Ok (command) => {
 
                                 info!("Received command: {:#?}, partition: {}, offset: {}, timestamp: {:?}", command, m.partition(), m.offset(), m.timestamp());
+                                metrics::COMMANDS_RECEIVED.with_label_values (&[command.name ()]).inc ();
+
+                                // commands written before envelopes were introduced have no headers,
+                                // a command whose expiry cannot be read is rejected since it cannot be enforced
+                                let (envelope, invalid_expiry) = match Envelope::from_headers (m.headers ()) {
+                                    Ok (envelope) => (envelope, None),
+                                    Err (why) => {
+                                        warn!("Command {} without valid envelope: {}", command.id (), why);
+                                        let envelope = Envelope::new (command.name (), &config, &command.id ().to_string ());
+                                        match envelope::expiry_headers (m.headers ()) {
+                                            Ok ((expires_at, deadline_ms)) => (Envelope { expires_at, deadline_ms, ..envelope }, None),
+                                            Err (why) => (envelope, Some (why))
+                                        }
+                                    }
+                                };
+                                debug!("Command envelope: {:?}", envelope);
 
-                                // run validation and emit events
-                                match command {
-                                    Command::CreateValue {id, data} => validate_create_value (id, data, &config.clone (), &mut state, producer.clone ()).await,
-                                    Command::UpdateValue {id, data} => validate_update_value (id, data, &config.clone (), &mut state, producer.clone ()).await,
+                                let span = tracing::info_span!("validate_command",
+                                                               command = command.name (),
+                                                               command_id = %command.id (),
+                                                               tenant = %command.tenant (),
+                                                               correlation_id = %envelope.correlation_id);
+                                span.set_parent (telemetry::extract (m.headers ()));
+
+                                match (from_tenant_topic (&command, m.topic (), &config), invalid_expiry) {
+                                    (false, _) => {
+                                        error!("command {} rejected: tenant {} does not write to topic {}", command.id (), command.tenant (), m.topic ());
+                                        metrics::COMMANDS_REJECTED.with_label_values (&[command.name ()]).inc ();
+                                    },
+                                    (true, Some (why)) => reject::<A> (&command, format!("command has an invalid expiry: {}", why), &envelope, &config, producer.clone ()).instrument (span).await,
+                                    // run validation and emit events
+                                    (true, None) => {
+                                        let committed = committed.get (&(String::from (m.topic ()), m.partition ()));
+                                        let expiry = enforced_expiry (&envelope, written_at (&m, &envelope), m.offset (), committed);
+                                        validate (command, &envelope, expiry, &config, &mut state, &mut names, producer.clone ()).instrument (span).await
+                                    }
                                 };
                             },
-                            Err (why) => error!("Could not deserialize command: {:?}", why)
+                            Err (why) => {
+                                error!("Could not deserialize command: {:?}", why);
+                                metrics::DESERIALIZATION_ERRORS.with_label_values (&["command-processor"]).inc ();
+                            }
                         };
 
                     },
-                    Some(Err(e)) => error!("Error while deserializing command payload: {:?}", e)
+                    Some(Err(e)) => {
+                        error!("Error while deserializing command payload: {:?}", e);
+                        metrics::DESERIALIZATION_ERRORS.with_label_values (&["command-processor"]).inc ();
+                    }
                 };
 
                 match consumer.commit_message(&m, CommitMode::Async) {
@@ -79,74 +145,244 @@ pub async fn run (config : Arc<Config>) {
     }
 }
 
-/// implements business logic
-async fn validate_create_value (
-    command_id: Uuid,
-    data : Value,
+/// with per tenant topics, a tenant's commands are only accepted from its own topic
+fn from_tenant_topic<C: AggregateCommand> (command: &C, topic: &str, config: &Config) -> bool {
+    topic == config.commands_topic_for (command.tenant ())
+}
+
+/// when the command was written to Kafka: the message timestamp, or the creation time of its envelope without one
+fn written_at (m: &BorrowedMessage, envelope: &Envelope) -> DateTime<Utc> {
+    m.timestamp ().to_millis ()
+        .and_then (DateTime::from_timestamp_millis)
+        .unwrap_or (envelope.created_at)
+}
+
+/// the expiry enforced on the command at `offset`, none for a command below the offset committed before the restart:
+/// it was handled then, and rejecting it now that it expired would change the state built from its events
+fn enforced_expiry (envelope: &Envelope, written_at: DateTime<Utc>, offset: i64, committed: Option<&i64>) -> Option<DateTime<Utc>> {
+    match committed {
+        Some (committed) if offset < *committed => None,
+        _ => envelope.expiry (written_at)
+    }
+}
+
+/// the aggregate decides the events of the command, which are applied to its state once written
+async fn validate<A: Aggregate> (
+    command: A::Command,
+    envelope: &Envelope,
+    expiry: Option<DateTime<Utc>>,
     config: &Config,
-    state : &mut HashMap<Uuid, f64>,
+    state : &mut HashMap<(String, Uuid), A>,
+    names : &mut HashMap<(String, String), Uuid>,
     producer : Producer
 ) {
 
-    info!("validating command {} with data {:?}", command_id, data);
-
-    let value_id = data.value_id;
-    match state.contains_key(&value_id) {
-        true => error!("command {} rejected: value with id {} already exists", command_id, value_id),
-        false => {
-            // emit event
-            let event_id = Uuid::new_v4();
-            let event = Event::ValueCreated {id: event_id,
-                                             parent: command_id,
-                                             data: data.clone ()};
-            let payload : String = serde_json::to_string(&event).expect ("Could not serialize event");
-            let producer = producer.lock().await;
-
-            match producer.send(FutureRecord::to(&config.events_topic)
-                                .payload(&payload)
-                                .key(&format!("{}", &event_id)),
-                                Duration::from_secs(0)).await {
-                Ok(_) => {
-                    info!("Succesfully sent event {:#?} to topic {}", event, &config.events_topic);
-                    state.insert (value_id, data.value);
-                },
-                Err(why) => warn!("Error sending event: {:#?}", why)
-            };
+    info!("validating command {:?}", command);
+
+    let key = (String::from (command.tenant ()), command.aggregate_id ());
+
+    match decide (&command, expiry, Utc::now (), state, names) {
+        Err (reason) => reject::<A> (&command, reason, envelope, config, producer).await,
+        Ok (events) => {
+            metrics::COMMANDS_ACCEPTED.with_label_values (&[command.name ()]).inc ();
+
+            // the events after one that could not be sent are dropped, the state has the ones sent
+            for event in events {
+                match send_event (&event, envelope, config, producer.clone ()).await {
+                    true => apply (state, names, &key, &event),
+                    false => break
+                }
+            }
         }
     }
 }
 
-/// implements business logic
-async fn validate_update_value (
-    command_id: Uuid,
-    data : UpdateOperation,
-    config: &Config,
-    state : &mut HashMap<Uuid, f64>,
-    producer : Producer
+/// applies the written event to the aggregate's state, and frees its previous name when the event renames it
+fn apply<A: Aggregate> (
+    state : &mut HashMap<(String, Uuid), A>,
+    names : &mut HashMap<(String, String), Uuid>,
+    key: &(String, Uuid),
+    event: &A::Event
 ) {
+    let aggregate = state.entry (key.clone ()).or_default ();
+    let previous = aggregate.name ().map (String::from);
+    aggregate.apply (event);
 
-    info!("validating command {} with data {:?}", command_id, data);
-
-    let value_id = data.value_id;
-    match state.contains_key(&value_id) {
-        false => error!("command {} rejected: value with id {} does not exist", command_id, value_id),
-        true => {
-            // emit event
-            let event_id = Uuid::new_v4();
-            let event = Event::ValueUpdated {id: event_id,
-                                             parent: command_id,
-                                             data: data.clone ()};
-
-            let payload : String = serde_json::to_string(&event).expect ("Could not serialize event");
-            let producer = producer.lock().await;
-
-            match producer.send(FutureRecord::to(&config.events_topic)
-                                .payload(&payload)
-                                .key(&format!("{}", &event_id)),
-                                Duration::from_secs(0)).await {
-                Ok(_) => info!("Succesfully sent event {:#?} to topic {}", event, &config.events_topic),
-                Err(why) => warn!("Error sending event: {:#?}", why),
-            };
+    let current = aggregate.name ();
+    if previous.as_deref () != current {
+        if let Some (previous) = previous {
+            names.remove (&(key.0.clone (), previous));
         }
+        if let Some (current) = current {
+            names.insert ((key.0.clone (), String::from (current)), key.1);
+        }
+    }
+}
+
+/// the events of the command, or why it is rejected: a command processed after its expiry,
+/// or giving a name another aggregate of its tenant has, is rejected without reaching the aggregate
+fn decide<A: Aggregate> (
+    command: &A::Command,
+    expiry: Option<DateTime<Utc>>,
+    now: DateTime<Utc>,
+    state : &HashMap<(String, Uuid), A>,
+    names : &HashMap<(String, String), Uuid>
+) -> Result<Vec<A::Event>, String> {
+
+    let owner = A::unique_name (command)
+        .and_then (|name| names.get (&(String::from (command.tenant ()), String::from (name))))
+        .filter (|owner| **owner != command.aggregate_id ());
+
+    match (expiry, owner, state.get (&(String::from (command.tenant ()), command.aggregate_id ()))) {
+        (Some (expiry), _, _) if expiry < now => {
+            metrics::COMMANDS_EXPIRED.with_label_values (&[command.name ()]).inc ();
+            Err (format!("command expired at {} before it was processed at {}", expiry.to_rfc3339 (), now.to_rfc3339 ()))
+        },
+        (_, Some (owner), _) => Err (format!("name {} is already used by aggregate {}", A::unique_name (command).unwrap_or_default (), owner)),
+        (_, None, Some (aggregate)) => aggregate.handle (command),
+        (_, None, None) => A::default ().handle (command)
+    }
+}
+
+/// writes the rejection of the command to the events topic
+async fn reject<A: Aggregate> (command: &A::Command, reason: String, envelope: &Envelope, config: &Config, producer: Producer) {
+    error!("command {} rejected: {}", command.id (), reason);
+    metrics::COMMANDS_REJECTED.with_label_values (&[command.name ()]).inc ();
+    send_event (&A::rejected (command, reason), envelope, config, producer).await;
+}
+
+/// writes the event to its tenant's events topic, returns whether it was acknowledged
+/// `command` is the envelope of the command that caused the event
+async fn send_event<E: AggregateEvent> (event: &E, command: &Envelope, config: &Config, producer: Producer) -> bool {
+
+    let event_id = event.id ();
+    let topic = config.events_topic_for (event.tenant ());
+    let span = tracing::info_span!("produce_event",
+                                   event = event.name (),
+                                   event_id = %event_id,
+                                   topic = %topic);
+
+    let payload : String = serde_json::to_string(event).expect ("Could not serialize event");
+    let envelope = Envelope {
+        tenant_id: Some (String::from (event.tenant ())),
+        ..command.caused (event.name (), event.parent (), config)
+    };
+    let headers = span.in_scope (|| envelope.to_headers ());
+
+    async {
+        let producer = producer.lock().await;
+        let started = Instant::now ();
+
+        let result = producer.send(FutureRecord::to(&topic)
+                                   .payload(&payload)
+                                   .key(&format!("{}", &event_id))
+                                   .headers(headers),
+                                   Duration::from_secs(0)).await;
+        metrics::record_send (&topic, started, result.is_ok ());
+
+        match result {
+            Ok(_) => {
+                info!("Succesfully sent event {:#?} to topic {}", event, &topic);
+                metrics::EVENTS_PRODUCED.with_label_values (&[event.name ()]).inc ();
+                true
+            },
+            Err(why) => {
+                warn!("Error sending event: {:#?}", why);
+                false
+            }
+        }
+    }.instrument (span).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands_schema::{Command, Relabel, Value};
+    use crate::config::tests::config;
+    use crate::inputs_schema::Labels;
+    use crate::number::{Number, ValueType};
+    use crate::value::ValueAggregate;
+
+    fn create (tenant: &str, value_id: Uuid, name: Option<&str>) -> Command {
+        Command::CreateValue {id: Uuid::new_v4 (),
+                              tenant: String::from (tenant),
+                              data: Value {value_id,
+                                           value: Number::Integer (1),
+                                           value_type: ValueType::default (),
+                                           constraints: None,
+                                           labels: Labels {name: name.map (String::from), ..Labels::default ()}}}
+    }
+
+    fn relabel (tenant: &str, value_id: Uuid, name: &str) -> Command {
+        Command::RelabelValue {id: Uuid::new_v4 (),
+                               tenant: String::from (tenant),
+                               data: Relabel {value_id, labels: Labels {name: Some (String::from (name)), ..Labels::default ()}}}
+    }
+
+    #[test]
+    fn rejects_the_commands_of_another_tenants_topic () {
+        let config = config (&["KAFKA_COMMANDS_TOPICS=commands", "TENANTS=acme,globex", "TENANT_TOPIC_PREFIX=true"]);
+        let command = relabel ("acme", Uuid::new_v4 (), "savings");
+
+        assert!(from_tenant_topic (&command, "acme.commands", &config));
+        assert!(!from_tenant_topic (&command, "globex.commands", &config));
+        assert!(!from_tenant_topic (&command, "commands", &config));
+    }
+
+    #[test]
+    fn replays_an_expired_command_accepted_before_the_restart () {
+        let written_at = Utc::now () - chrono::Duration::hours (1);
+        let envelope = Envelope { expires_at: Some (written_at + chrono::Duration::seconds (1)),
+                                  ..Envelope::new ("CreateValue", &config (&[]), "replay") };
+        let command = create ("acme", Uuid::new_v4 (), None);
+        let (state, names) = (HashMap::<(String, Uuid), ValueAggregate>::new (), HashMap::new ());
+
+        // handled before the restart: accepted again
+        let expiry = enforced_expiry (&envelope, written_at, 3, Some (&4));
+        assert_eq!(expiry, None);
+        assert_eq!(decide (&command, expiry, Utc::now (), &state, &names).unwrap ().len (), 1);
+
+        // not handled yet: rejected
+        for committed in [None, Some (&3)] {
+            let expiry = enforced_expiry (&envelope, written_at, 3, committed);
+            assert!(decide (&command, expiry, Utc::now (), &state, &names).unwrap_err ().contains ("expired"));
+        }
+    }
+
+    /// decides the command and applies its events, as `validate` does once they are written
+    fn handle (command: &Command, state: &mut HashMap<(String, Uuid), ValueAggregate>, names: &mut HashMap<(String, String), Uuid>) -> Result<(), String> {
+        let key = (String::from (command.tenant ()), command.aggregate_id ());
+        for event in decide (command, None, Utc::now (), state, names)? {
+            apply (state, names, &key, &event);
+        }
+        Ok (())
+    }
+
+    #[test]
+    fn names_are_unique_per_tenant () {
+        let (mut state, mut names) = (HashMap::new (), HashMap::new ());
+        let (savings, other) = (Uuid::new_v4 (), Uuid::new_v4 ());
+
+        handle (&create ("acme", savings, Some ("savings")), &mut state, &mut names).unwrap ();
+        assert_eq!(names.get (&(String::from ("acme"), String::from ("savings"))), Some (&savings));
+
+        let taken = handle (&create ("acme", other, Some ("savings")), &mut state, &mut names).unwrap_err ();
+        assert!(taken.contains ("already used"), "{}", taken);
+        assert!(handle (&relabel ("acme", savings, "savings"), &mut state, &mut names).is_ok ());
+        assert!(handle (&create ("globex", other, Some ("savings")), &mut state, &mut names).is_ok ());
+    }
+
+    #[test]
+    fn relabeling_frees_the_previous_name () {
+        let (mut state, mut names) = (HashMap::new (), HashMap::new ());
+        let (savings, other) = (Uuid::new_v4 (), Uuid::new_v4 ());
+
+        handle (&create ("acme", savings, Some ("savings")), &mut state, &mut names).unwrap ();
+        handle (&relabel ("acme", savings, "checking"), &mut state, &mut names).unwrap ();
+        assert_eq!(names.get (&(String::from ("acme"), String::from ("savings"))), None);
+        assert_eq!(names.get (&(String::from ("acme"), String::from ("checking"))), Some (&savings));
+
+        assert!(handle (&create ("acme", other, Some ("savings")), &mut state, &mut names).is_ok ());
+        assert!(handle (&relabel ("acme", other, "checking"), &mut state, &mut names).unwrap_err ().contains ("already used"));
     }
 }
@@ -1,68 +1,269 @@
+use crate::aggregate::AggregateCommand;
+use crate::auth::Principal;
 use crate::config::{Config};
+use crate::envelope::Envelope;
+use crate::errors::ApiError;
+use crate::metrics;
+use crate::process_manager::SagaRecord;
+use crate::process_manager;
 use crate::producer::Producer;
-use crate::commands_schema::{Command, Value, UpdateOperation};
-use crate::inputs_schema::{ ValueInput, ValueOperationInput };
+use crate::scheduler::{Owner, Schedule, ScheduleMessage, ScheduleStatus};
+use crate::scheduler;
+use crate::commands_schema::{Command, Relabel, Value, UpdateOperation};
+use crate::transfer::Transfer;
+use crate::inputs_schema::{ Expiry, Labels, ScheduleInput, TransferInput, ValueInput, ValueOperationInput };
+use chrono::Utc;
 use log::{info, warn};
 use rdkafka::producer::FutureRecord;
-use std::convert::Infallible;
+use tracing::Instrument;
 use uuid::Uuid;
 use warp::http::StatusCode;
-use serde_json;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 // TODO : serialize as avro
 pub async fn create_value(
+    principal: Principal,
     initial_value: ValueInput,
+    correlation_id: String,
     producer: Producer,
     config: Config
-) -> Result<impl warp::Reply, Infallible> {
+) -> Result<impl warp::Reply, warp::Rejection> {
 
-    info!("Create value {:#?}", initial_value);
+    let value_id = create (&principal, initial_value, &correlation_id, producer, &config).await
+        .map_err (warp::reject::custom)?;
+
+    Ok(warp::reply::with_header(warp::reply::with_status(warp::reply::json(&value_id),
+                                                         warp::http::StatusCode::ACCEPTED),
+                                "x-correlation-id", correlation_id))
+}
+
+pub async fn update_value(
+    value_id: Uuid,
+    principal: Principal,
+    operation : ValueOperationInput,
+    correlation_id: String,
+    producer: Producer,
+    config: Config
+) -> Result<impl warp::Reply, warp::Rejection> {
+
+    update (&principal, value_id, operation, &correlation_id, producer, &config).await
+        .map_err (warp::reject::custom)?;
+
+    Ok(warp::reply::with_header(StatusCode::ACCEPTED, "x-correlation-id", correlation_id))
+}
+
+/// produces a RelabelValue command, the command processor rejects it when another value has the name
+pub async fn relabel_value(
+    value_id: Uuid,
+    principal: Principal,
+    labels: Labels,
+    correlation_id: String,
+    producer: Producer,
+    config: Config
+) -> Result<impl warp::Reply, warp::Rejection> {
+
+    relabel (&principal, value_id, labels, &correlation_id, producer, &config).await
+        .map_err (warp::reject::custom)?;
+
+    Ok(warp::reply::with_header(StatusCode::ACCEPTED, "x-correlation-id", correlation_id))
+}
+
+pub async fn create_transfer(
+    principal: Principal,
+    input: TransferInput,
+    correlation_id: String,
+    producer: Producer,
+    config: Config
+) -> Result<impl warp::Reply, warp::Rejection> {
+
+    info!("Transfer {:#?} for {}", input, principal.subject);
+
+    input.validate ().map_err (|why| warp::reject::custom (ApiError::Unprocessable (why)))?;
+
+    let record = SagaRecord::new (principal.tenant (), &principal.subject, &correlation_id, Transfer::new (input));
+    process_manager::request (&record, producer, &config).await
+        .map_err (warp::reject::custom)?;
+
+    Ok(warp::reply::with_header(warp::reply::with_status(warp::reply::json(&record.id),
+                                                         warp::http::StatusCode::ACCEPTED),
+                                "x-correlation-id", correlation_id))
+}
+
+pub async fn schedule_value(
+    value_id: Uuid,
+    principal: Principal,
+    input: ScheduleInput,
+    correlation_id: String,
+    producer: Producer,
+    config: Config
+) -> Result<impl warp::Reply, warp::Rejection> {
+
+    info!("Schedule {:#?} on value {} for {}", input, value_id, principal.subject);
+
+    let now = Utc::now ();
+    let next_run = input.validate (now)
+        .and_then (|_| scheduler::first_run (&input, now))
+        .map_err (|why| warp::reject::custom (ApiError::Unprocessable (why)))?;
+
+    let schedule = Schedule {schedule_id: Uuid::new_v4 (),
+                             value_id,
+                             operation: input.operation,
+                             value: input.value,
+                             cron: input.cron,
+                             next_run: Some (next_run),
+                             last_run: None,
+                             runs: 0,
+                             status: ScheduleStatus::Active,
+                             created_at: now,
+                             owner: Some (Owner {tenant: String::from (principal.tenant ()),
+                                                 user_id: principal.subject.clone (),
+                                                 correlation_id: correlation_id.clone ()})};
+    let schedule_id = schedule.schedule_id;
+    let message = ScheduleMessage::ScheduleRequested {tenant: String::from (principal.tenant ()), schedule};
+    scheduler::write (&message, &principal.subject, &correlation_id, producer, &config).await
+        .map_err (warp::reject::custom)?;
+
+    Ok(warp::reply::with_header(warp::reply::with_status(warp::reply::json(&schedule_id),
+                                                         warp::http::StatusCode::ACCEPTED),
+                                "x-correlation-id", correlation_id))
+}
+
+pub async fn cancel_schedule(
+    schedule_id: Uuid,
+    principal: Principal,
+    correlation_id: String,
+    producer: Producer,
+    config: Config
+) -> Result<impl warp::Reply, warp::Rejection> {
+
+    info!("Cancel schedule {} for {}", schedule_id, principal.subject);
+
+    let message = ScheduleMessage::CancelRequested {tenant: String::from (principal.tenant ()),
+                                                    schedule_id,
+                                                    user_id: principal.subject.clone ()};
+    scheduler::write (&message, &principal.subject, &correlation_id, producer, &config).await
+        .map_err (warp::reject::custom)?;
+
+    Ok(warp::reply::with_header(StatusCode::ACCEPTED, "x-correlation-id", correlation_id))
+}
+
+/// validates the input and produces a CreateValue command, returns the id of the value to create,
+/// shared by the REST and GraphQL APIs
+pub async fn create (
+    principal: &Principal,
+    initial_value: ValueInput,
+    correlation_id: &str,
+    producer: Producer,
+    config: &Config
+) -> Result<Uuid, ApiError> {
+
+    info!("Create value {:#?} for {}", initial_value, principal.subject);
+
+    initial_value.validate ().map_err (ApiError::Unprocessable)?;
 
-    let producer = producer.lock().await;
     let command_id = Uuid::new_v4();
     let value_id = Uuid::new_v4();
     let command = Command::CreateValue {id: command_id,
-                                        data: Value {value_id : value_id,
-                                                     value : initial_value.value}};
-    let payload : String = serde_json::to_string(&command).expect ("Could not serialize command");
-
-    match producer.send(FutureRecord::to(&config.commands_topic)
-                        .payload(&payload)
-                        .key(&format!("{}", &command_id)),
-                        Duration::from_secs(0)).await {
-        Ok(_) => info!("Succesfully sent command {:#?} to topic {}", command, &config.commands_topic),
-        Err(why) => warn!("Error sending command: {:#?}", why)
-    };
+                                        tenant: String::from (principal.tenant ()),
+                                        data: Value {value_id,
+                                                     value : initial_value.value.clone (),
+                                                     value_type: initial_value.value_type,
+                                                     constraints: initial_value.constraints.clone (),
+                                                     labels: initial_value.labels.clone ()}};
 
-    Ok(warp::reply::with_status(warp::reply::json(&value_id),
-                                warp::http::StatusCode::ACCEPTED))
+    send (&command, &initial_value.expiry, correlation_id, &principal.subject, producer, config).await?;
+    Ok (value_id)
 }
 
-pub async fn update_value(
+/// validates the input and produces an UpdateValue command, shared by the REST and GraphQL APIs
+pub async fn update (
+    principal: &Principal,
     value_id: Uuid,
     operation : ValueOperationInput,
+    correlation_id: &str,
     producer: Producer,
-    config: Config
-) -> Result<impl warp::Reply, Infallible> {
+    config: &Config
+) -> Result<(), ApiError> {
+
+    info!("Update value {:#?} with {:#?} for {}", value_id, operation, principal.subject);
 
-    info!("Update value {:#?} with {:#?}", value_id, operation);
+    operation.validate ().map_err (ApiError::Unprocessable)?;
 
-    let producer = producer.lock().await;
     let command_id = Uuid::new_v4();
     let command = Command::UpdateValue {id: command_id,
-                                        data : UpdateOperation {value_id: value_id,
+                                        tenant: String::from (principal.tenant ()),
+                                        data : UpdateOperation {value_id,
                                                                 operation: operation.operation,
                                                                 value: operation.value}};
-    let payload : String = serde_json::to_string(&command).expect ("Could not serialize command");
-
-    match producer.send(FutureRecord::to(&config.commands_topic)
-                        .payload(&payload)
-                        .key(&format!("{}", &command_id)),
-                        Duration::from_secs(0)).await {
-        Ok(_) => info!("Succesfully sent command {:#?} to topic {}", command, &config.commands_topic),
-        Err(why) => warn!("Error sending command: {:#?}", why)
+
+    send (&command, &operation.expiry, correlation_id, &principal.subject, producer, config).await
+}
+
+/// validates the labels and produces a RelabelValue command, the command processor rejects it
+/// when another value has the name, shared by the REST and gRPC APIs
+pub async fn relabel (
+    principal: &Principal,
+    value_id: Uuid,
+    labels: Labels,
+    correlation_id: &str,
+    producer: Producer,
+    config: &Config
+) -> Result<(), ApiError> {
+
+    info!("Relabel value {} with {:#?} for {}", value_id, labels, principal.subject);
+
+    labels.validate ().map_err (ApiError::Unprocessable)?;
+
+    let command = Command::RelabelValue {id: Uuid::new_v4 (),
+                                         tenant: String::from (principal.tenant ()),
+                                         data: Relabel {value_id, labels}};
+    send (&command, &Expiry::default (), correlation_id, &principal.subject, producer, config).await
+}
+
+/// writes the command to the commands topic with its envelope and the trace context in the message headers,
+/// `user_id` is recorded as the command's user, the command goes to its tenant's topic,
+/// the command processor rejects it once `expiry` has passed, fails when Kafka does not acknowledge the command
+pub async fn send (command: &Command, expiry: &Expiry, correlation_id: &str, user_id: &str, producer: Producer, config: &Config) -> Result<(), ApiError> {
+
+    let command_id = command.id ();
+    let topic = config.commands_topic_for (command.tenant ());
+    let span = tracing::info_span!("produce_command",
+                                   command = command.name (),
+                                   command_id = %command_id,
+                                   correlation_id = %correlation_id,
+                                   tenant = %command.tenant (),
+                                   topic = %topic);
+
+    let payload : String = serde_json::to_string(command).expect ("Could not serialize command");
+    let envelope = Envelope {
+        user_id: Some (String::from (user_id)),
+        tenant_id: Some (String::from (command.tenant ())),
+        expires_at: expiry.expires_at,
+        deadline_ms: expiry.deadline_ms,
+        ..Envelope::new (command.name (), config, correlation_id)
     };
+    let headers = span.in_scope (|| envelope.to_headers ());
+
+    async {
+        let producer = producer.lock().await;
+
+        let started = Instant::now ();
+        let result = producer.send(FutureRecord::to(&topic)
+                                   .payload(&payload)
+                                   .key(&format!("{}", &command_id))
+                                   .headers(headers),
+                                   Duration::from_secs(0)).await;
+        metrics::record_send (&topic, started, result.is_ok ());
 
-    Ok(StatusCode::ACCEPTED)
+        match result {
+            Ok(_) => {
+                info!("Succesfully sent command {:#?} to topic {}", command, &topic);
+                Ok (())
+            },
+            Err((why, _)) => {
+                warn!("Error sending command: {:#?}", why);
+                Err (why.into ())
+            }
+        }
+    }.instrument (span).await
 }
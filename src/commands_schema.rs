@@ -1,23 +1,88 @@
+use crate::aggregate::AggregateCommand;
 use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
 use uuid::Uuid;
-use crate::inputs_schema::{ OperationType };
+use crate::inputs_schema::{ Constraints, Labels, OperationType };
+use crate::number::{Number, ValueType};
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
+/// tenant of the requests that name none, and of the messages written before tenants were introduced
+pub const DEFAULT_TENANT: &str = "default";
+
+pub fn default_tenant () -> String {
+    String::from (DEFAULT_TENANT)
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema)]
 pub struct Value {
+    #[schema(value_type = String, format = Uuid)]
     pub value_id: Uuid,
-    pub value: f64,
+    pub value: Number,
+    /// a float for the values created before value types were introduced
+    #[serde(default)]
+    pub value_type: ValueType,
+    /// given at the creation of the value, not returned by the queries
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(ignore)]
+    pub constraints: Option<Constraints>,
+    #[serde(flatten)]
+    pub labels: Labels,
+}
+
+/// the new labels of a value, replacing all of its labels
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Relabel {
+    pub value_id: Uuid,
+    #[serde(flatten)]
+    pub labels: Labels,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct UpdateOperation {
     pub value_id: Uuid,
     pub operation: OperationType,
-    pub value: f64,
+    pub value: Number,
 }
 
+// the variant names are the `action` of the commands in the topic
+#[allow(clippy::enum_variant_names)]
 #[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(tag = "action")]
 pub enum Command {
-    CreateValue { id: Uuid, data: Value },
-    UpdateValue { id: Uuid, data: UpdateOperation }
+    CreateValue { id: Uuid, #[serde(default = "default_tenant")] tenant: String, data: Value },
+    UpdateValue { id: Uuid, #[serde(default = "default_tenant")] tenant: String, data: UpdateOperation },
+    RelabelValue { id: Uuid, #[serde(default = "default_tenant")] tenant: String, data: Relabel }
+}
+
+impl AggregateCommand for Command {
+    fn name (&self) -> &'static str {
+        match self {
+            Command::CreateValue { .. } => "CreateValue",
+            Command::UpdateValue { .. } => "UpdateValue",
+            Command::RelabelValue { .. } => "RelabelValue"
+        }
+    }
+
+    fn id (&self) -> Uuid {
+        match self {
+            Command::CreateValue { id, .. } => *id,
+            Command::UpdateValue { id, .. } => *id,
+            Command::RelabelValue { id, .. } => *id
+        }
+    }
+
+    fn tenant (&self) -> &str {
+        match self {
+            Command::CreateValue { tenant, .. } => tenant,
+            Command::UpdateValue { tenant, .. } => tenant,
+            Command::RelabelValue { tenant, .. } => tenant
+        }
+    }
+
+    fn aggregate_id (&self) -> Uuid {
+        match self {
+            Command::CreateValue { data, .. } => data.value_id,
+            Command::UpdateValue { data, .. } => data.value_id,
+            Command::RelabelValue { data, .. } => data.value_id
+        }
+    }
 }
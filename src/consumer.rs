@@ -1,34 +1,99 @@
-use log::debug;
-use rdkafka::client::ClientContext;
-use rdkafka::config::{ClientConfig, RDKafkaLogLevel};
+use crate::config::Config;
+use crate::context::CustomContext;
+use crate::context;
+use crate::health::Health;
+use crate::health;
+use log::warn;
+use rdkafka::config::RDKafkaLogLevel;
+use rdkafka::consumer::{BaseConsumer, Consumer};
 use rdkafka::consumer::stream_consumer::StreamConsumer;
-use rdkafka::consumer::ConsumerContext;
 use rdkafka::error::KafkaResult;
 use rdkafka::topic_partition_list::TopicPartitionList;
+use std::time::Duration;
 
-pub struct CustomContext;
+pub type CustomConsumer = StreamConsumer<CustomContext>;
 
-impl ClientContext for CustomContext {}
+/// wait between two attempts to reach the assigned partitions
+const RETRY_INTERVAL: Duration = Duration::from_secs (1);
 
-impl ConsumerContext for CustomContext {
-    fn commit_callback(&self, _result: KafkaResult<()>, offsets: &TopicPartitionList) {
-        debug!("Committing offsets {:?}", offsets);
-    }
+pub fn init (config : &Config, group_id : &str, name : &str, health : Health) -> CustomConsumer {
+
+    let context = context::init (name, config, Some (health));
+
+    let mut client_config = context::client_config (config);
+    client_config
+        .set("group.id", group_id)
+        .set("enable.partition.eof", "false")
+        .set("session.timeout.ms", config.session_timeout_ms.to_string ())
+        .set("statistics.interval.ms", config.statistics_interval_ms.to_string ()) // reports consumer lag to the health checks and metrics
+        .set("enable.auto.commit", "false") // only commit the offsets explicitly
+        .set_log_level(RDKafkaLogLevel::Debug);
+
+    context::apply_properties (&mut client_config, config, &config.kafka_consumer);
+
+    client_config
+        .create_with_context(context)
+        .expect("Consumer creation failed")
 }
 
-pub type CustomConsumer = StreamConsumer<CustomContext>;
+/// marks the consumer as assigned once the brokers report the watermarks of all its partitions,
+/// retrying until they do, e.g. while the brokers are unreachable or the topics not created yet
+pub async fn confirm_assignment (consumer: &CustomConsumer, tpl: &TopicPartitionList, config: &Config, health: &Health, name: &str) {
 
-pub fn init (broker : String, group_id : String) -> CustomConsumer {
+    let timeout = Duration::from_millis (config.message_timeout_ms);
+    loop {
+        let watermarks = tpl.elements ().iter ()
+            .map (|partition| tokio::task::block_in_place (|| consumer.fetch_watermarks (partition.topic (), partition.partition (), timeout)))
+            .collect::<KafkaResult<Vec<(i64, i64)>>> ();
+        match watermarks {
+            Ok (_) => break,
+            Err (why) => {
+                warn!("Could not reach the partitions of {}, retrying: {}", name, why);
+                tokio::time::sleep (RETRY_INTERVAL).await;
+            }
+        }
+    }
+    health::update (health, name, |status| status.assigned = true);
+}
+
+/// consumer that never joins its group, used to read and commit the group's offsets
+pub fn group_client (config : &Config, group_id : &str) -> BaseConsumer<CustomContext> {
 
-    let context = CustomContext;
+    let context = context::init ("admin", config, None);
 
-    ClientConfig::new()
+    let mut client_config = context::client_config (config);
+    client_config
         .set("group.id", group_id)
-        .set("bootstrap.servers", broker)
-        .set("enable.partition.eof", "false")
-        .set("session.timeout.ms", "6000")
-        .set("enable.auto.commit", "false") // only commit the offsets explicitly
-        .set_log_level(RDKafkaLogLevel::Debug)
+        .set("enable.auto.commit", "false")
+        .set_log_level(RDKafkaLogLevel::Debug);
+
+    context::apply_properties (&mut client_config, config, &config.kafka_consumer);
+
+    client_config
         .create_with_context(context)
         .expect("Consumer creation failed")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::tests::config;
+    use rdkafka::topic_partition_list::Offset;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn is_not_assigned_until_the_partitions_are_reached () {
+        let config = config (&["KAFKA_BROKER=localhost:9", "KAFKA_MESSAGE_TIMEOUT_MS=100"]);
+        let health = health::init ();
+        let _registration = health::register (&health, "projection", None);
+        let consumer = init (&config, "group", "projection", health.clone ());
+
+        let mut tpl = TopicPartitionList::new ();
+        tpl.add_partition_offset ("events", 0, Offset::Beginning).unwrap ();
+        consumer.assign (&tpl).unwrap ();
+
+        let confirmed = tokio::time::timeout (Duration::from_millis (500),
+                                              confirm_assignment (&consumer, &tpl, &config, &health, "projection")).await;
+        assert!(confirmed.is_err ());
+        assert!(!health::consumers (&health)["projection"].assigned);
+    }
+}
@@ -0,0 +1,138 @@
+use crate::config::Config;
+use crate::health::Health;
+use crate::health;
+use crate::metrics;
+use log::{debug, info};
+use rdkafka::client::{ClientContext, OAuthToken};
+use rdkafka::config::ClientConfig;
+use rdkafka::consumer::ConsumerContext;
+use rdkafka::error::KafkaResult;
+use rdkafka::statistics::Statistics;
+use rdkafka::topic_partition_list::TopicPartitionList;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// supplies SASL/OAUTHBEARER tokens, called by librdkafka before the current token expires
+pub trait TokenProvider: Send + Sync {
+    fn token (&self, oauthbearer_config: Option<&str>) -> Result<OAuthToken, Box<dyn Error>>;
+}
+
+/// reads the token from a file on every refresh, e.g. one kept up to date by a sidecar
+pub struct FileTokenProvider {
+    pub path: String,
+    pub principal: String,
+    pub lifetime: Duration,
+}
+
+impl TokenProvider for FileTokenProvider {
+    fn token (&self, _oauthbearer_config: Option<&str>) -> Result<OAuthToken, Box<dyn Error>> {
+        let token = fs::read_to_string (&self.path)
+            .map_err (|why| format!("Could not read OAUTHBEARER token from {}: {}", self.path, why))?;
+        let expires_at = SystemTime::now ().duration_since (UNIX_EPOCH)? + self.lifetime;
+
+        info!("Refreshed OAUTHBEARER token from {}", self.path);
+        Ok (OAuthToken {
+            token: String::from (token.trim ()),
+            principal_name: self.principal.clone (),
+            lifetime_ms: expires_at.as_millis () as i64,
+        })
+    }
+}
+
+/// context shared by the producers, consumers and the admin client
+pub struct CustomContext {
+    pub name: String,
+    /// consumers report their lag to the health checks
+    pub health: Option<Health>,
+    pub token_provider: Option<Arc<dyn TokenProvider>>,
+}
+
+impl ClientContext for CustomContext {
+    // a compile time constant, librdkafka only asks for tokens when the mechanism is OAUTHBEARER,
+    // which is the only one `init` sets up a token provider for
+    const ENABLE_REFRESH_OAUTH_TOKEN: bool = true;
+
+    /// records the consumer lag and client statistics reported by librdkafka
+    fn stats(&self, statistics: Statistics) {
+        metrics::record_statistics (&self.name, &statistics);
+
+        let mut lag = Some (0);
+        for topic in statistics.topics.values () {
+            // partition -1 is librdkafka's internal unassigned partition
+            for partition in topic.partitions.values ().filter (|p| p.partition >= 0 && p.desired) {
+                lag = match (lag, partition.consumer_lag) {
+                    (Some (total), l) if l >= 0 => Some (total + l),
+                    // nothing consumed yet from an empty partition
+                    (Some (total), _) if partition.hi_offset <= 0 => Some (total),
+                    _ => None
+                };
+            }
+        }
+        debug!("Client {} lag: {:?}", self.name, lag);
+
+        if let Some (health) = &self.health {
+            health::update (health, &self.name, |status| status.lag = lag);
+        }
+    }
+
+    fn generate_oauth_token(&self, oauthbearer_config: Option<&str>) -> Result<OAuthToken, Box<dyn Error>> {
+        match &self.token_provider {
+            Some (provider) => provider.token (oauthbearer_config),
+            None => Err ("No OAUTHBEARER token provider configured".into ())
+        }
+    }
+}
+
+impl ConsumerContext for CustomContext {
+    fn commit_callback(&self, _result: KafkaResult<()>, offsets: &TopicPartitionList) {
+        debug!("Committing offsets {:?}", offsets);
+    }
+}
+
+pub fn init (name : &str, config : &Config, health : Option<Health>) -> CustomContext {
+
+    let token_provider : Option<Arc<dyn TokenProvider>> = match (config.sasl_mechanism.as_deref (), &config.sasl_oauthbearer_token_file) {
+        (Some ("OAUTHBEARER"), Some (path)) => Some (Arc::new (FileTokenProvider {
+            path: path.clone (),
+            principal: config.sasl_oauthbearer_principal.clone (),
+            lifetime: Duration::from_millis (config.sasl_oauthbearer_lifetime_ms),
+        })),
+        _ => None
+    };
+
+    CustomContext { name: String::from (name), health, token_provider }
+}
+
+/// client config with the brokers and the TLS and SASL settings, common to every client
+pub fn client_config (config : &Config) -> ClientConfig {
+
+    let mut client_config = ClientConfig::new ();
+    client_config
+        .set("bootstrap.servers", &config.broker)
+        .set("security.protocol", &config.security_protocol);
+
+    let optional = vec![("ssl.ca.location", config.ssl_ca_location.as_ref ()),
+                        ("ssl.certificate.location", config.ssl_certificate_location.as_ref ()),
+                        ("ssl.key.location", config.ssl_key_location.as_ref ()),
+                        ("ssl.key.password", config.ssl_key_password.as_ref ().map (|secret| &secret.0)),
+                        ("sasl.mechanism", config.sasl_mechanism.as_ref ()),
+                        ("sasl.username", config.sasl_username.as_ref ()),
+                        ("sasl.password", config.sasl_password.as_ref ().map (|secret| &secret.0))];
+    for (key, value) in optional {
+        if let Some (value) = value {
+            client_config.set(key, value);
+        }
+    }
+
+    client_config
+}
+
+/// applies the pass-through properties, the client's own section overrides the shared one
+pub fn apply_properties (client_config : &mut ClientConfig, config : &Config, properties : &BTreeMap<String, String>) {
+    for (key, value) in config.kafka_client.iter ().chain (properties.iter ()) {
+        client_config.set(key, value);
+    }
+}
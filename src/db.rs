@@ -1,24 +1,52 @@
+use crate::inputs_schema::Labels;
+use crate::number::{Number, ValueType};
 use std::collections::HashMap;
 use std::sync::Arc;
 use tokio::sync::Mutex;
 use uuid::Uuid;
 
-pub type Db = Arc<Mutex<HashMap<Uuid, f64>>>;
+/// a value of the view with its type, needed to apply its updates, and its labels
+#[derive(Clone, Debug)]
+pub struct Record {
+    pub value: Number,
+    pub value_type: ValueType,
+    pub labels: Labels,
+}
+
+/// values keyed by tenant and value id
+pub type Db = Arc<Mutex<HashMap<(String, Uuid), Record>>>;
 
 /// atomic, thread safe in-memory db
 pub fn init () -> Db {
     Arc::new(Mutex::new(HashMap::new()))
 }
 
-pub async fn insert (db: &Db, key : Uuid , value : f64) {
+pub async fn insert (db: &Db, tenant: &str, key : Uuid , record : Record) {
     let mut db = db.lock().await;
-    db.insert (key, value);
+    db.insert ((String::from (tenant), key), record);
+}
+
+/// the tenant's value, `None` for the values of other tenants
+pub async fn get (db: &Db, tenant: &str, key : &Uuid) -> Option<Record> {
+    let db = db.lock().await;
+    db.get (&(String::from (tenant), *key)).cloned ()
+}
+
+/// the tenant's values, ordered by id
+pub async fn list (db: &Db, tenant: &str) -> Vec<(Uuid, Record)> {
+    let db = db.lock().await;
+    let mut values : Vec<(Uuid, Record)> = db.iter ()
+        .filter (|((value_tenant, _), _)| value_tenant == tenant)
+        .map (|((_, key), record)| (*key, record.clone ()))
+        .collect ();
+    values.sort_by_key (|(key, _)| *key);
+    values
 }
 
-pub async fn get (db: &Db, key : &Uuid) -> Option<f64> {
+/// the tenant's value with this name
+pub async fn find_by_name (db: &Db, tenant: &str, name: &str) -> Option<(Uuid, Record)> {
     let db = db.lock().await;
-    match db.get (key) {
-        None => None,
-        Some (v) => Some (*v)
-    }
+    db.iter ()
+        .find (|((value_tenant, _), record)| value_tenant == tenant && record.labels.name.as_deref () == Some (name))
+        .map (|((_, key), record)| (*key, record.clone ()))
 }
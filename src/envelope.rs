@@ -0,0 +1,258 @@
+use crate::config::Config;
+use crate::telemetry;
+use chrono::{DateTime, Utc};
+use rdkafka::message::{BorrowedHeaders, Header, Headers, OwnedHeaders};
+use uuid::Uuid;
+
+pub const MESSAGE_TYPE_HEADER: &str = "message_type";
+pub const SCHEMA_VERSION_HEADER: &str = "schema_version";
+pub const CONTENT_TYPE_HEADER: &str = "content_type";
+pub const PRODUCER_HEADER: &str = "producer";
+pub const CAUSATION_ID_HEADER: &str = "causation_id";
+pub const CORRELATION_ID_HEADER: &str = "correlation_id";
+pub const CREATED_AT_HEADER: &str = "created_at";
+pub const USER_ID_HEADER: &str = "user_id";
+pub const TENANT_ID_HEADER: &str = "tenant_id";
+pub const EXPIRES_AT_HEADER: &str = "expires_at";
+pub const DEADLINE_MS_HEADER: &str = "deadline_ms";
+
+/// version of the JSON payloads in `commands_schema` and `events_schema`
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// longest correlation id accepted from a client
+pub const MAX_CORRELATION_ID_LENGTH: usize = 128;
+
+/// metadata written in the headers of every command and event,
+/// lets consumers and Kafka Connect sinks route messages without parsing the payload
+#[derive(Clone, Debug, PartialEq)]
+pub struct Envelope {
+    pub message_type: String,
+    pub schema_version: u32,
+    pub content_type: String,
+    /// service that produced the message
+    pub producer: String,
+    /// id of the message that caused this one, the command for an event
+    pub causation_id: Option<Uuid>,
+    /// shared by all the messages caused by one request
+    pub correlation_id: String,
+    pub created_at: DateTime<Utc>,
+    pub user_id: Option<String>,
+    pub tenant_id: Option<String>,
+    /// a command processed after this time is rejected
+    pub expires_at: Option<DateTime<Utc>>,
+    /// a command processed more than this many milliseconds after its message timestamp is rejected
+    pub deadline_ms: Option<u64>,
+}
+
+impl Envelope {
+    pub fn new (message_type: &str, config: &Config, correlation_id: &str) -> Envelope {
+        Envelope {
+            message_type: String::from (message_type),
+            schema_version: SCHEMA_VERSION,
+            content_type: String::from ("application/json"),
+            producer: service_name (config),
+            causation_id: None,
+            correlation_id: String::from (correlation_id),
+            created_at: Utc::now (),
+            user_id: None,
+            tenant_id: None,
+            expires_at: None,
+            deadline_ms: None,
+        }
+    }
+
+    /// envelope for a message caused by the message with this envelope
+    pub fn caused (&self, message_type: &str, causation_id: Uuid, config: &Config) -> Envelope {
+        Envelope {
+            causation_id: Some (causation_id),
+            user_id: self.user_id.clone (),
+            tenant_id: self.tenant_id.clone (),
+            ..Envelope::new (message_type, config, &self.correlation_id)
+        }
+    }
+
+    /// headers for producing, including the trace context of the current span
+    pub fn to_headers (&self) -> OwnedHeaders {
+        let mut headers = OwnedHeaders::new ();
+        headers = add (headers, MESSAGE_TYPE_HEADER, &self.message_type);
+        headers = add (headers, SCHEMA_VERSION_HEADER, &self.schema_version.to_string ());
+        headers = add (headers, CONTENT_TYPE_HEADER, &self.content_type);
+        headers = add (headers, PRODUCER_HEADER, &self.producer);
+        headers = add (headers, CORRELATION_ID_HEADER, &self.correlation_id);
+        headers = add (headers, CREATED_AT_HEADER, &self.created_at.to_rfc3339 ());
+
+        if let Some (causation_id) = &self.causation_id {
+            headers = add (headers, CAUSATION_ID_HEADER, &causation_id.to_string ());
+        }
+        if let Some (user_id) = &self.user_id {
+            headers = add (headers, USER_ID_HEADER, user_id);
+        }
+        if let Some (tenant_id) = &self.tenant_id {
+            headers = add (headers, TENANT_ID_HEADER, tenant_id);
+        }
+        if let Some (expires_at) = &self.expires_at {
+            headers = add (headers, EXPIRES_AT_HEADER, &expires_at.to_rfc3339 ());
+        }
+        if let Some (deadline_ms) = &self.deadline_ms {
+            headers = add (headers, DEADLINE_MS_HEADER, &deadline_ms.to_string ());
+        }
+
+        telemetry::inject (headers)
+    }
+
+    /// parses the headers of a consumed message, fails if a required header is missing or malformed
+    pub fn from_headers (headers: Option<&BorrowedHeaders>) -> Result<Envelope, String> {
+
+        let required = |name: &str| header (headers, name)
+            .ok_or_else (|| format!("missing header {}", name));
+
+        let schema_version = required (SCHEMA_VERSION_HEADER)?
+            .parse::<u32> ()
+            .map_err (|why| format!("invalid header {}: {}", SCHEMA_VERSION_HEADER, why))?;
+
+        let created_at = DateTime::parse_from_rfc3339 (&required (CREATED_AT_HEADER)?)
+            .map_err (|why| format!("invalid header {}: {}", CREATED_AT_HEADER, why))?
+            .with_timezone (&Utc);
+
+        let causation_id = match header (headers, CAUSATION_ID_HEADER) {
+            None => None,
+            Some (id) => Some (id.parse::<Uuid> ()
+                               .map_err (|why| format!("invalid header {}: {}", CAUSATION_ID_HEADER, why))?)
+        };
+
+        let (expires_at, deadline_ms) = expiry_headers (headers)?;
+
+        Ok (Envelope {
+            message_type: required (MESSAGE_TYPE_HEADER)?,
+            schema_version,
+            content_type: required (CONTENT_TYPE_HEADER)?,
+            producer: required (PRODUCER_HEADER)?,
+            causation_id,
+            correlation_id: required (CORRELATION_ID_HEADER)?,
+            created_at,
+            user_id: header (headers, USER_ID_HEADER),
+            tenant_id: header (headers, TENANT_ID_HEADER),
+            expires_at,
+            deadline_ms,
+        })
+    }
+
+    /// when the message expires, the earliest of `expires_at` and `deadline_ms` after `written_at`, its timestamp in Kafka
+    pub fn expiry (&self, written_at: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let deadline = self.deadline_ms
+            .and_then (|deadline_ms| chrono::Duration::from_std (std::time::Duration::from_millis (deadline_ms)).ok ())
+            .and_then (|deadline| written_at.checked_add_signed (deadline));
+        match (self.expires_at, deadline) {
+            (Some (expires_at), Some (deadline)) => Some (expires_at.min (deadline)),
+            (expires_at, deadline) => expires_at.or (deadline)
+        }
+    }
+}
+
+/// the `expires_at` and `deadline_ms` headers, fails if either is malformed
+pub fn expiry_headers (headers: Option<&BorrowedHeaders>) -> Result<(Option<DateTime<Utc>>, Option<u64>), String> {
+    let expires_at = match header (headers, EXPIRES_AT_HEADER) {
+        None => None,
+        Some (expires_at) => Some (DateTime::parse_from_rfc3339 (&expires_at)
+                                   .map_err (|why| format!("invalid header {}: {}", EXPIRES_AT_HEADER, why))?
+                                   .with_timezone (&Utc))
+    };
+
+    let deadline_ms = match header (headers, DEADLINE_MS_HEADER) {
+        None => None,
+        Some (deadline_ms) => Some (deadline_ms.parse::<u64> ()
+                                    .map_err (|why| format!("invalid header {}: {}", DEADLINE_MS_HEADER, why))?)
+    };
+
+    Ok ((expires_at, deadline_ms))
+}
+
+/// the correlation id given by a client, or a new one when it is missing, longer than `MAX_CORRELATION_ID_LENGTH`
+/// or not only letters, digits, `-`, `_`, `.` and `:`
+pub fn correlation_id (requested: Option<&str>) -> String {
+    let valid = |id: &&str| !id.is_empty ()
+        && id.len () <= MAX_CORRELATION_ID_LENGTH
+        && id.chars ().all (|c| c.is_ascii_alphanumeric () || c == '-' || c == '_' || c == '.' || c == ':');
+    match requested.filter (valid) {
+        Some (id) => String::from (id),
+        None => Uuid::new_v4 ().to_string ()
+    }
+}
+
+/// name of this service in the `producer` header and the trace resource
+pub fn service_name (config: &Config) -> String {
+    format!("type-kafka-{}", config.role)
+}
+
+/// value of the first header called `name`, if it is valid UTF-8
+pub fn header (headers: Option<&BorrowedHeaders>, name: &str) -> Option<String> {
+    headers?.iter ()
+        .find (|header| header.key == name)
+        .and_then (|header| header.value)
+        .and_then (|value| std::str::from_utf8 (value).ok ())
+        .map (String::from)
+}
+
+/// appends a UTF-8 header
+pub fn add (headers: OwnedHeaders, key: &str, value: &str) -> OwnedHeaders {
+    headers.insert (Header { key, value: Some (value) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::tests::config;
+    use chrono::Duration;
+
+    fn envelope (expires_at: Option<DateTime<Utc>>, deadline_ms: Option<u64>) -> Envelope {
+        Envelope { expires_at, deadline_ms, ..Envelope::new ("CreateValue", &config (&[]), "correlation") }
+    }
+
+    #[test]
+    fn expires_at_the_earliest_of_the_expiry_and_the_deadline () {
+        let written_at = DateTime::parse_from_rfc3339 ("2026-10-19T12:00:00Z").unwrap ().with_timezone (&Utc);
+        let soon = written_at + Duration::seconds (1);
+        let later = written_at + Duration::seconds (10);
+
+        assert_eq!(envelope (None, None).expiry (written_at), None);
+        assert_eq!(envelope (Some (later), None).expiry (written_at), Some (later));
+        assert_eq!(envelope (None, Some (1000)).expiry (written_at), Some (soon));
+        assert_eq!(envelope (Some (later), Some (1000)).expiry (written_at), Some (soon));
+        assert_eq!(envelope (Some (soon), Some (10_000)).expiry (written_at), Some (soon));
+        // the deadline runs from the message timestamp, not from the creation of the envelope
+        assert_eq!(envelope (None, Some (1000)).expiry (later), Some (later + Duration::seconds (1)));
+        assert_eq!(envelope (Some (soon), Some (u64::MAX)).expiry (written_at), Some (soon));
+    }
+
+    #[test]
+    fn round_trips_the_expiry_through_the_headers () {
+        let expires_at = DateTime::parse_from_rfc3339 ("2026-10-19T12:00:00.250Z").unwrap ().with_timezone (&Utc);
+        let sent = envelope (Some (expires_at), Some (500));
+        let headers = sent.to_headers ();
+        let received = Envelope::from_headers (Some (headers.as_borrowed ())).unwrap ();
+        assert_eq!(received.expires_at, Some (expires_at));
+        assert_eq!(received.deadline_ms, Some (500));
+    }
+
+    #[test]
+    fn replaces_an_invalid_correlation_id () {
+        assert_eq!(correlation_id (Some ("checkout-42:step.1_a")), "checkout-42:step.1_a");
+        let long = "a".repeat (MAX_CORRELATION_ID_LENGTH);
+        assert_eq!(correlation_id (Some (&long)), long);
+
+        for invalid in [None, Some (""), Some ("a b"), Some ("a\r\nb"), Some ("é"), Some (&*format!("{}a", long))] {
+            let generated = correlation_id (invalid);
+            assert!(generated.parse::<Uuid> ().is_ok (), "{:?} gave {}", invalid, generated);
+        }
+    }
+
+    #[test]
+    fn rejects_a_malformed_expiry () {
+        let headers = add (OwnedHeaders::new (), EXPIRES_AT_HEADER, "tomorrow");
+        assert!(expiry_headers (Some (headers.as_borrowed ())).is_err ());
+        let headers = add (OwnedHeaders::new (), DEADLINE_MS_HEADER, "-1");
+        assert!(expiry_headers (Some (headers.as_borrowed ())).is_err ());
+        assert_eq!(expiry_headers (Some (OwnedHeaders::new ().as_borrowed ())), Ok ((None, None)));
+        assert_eq!(expiry_headers (None), Ok ((None, None)));
+    }
+}
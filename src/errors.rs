@@ -0,0 +1,248 @@
+use log::{debug, warn};
+use rdkafka::error::KafkaError;
+use serde::Serialize;
+use std::convert::Infallible;
+use std::time::Duration;
+use warp::http::StatusCode;
+use warp::reply::Response;
+use utoipa::openapi::{ContentBuilder, Ref, RefOr, ResponseBuilder};
+use utoipa::openapi;
+use utoipa::{ToResponse, ToSchema};
+use warp::Reply;
+
+/// errors of the HTTP API, rendered as `application/problem+json` (RFC 7807)
+#[derive(Clone, Debug)]
+pub enum ApiError {
+    /// body or parameters that cannot be parsed
+    InvalidBody (String),
+    Unauthorized (String),
+    Forbidden (String),
+    NotFound (String),
+    MethodNotAllowed,
+    Conflict (String),
+    PayloadTooLarge,
+    UnsupportedMediaType,
+    /// well formed input that breaks a rule, e.g. a non-finite value
+    Unprocessable (String),
+    TooManyRequests { retry_after: Duration },
+    /// the view service could not be reached
+    BadGateway (String),
+    /// Kafka could not be reached or did not acknowledge in time
+    Unavailable (String),
+    Internal (String),
+}
+
+impl warp::reject::Reject for ApiError {}
+
+impl From<KafkaError> for ApiError {
+    fn from (why: KafkaError) -> ApiError {
+        ApiError::Unavailable (why.to_string ())
+    }
+}
+
+/// problem details, see RFC 7807
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Problem {
+    #[serde(rename = "type")]
+    #[schema(example = "/problems/not-found")]
+    pub problem_type: String,
+    pub title: String,
+    pub status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+/// the response of every error status in the OpenAPI document
+impl<'r> ToResponse<'r> for Problem {
+    fn response () -> (&'r str, RefOr<openapi::Response>) {
+        let content = ContentBuilder::new ().schema (Some (Ref::from_schema_name ("Problem"))).build ();
+        let response = ResponseBuilder::new ()
+            .description ("problem details, `type` tells the error, `detail` its cause")
+            .content ("application/problem+json", content)
+            .build ();
+        ("Problem", RefOr::T (response))
+    }
+}
+
+impl ApiError {
+    pub fn status (&self) -> StatusCode {
+        match self {
+            ApiError::InvalidBody (_) => StatusCode::BAD_REQUEST,
+            ApiError::Unauthorized (_) => StatusCode::UNAUTHORIZED,
+            ApiError::Forbidden (_) => StatusCode::FORBIDDEN,
+            ApiError::NotFound (_) => StatusCode::NOT_FOUND,
+            ApiError::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+            ApiError::Conflict (_) => StatusCode::CONFLICT,
+            ApiError::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            ApiError::UnsupportedMediaType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ApiError::Unprocessable (_) => StatusCode::UNPROCESSABLE_ENTITY,
+            ApiError::TooManyRequests { .. } => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::BadGateway (_) => StatusCode::BAD_GATEWAY,
+            ApiError::Unavailable (_) => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::Internal (_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// short name of the error, the last segment of the problem type
+    pub fn kind (&self) -> &'static str {
+        match self {
+            ApiError::InvalidBody (_) => "invalid-body",
+            ApiError::Unauthorized (_) => "unauthorized",
+            ApiError::Forbidden (_) => "forbidden",
+            ApiError::NotFound (_) => "not-found",
+            ApiError::MethodNotAllowed => "method-not-allowed",
+            ApiError::Conflict (_) => "conflict",
+            ApiError::PayloadTooLarge => "payload-too-large",
+            ApiError::UnsupportedMediaType => "unsupported-media-type",
+            ApiError::Unprocessable (_) => "invalid-value",
+            ApiError::TooManyRequests { .. } => "too-many-requests",
+            ApiError::BadGateway (_) => "view-unavailable",
+            ApiError::Unavailable (_) => "kafka-unavailable",
+            ApiError::Internal (_) => "internal",
+        }
+    }
+
+    pub fn detail (&self) -> Option<String> {
+        match self {
+            ApiError::InvalidBody (detail) |
+            ApiError::Unauthorized (detail) |
+            ApiError::Forbidden (detail) |
+            ApiError::NotFound (detail) |
+            ApiError::Conflict (detail) |
+            ApiError::Unprocessable (detail) |
+            ApiError::BadGateway (detail) |
+            ApiError::Unavailable (detail) |
+            ApiError::Internal (detail) => Some (detail.clone ()),
+            ApiError::TooManyRequests { retry_after } => Some (format!("retry in {:?}", retry_after)),
+            ApiError::MethodNotAllowed | ApiError::PayloadTooLarge | ApiError::UnsupportedMediaType => None,
+        }
+    }
+
+    pub fn problem (&self) -> Problem {
+        let status = self.status ();
+        Problem {
+            problem_type: format!("/problems/{}", self.kind ()),
+            title: String::from (status.canonical_reason ().unwrap_or ("Error")),
+            status: status.as_u16 (),
+            detail: self.detail (),
+        }
+    }
+}
+
+impl Reply for ApiError {
+    fn into_response (self) -> Response {
+        let problem = self.problem ();
+        let mut response = warp::reply::with_status (warp::reply::json (&problem), self.status ()).into_response ();
+        let headers = response.headers_mut ();
+        headers.insert ("content-type", "application/problem+json".parse ().expect ("valid header"));
+
+        match self {
+            ApiError::Unauthorized (_) => {
+                headers.insert ("www-authenticate", "Bearer".parse ().expect ("valid header"));
+            },
+            ApiError::TooManyRequests { retry_after } => {
+                let seconds = retry_after.as_secs_f64 ().ceil ().max (1.0) as u64;
+                headers.insert ("retry-after", seconds.into ());
+            },
+            _ => ()
+        };
+        response
+    }
+}
+
+/// turns every rejection into a problem response, the API's own errors and warp's
+pub async fn recover (rejection: warp::Rejection) -> Result<Response, Infallible> {
+
+    let error = if let Some (error) = rejection.find::<ApiError> () {
+        error.clone ()
+    } else if rejection.is_not_found () {
+        ApiError::NotFound (String::from ("no such resource"))
+    } else if let Some (why) = rejection.find::<warp::filters::body::BodyDeserializeError> () {
+        ApiError::InvalidBody (why.to_string ())
+    } else if let Some (why) = rejection.find::<async_graphql_warp::GraphQLBadRequest> () {
+        ApiError::InvalidBody (why.0.to_string ())
+    } else if let Some (why) = rejection.find::<warp::reject::InvalidQuery> () {
+        ApiError::InvalidBody (why.to_string ())
+    } else if let Some (why) = rejection.find::<warp::reject::InvalidHeader> () {
+        ApiError::InvalidBody (why.to_string ())
+    } else if let Some (why) = rejection.find::<warp::reject::MissingHeader> () {
+        ApiError::InvalidBody (why.to_string ())
+    } else if rejection.find::<warp::reject::LengthRequired> ().is_some () {
+        ApiError::InvalidBody (String::from ("the request needs a content-length"))
+    } else if rejection.find::<warp::reject::PayloadTooLarge> ().is_some () {
+        ApiError::PayloadTooLarge
+    } else if rejection.find::<warp::reject::UnsupportedMediaType> ().is_some () {
+        ApiError::UnsupportedMediaType
+    } else if rejection.find::<warp::reject::MethodNotAllowed> ().is_some () {
+        ApiError::MethodNotAllowed
+    } else {
+        ApiError::Internal (format!("unhandled rejection {:?}", rejection))
+    };
+
+    match &error {
+        ApiError::Unavailable (why) | ApiError::BadGateway (why) | ApiError::Internal (why) => warn!("Request failed: {}", why),
+        other => debug!("Request refused: {:?}", other)
+    };
+    Ok (error.into_response ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use warp::Filter;
+
+    /// a JSON route of at most 16 bytes, recovered by `recover`
+    fn route () -> impl Filter<Extract = (Response,), Error = Infallible> + Clone {
+        warp::path!("values")
+            .and (warp::post ())
+            .and (warp::body::content_length_limit (16))
+            .and (warp::body::json ())
+            .map (|_: serde_json::Value| warp::reply ().into_response ())
+            .or (warp::path!("failing").and_then (|| async { Err::<Response, _> (warp::reject::custom (ApiError::Conflict (String::from ("taken")))) }))
+            .unify ()
+            .recover (recover)
+            .unify ()
+    }
+
+    /// the status, problem type and content type of the response
+    async fn problem (request: warp::test::RequestBuilder) -> (u16, String, String) {
+        let response = request.reply (&route ()).await;
+        let body : serde_json::Value = serde_json::from_slice (response.body ()).unwrap_or_default ();
+        (response.status ().as_u16 (),
+         body["type"].as_str ().unwrap_or_default ().to_string (),
+         response.headers ().get ("content-type").map (|value| value.to_str ().unwrap ().to_string ()).unwrap_or_default ())
+    }
+
+    fn expected (status: u16, kind: &str) -> (u16, String, String) {
+        (status, format!("/problems/{}", kind), String::from ("application/problem+json"))
+    }
+
+    #[tokio::test]
+    async fn turns_the_rejections_into_problems () {
+        let post = || warp::test::request ().method ("POST").path ("/values");
+        assert_eq!(problem (post ().json (&1)).await.0, 200);
+        assert_eq!(problem (warp::test::request ().path ("/failing")).await, expected (409, "conflict"));
+        assert_eq!(problem (warp::test::request ().path ("/nothing")).await, expected (404, "not-found"));
+        assert_eq!(problem (post ().header ("content-type", "application/json").body ("{")).await, expected (400, "invalid-body"));
+        assert_eq!(problem (post ().json (&"more than sixteen bytes")).await, expected (413, "payload-too-large"));
+        assert_eq!(problem (post ().header ("content-type", "text/plain").body ("1")).await, expected (415, "unsupported-media-type"));
+        assert_eq!(problem (warp::test::request ().method ("GET").path ("/values")).await, expected (405, "method-not-allowed"));
+    }
+
+    #[test]
+    fn renders_the_headers_of_the_problem () {
+        let response = ApiError::Unauthorized (String::from ("no credentials")).into_response ();
+        assert_eq!(response.status (), StatusCode::UNAUTHORIZED);
+        assert_eq!(response.headers ()["www-authenticate"], "Bearer");
+
+        let response = ApiError::TooManyRequests { retry_after: Duration::from_millis (1500) }.into_response ();
+        assert_eq!(response.headers ()["retry-after"], "2");
+        let response = ApiError::TooManyRequests { retry_after: Duration::from_millis (10) }.into_response ();
+        assert_eq!(response.headers ()["retry-after"], "1");
+
+        let problem = ApiError::Unprocessable (String::from ("value is not finite")).problem ();
+        assert_eq!(serde_json::to_value (problem).unwrap (),
+                   serde_json::json!({"type": "/problems/invalid-value", "title": "Unprocessable Entity", "status": 422, "detail": "value is not finite"}));
+        assert!(serde_json::to_value (ApiError::PayloadTooLarge.problem ()).unwrap ().get ("detail").is_none ());
+    }
+}
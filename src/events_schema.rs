@@ -1,10 +1,70 @@
-use crate::commands_schema::{Value, UpdateOperation};
+use crate::aggregate::AggregateEvent;
+use crate::commands_schema::{default_tenant, Relabel, Value, UpdateOperation};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+/// why a command of a value was rejected by the command processor
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Rejection {
+    pub value_id: Uuid,
+    /// type of the rejected command
+    pub command: String,
+    pub reason: String,
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(tag = "action")]
 pub enum Event {
-    ValueCreated {id: Uuid, parent: Uuid, data: Value},
-    ValueUpdated {id: Uuid, parent: Uuid, data: UpdateOperation}
+    ValueCreated {id: Uuid, parent: Uuid, #[serde(default = "default_tenant")] tenant: String, data: Value},
+    ValueUpdated {id: Uuid, parent: Uuid, #[serde(default = "default_tenant")] tenant: String, data: UpdateOperation},
+    ValueRelabeled {id: Uuid, parent: Uuid, #[serde(default = "default_tenant")] tenant: String, data: Relabel},
+    /// the command `parent` changed nothing, it is recorded for the process managers and audits
+    CommandRejected {id: Uuid, parent: Uuid, #[serde(default = "default_tenant")] tenant: String, data: Rejection}
+}
+
+impl AggregateEvent for Event {
+    fn name (&self) -> &'static str {
+        match self {
+            Event::ValueCreated { .. } => "ValueCreated",
+            Event::ValueUpdated { .. } => "ValueUpdated",
+            Event::ValueRelabeled { .. } => "ValueRelabeled",
+            Event::CommandRejected { .. } => "CommandRejected"
+        }
+    }
+
+    fn id (&self) -> Uuid {
+        match self {
+            Event::ValueCreated { id, .. } => *id,
+            Event::ValueUpdated { id, .. } => *id,
+            Event::ValueRelabeled { id, .. } => *id,
+            Event::CommandRejected { id, .. } => *id
+        }
+    }
+
+    fn parent (&self) -> Uuid {
+        match self {
+            Event::ValueCreated { parent, .. } => *parent,
+            Event::ValueUpdated { parent, .. } => *parent,
+            Event::ValueRelabeled { parent, .. } => *parent,
+            Event::CommandRejected { parent, .. } => *parent
+        }
+    }
+
+    fn tenant (&self) -> &str {
+        match self {
+            Event::ValueCreated { tenant, .. } => tenant,
+            Event::ValueUpdated { tenant, .. } => tenant,
+            Event::ValueRelabeled { tenant, .. } => tenant,
+            Event::CommandRejected { tenant, .. } => tenant
+        }
+    }
+
+    fn aggregate_id (&self) -> Uuid {
+        match self {
+            Event::ValueCreated { data, .. } => data.value_id,
+            Event::ValueUpdated { data, .. } => data.value_id,
+            Event::ValueRelabeled { data, .. } => data.value_id,
+            Event::CommandRejected { data, .. } => data.value_id
+        }
+    }
 }
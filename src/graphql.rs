@@ -0,0 +1,276 @@
+use crate::auth::{Auth, Credentials, Principal};
+use crate::auth;
+use crate::commands;
+use crate::config::Config;
+use crate::db::Db;
+use crate::db;
+use crate::errors::ApiError;
+use crate::inputs_schema::{Constraints, Expiry, Labels, OperationType, ValueInput, ValueOperationInput};
+use crate::materialized_view::Changes;
+use crate::materialized_view;
+use crate::number::{Number, ValueType};
+use crate::producer::Producer;
+use crate::rate_limit::RateLimiter;
+use crate::rate_limit;
+use async_graphql::{Context, Data, ErrorExtensions, Object, Schema, SimpleObject, Subscription, ID};
+use async_graphql_warp::{GraphQLResponse, GraphQLWebSocket};
+use async_graphql::http::WebSocketProtocols;
+use chrono::{DateTime, Utc};
+use futures::{Stream, StreamExt};
+use log::{debug, info};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use uuid::Uuid;
+use warp::http::HeaderMap;
+use warp::ws::Ws;
+
+/// the schema served at /graphql, see `schema`
+pub type ApiSchema = Schema<Query, Mutation, Subscription>;
+
+/// page size of `values` when `first` is not given
+const DEFAULT_PAGE_SIZE: usize = 100;
+const MAX_PAGE_SIZE: usize = 1000;
+
+/// the parts of the process the resolvers use, `None` for the ones the role does not run:
+/// the queries and subscriptions need the local view, the mutations the producer and rate limiter
+pub fn schema (config: Config, db: Option<Db>, changes: Changes, commands: Option<(Producer, RateLimiter)>) -> ApiSchema {
+    let (producer, limiter) = match commands {
+        Some ((producer, limiter)) => (Some (producer), Some (limiter)),
+        None => (None, None)
+    };
+    Schema::build (Query, Mutation, Subscription)
+        .data (config)
+        .data (db)
+        .data (changes)
+        .data (producer)
+        .data (limiter)
+        .finish ()
+}
+
+/// the HTTP request a query or mutation comes from, missing for the operations of the websocket
+struct Caller {
+    correlation_id: String,
+    addr: Option<SocketAddr>,
+}
+
+/// a value of the materialized view
+#[derive(SimpleObject)]
+#[graphql(name = "Value")]
+struct ValueObject {
+    id: ID,
+    value: Number,
+}
+
+/// a value as of an event applied to the view
+#[derive(SimpleObject)]
+struct ValueChange {
+    id: ID,
+    value: Number,
+    /// id of the event that changed the value
+    event_id: ID,
+}
+
+pub struct Query;
+
+#[Object]
+impl Query {
+    /// the value, null when it does not exist, or not yet
+    async fn value (&self, ctx: &Context<'_>, id: ID) -> async_graphql::Result<Option<ValueObject>> {
+        let principal = principal (ctx, auth::VALUES_READ)?;
+        let db = view (ctx)?;
+        let value_id = parse_id (&id)?;
+
+        info!("Querying value id {} for {} of tenant {}", value_id, principal.subject, principal.tenant ());
+
+        Ok (db::get (db, principal.tenant (), &value_id).await
+            .map (|record| ValueObject { id, value: record.value }))
+    }
+
+    /// the values ordered by id, `ids` only returns those, `after` pages from the id of the last value of the previous page
+    async fn values (
+        &self,
+        ctx: &Context<'_>,
+        ids: Option<Vec<ID>>,
+        first: Option<i32>,
+        after: Option<ID>
+    ) -> async_graphql::Result<Vec<ValueObject>> {
+        let principal = principal (ctx, auth::VALUES_READ)?;
+        let db = view (ctx)?;
+
+        let ids = match ids {
+            Some (ids) => Some (ids.iter ().map (parse_id).collect::<async_graphql::Result<Vec<Uuid>>> ()?),
+            None => None
+        };
+        let after = after.as_ref ().map (parse_id).transpose ()?;
+        let first = match first {
+            None => DEFAULT_PAGE_SIZE,
+            Some (first) if first >= 0 && first as usize <= MAX_PAGE_SIZE => first as usize,
+            Some (first) => return Err (graphql_error (ApiError::Unprocessable (format!("first must be between 0 and {}, got {}", MAX_PAGE_SIZE, first))))
+        };
+
+        info!("Querying values for {} of tenant {}", principal.subject, principal.tenant ());
+
+        Ok (db::list (db, principal.tenant ()).await.into_iter ()
+            .filter (|(value_id, _)| ids.as_ref ().is_none_or (|ids| ids.contains (value_id)))
+            .filter (|(value_id, _)| after.is_none_or (|after| *value_id > after))
+            .take (first)
+            .map (|(value_id, record)| ValueObject { id: ID::from (value_id.to_string ()), value: record.value })
+            .collect ())
+    }
+}
+
+pub struct Mutation;
+
+#[Object]
+impl Mutation {
+    /// produces a CreateValue command like POST /values, returns the id of the value to create,
+    /// the value is a float unless `value_type` says otherwise and is kept within its `constraints`,
+    /// the command is rejected when processed after `expires_at` or more than `deadline_ms` after it was written
+    async fn create_value (&self, ctx: &Context<'_>, value: Number, value_type: Option<ValueType>, constraints: Option<Constraints>, expires_at: Option<DateTime<Utc>>, deadline_ms: Option<u64>) -> async_graphql::Result<ID> {
+        let (principal, caller, producer) = command_context (ctx)?;
+        let config = ctx.data_unchecked::<Config> ();
+
+        let value_id = commands::create (principal, ValueInput { value, value_type: value_type.unwrap_or_default (), constraints, labels: Labels::default (), expiry: Expiry { expires_at, deadline_ms } }, &caller.correlation_id, producer, config).await
+            .map_err (graphql_error)?;
+        Ok (ID::from (value_id.to_string ()))
+    }
+
+    /// produces an UpdateValue command like PUT /values/:id, returns the id of the value,
+    /// the command is rejected later when the value does not exist, or when it expired like the one of `createValue`
+    async fn update_value (&self, ctx: &Context<'_>, id: ID, operation: OperationType, value: Number, expires_at: Option<DateTime<Utc>>, deadline_ms: Option<u64>) -> async_graphql::Result<ID> {
+        let (principal, caller, producer) = command_context (ctx)?;
+        let config = ctx.data_unchecked::<Config> ();
+        let value_id = parse_id (&id)?;
+
+        commands::update (principal, value_id, ValueOperationInput { operation, value, expiry: Expiry { expires_at, deadline_ms } }, &caller.correlation_id, producer, config).await
+            .map_err (graphql_error)?;
+        Ok (id)
+    }
+}
+
+pub struct Subscription;
+
+#[Subscription]
+impl Subscription {
+    /// the value each time an event changes it, from the events applied to the view of this process
+    async fn value_changed (&self, ctx: &Context<'_>, id: ID) -> async_graphql::Result<impl Stream<Item = ValueChange>> {
+        let principal = principal (ctx, auth::VALUES_READ)?;
+        view (ctx)?;
+        let value_id = parse_id (&id)?;
+        let tenant = String::from (principal.tenant ());
+
+        debug!("{} of tenant {} subscribed to value {}", principal.subject, tenant, value_id);
+
+        Ok (materialized_view::watch (ctx.data_unchecked::<Changes> (), &tenant, value_id)
+            .map (|change| ValueChange {
+                id: ID::from (change.value_id.to_string ()),
+                value: change.value,
+                event_id: ID::from (change.event_id.to_string ()),
+            }))
+    }
+}
+
+/// POST or GET /graphql
+pub async fn execute (
+    principal: Principal,
+    correlation_id: String,
+    addr: Option<SocketAddr>,
+    (schema, request): (ApiSchema, async_graphql::Request)
+) -> Result<GraphQLResponse, Infallible> {
+    let request = request
+        .data (principal)
+        .data (Caller { correlation_id, addr });
+    Ok (GraphQLResponse::from (schema.execute (request).await))
+}
+
+/// GET /graphql upgraded to a websocket, the subscriptions authenticate with the `authorization`,
+/// `x-api-key` and `x-tenant-id` of the connection_init payload, or else of the upgrade request's headers
+pub fn subscribe (ws: Ws, protocol: WebSocketProtocols, headers: HeaderMap, auth: Auth, schema: ApiSchema) -> impl warp::Reply {
+    let reply = ws.on_upgrade (move |socket| {
+        GraphQLWebSocket::new (socket, schema, protocol)
+            .on_connection_init (move |payload| async move {
+                let field = |name: &str| payload.get (name).and_then (|value| value.as_str ()).map (String::from)
+                    .or_else (|| headers.get (name).and_then (|value| value.to_str ().ok ()).map (String::from));
+                let credentials = Credentials::from_headers (field ("authorization").as_deref (), field ("x-api-key").as_deref ());
+                let principal = auth.resolve (credentials, field ("x-tenant-id"))
+                    .inspect_err (|why| info!("Subscription denied: {:?}", why))
+                    .map_err (graphql_error)?;
+
+                let mut data = Data::default ();
+                data.insert (principal);
+                Ok (data)
+            })
+            .serve ()
+    });
+    warp::reply::with_header (reply, "sec-websocket-protocol", protocol.sec_websocket_protocol ())
+}
+
+/// the caller, when it has the scope
+fn principal<'a> (ctx: &Context<'a>, scope: &str) -> async_graphql::Result<&'a Principal> {
+    let principal = ctx.data::<Principal> ()
+        .map_err (|_| graphql_error (ApiError::Unauthorized (String::from ("missing credentials"))))?;
+    principal.require (scope).map_err (graphql_error)?;
+    Ok (principal)
+}
+
+fn view<'a> (ctx: &Context<'a>) -> async_graphql::Result<&'a Db> {
+    ctx.data_unchecked::<Option<Db>> ().as_ref ()
+        .ok_or_else (|| graphql_error (ApiError::Unavailable (String::from ("no materialized view in this process, query the view service"))))
+}
+
+/// the caller of a mutation, after the scope and the rate limit checks, and the producer,
+/// the mutations are refused on the websocket, which is not rate limited
+fn command_context<'a> (ctx: &Context<'a>) -> async_graphql::Result<(&'a Principal, &'a Caller, Producer)> {
+    let principal = principal (ctx, auth::COMMANDS_WRITE)?;
+    let caller = ctx.data::<Caller> ()
+        .map_err (|_| graphql_error (ApiError::InvalidBody (String::from ("mutations are not served on the websocket, POST them to /graphql"))))?;
+    let (producer, limiter) = match (ctx.data_unchecked::<Option<Producer>> (), ctx.data_unchecked::<Option<RateLimiter>> ()) {
+        (Some (producer), Some (limiter)) => (producer.clone (), limiter),
+        _ => return Err (graphql_error (ApiError::Unavailable (String::from ("this process does not serve commands"))))
+    };
+    rate_limit::check (limiter, principal, caller.addr, "/graphql")
+        .map_err (graphql_error)?;
+    Ok ((principal, caller, producer))
+}
+
+fn parse_id (id: &ID) -> async_graphql::Result<Uuid> {
+    Uuid::parse_str (id).map_err (|_| graphql_error (ApiError::InvalidBody (format!("invalid id {}", id.as_str ()))))
+}
+
+/// the error with the problem type and status of the REST API in its extensions
+fn graphql_error (error: ApiError) -> async_graphql::Error {
+    let message = error.detail ().unwrap_or_else (|| String::from (error.kind ()));
+    async_graphql::Error::new (message).extend_with (|_, extensions| {
+        extensions.set ("code", error.kind ());
+        extensions.set ("status", error.status ().as_u16 ());
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::tests::config;
+
+    /// the schema of the `all` role, as served to a websocket: a principal but no caller
+    fn websocket_request (query: &str) -> (ApiSchema, async_graphql::Request) {
+        let config = config (&[]);
+        let commands = Some ((crate::producer::init (&config), crate::rate_limit::init (&config)));
+        let schema = schema (config, Some (db::init ()), materialized_view::changes (), commands);
+        (schema, async_graphql::Request::new (query).data (Principal::anonymous ()))
+    }
+
+    #[tokio::test]
+    async fn mutations_are_refused_on_the_websocket () {
+        let (schema, request) = websocket_request ("mutation { createValue(value: 2) }");
+        let response = schema.execute (request).await;
+        assert_eq!(response.errors.len (), 1);
+        assert!(response.errors[0].message.contains ("websocket"), "{:?}", response.errors);
+    }
+
+    #[tokio::test]
+    async fn queries_are_served_on_the_websocket () {
+        let (schema, request) = websocket_request ("{ values { id } }");
+        let response = schema.execute (request).await;
+        assert!(response.errors.is_empty (), "{:?}", response.errors);
+    }
+}
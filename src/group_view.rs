@@ -0,0 +1,340 @@
+use crate::aggregate::Projection;
+use crate::commands_schema::{Relabel, Value, UpdateOperation};
+use crate::config::Config;
+use crate::events_schema::Event;
+use crate::health::Health;
+use crate::number::{Number, ValueType};
+use crate::projection;
+use crate::value;
+use bigdecimal::{BigDecimal, RoundingMode};
+use log::error;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+/// digits of the mean after the decimal point
+const MEAN_SCALE: i64 = 18;
+
+/// the totals of a group as of the last event applied to the view, exact decimals
+/// written as strings whatever the kind of the values
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub struct Group {
+    pub group_id: String,
+    /// number of values in the group
+    pub count: u64,
+    #[schema(value_type = String, example = "100.00")]
+    pub sum: Number,
+    #[schema(value_type = String, example = "100.00")]
+    pub min: Number,
+    #[schema(value_type = String, example = "100.00")]
+    pub max: Number,
+    /// rounded half to even to 18 digits after the decimal point
+    #[schema(value_type = String, example = "100")]
+    pub mean: Number,
+}
+
+/// a value as the group view knows it, the type is needed to apply its updates
+#[derive(Debug)]
+struct Member {
+    value: BigDecimal,
+    number: Number,
+    value_type: ValueType,
+    groups: BTreeSet<String>,
+}
+
+/// the running totals of a group, the values are counted by value so that min and max
+/// follow the removals without going through the members
+#[derive(Debug, Default)]
+struct Totals {
+    count: u64,
+    sum: BigDecimal,
+    values: BTreeMap<BigDecimal, u64>,
+}
+
+impl Totals {
+    fn add (&mut self, value: &BigDecimal) {
+        self.count += 1;
+        self.sum += value;
+        *self.values.entry (value.clone ()).or_default () += 1;
+    }
+
+    fn remove (&mut self, value: &BigDecimal) {
+        self.count -= 1;
+        self.sum -= value;
+        if let Some (count) = self.values.get_mut (value) {
+            *count -= 1;
+            if *count == 0 {
+                self.values.remove (value);
+            }
+        }
+    }
+}
+
+/// the values and the totals of their groups, keyed by tenant
+#[derive(Debug, Default)]
+pub struct GroupState {
+    members: HashMap<(String, Uuid), Member>,
+    totals: HashMap<(String, String), Totals>,
+}
+
+pub type Groups = Arc<Mutex<GroupState>>;
+
+pub fn init () -> Groups {
+    Arc::new (Mutex::new (GroupState::default ()))
+}
+
+/// the totals of the tenant's group, `None` when no value is in the group
+pub async fn get (groups: &Groups, tenant: &str, group_id: &str) -> Option<Group> {
+    let state = groups.lock ().await;
+    let totals = state.totals.get (&(String::from (tenant), String::from (group_id)))?;
+    let min = totals.values.keys ().next ()?;
+    let max = totals.values.keys ().next_back ()?;
+    let mean = (&totals.sum / BigDecimal::from (totals.count))
+        .with_scale_round (MEAN_SCALE, RoundingMode::HalfEven)
+        .normalized ();
+    Some (Group {group_id: String::from (group_id),
+                 count: totals.count,
+                 sum: Number::Decimal (totals.sum.clone ()),
+                 min: Number::Decimal (min.clone ()),
+                 max: Number::Decimal (max.clone ()),
+                 mean: Number::Decimal (mean)})
+}
+
+pub async fn run (config : Arc<Config>, groups: Groups, health: Health) {
+    let group_id = config.groups_group_id.clone ();
+    projection::run (config, &group_id, GroupView { groups }, health).await;
+}
+
+/// the totals of the groups, updated with each value created, updated or regrouped
+struct GroupView {
+    groups: Groups,
+}
+
+impl Projection for GroupView {
+    type Event = Event;
+
+    const NAME: &'static str = "group-view";
+
+    async fn apply (&self, event: Event) {
+        let mut state = self.groups.lock ().await;
+        match event {
+            Event::ValueCreated { tenant, data, .. } => handle_value_created (&mut state, tenant, data),
+            Event::ValueUpdated { tenant, data, .. } => handle_value_updated (&mut state, tenant, data),
+            Event::ValueRelabeled { tenant, data, .. } => handle_value_relabeled (&mut state, tenant, data),
+            Event::CommandRejected { .. } => ()
+        };
+    }
+}
+
+/// a value created again, when the command processor replays its commands, replaces the one known
+fn handle_value_created (state: &mut GroupState, tenant: String, data: Value) {
+    if let Some (known) = state.members.remove (&(tenant.clone (), data.value_id)) {
+        for group in &known.groups {
+            leave (&mut state.totals, (tenant.clone (), group.clone ()), &known.value);
+        }
+    }
+    let member = Member {value: decimal (&data.value),
+                         number: data.value,
+                         value_type: data.value_type,
+                         groups: data.labels.groups};
+    for group in &member.groups {
+        state.totals.entry ((tenant.clone (), group.clone ())).or_default ().add (&member.value);
+    }
+    state.members.insert ((tenant, data.value_id), member);
+}
+
+/// moves the value in the totals of its groups, an update that cannot be applied is prevented by the command processor
+fn handle_value_updated (state: &mut GroupState, tenant: String, data: UpdateOperation) {
+    let GroupState { members, totals } = state;
+    let member = match members.get_mut (&(tenant.clone (), data.value_id)) {
+        Some (member) => member,
+        None => {
+            error!("Update of unknown value {}", data.value_id);
+            return
+        }
+    };
+    let number = match value::update (&member.value_type, &member.number, &data) {
+        Ok (number) => number,
+        Err (why) => {
+            error!("Could not update value {}: {}", data.value_id, why);
+            return
+        }
+    };
+    let value = decimal (&number);
+    for group in &member.groups {
+        let group = totals.entry ((tenant.clone (), group.clone ())).or_default ();
+        group.remove (&member.value);
+        group.add (&value);
+    }
+    member.value = value;
+    member.number = number;
+}
+
+/// takes the value out of the groups it left and adds it to the ones it joined
+fn handle_value_relabeled (state: &mut GroupState, tenant: String, data: Relabel) {
+    let GroupState { members, totals } = state;
+    let member = match members.get_mut (&(tenant.clone (), data.value_id)) {
+        Some (member) => member,
+        None => {
+            error!("Relabel of unknown value {}", data.value_id);
+            return
+        }
+    };
+    for group in member.groups.difference (&data.labels.groups) {
+        leave (totals, (tenant.clone (), group.clone ()), &member.value);
+    }
+    for group in data.labels.groups.difference (&member.groups) {
+        totals.entry ((tenant.clone (), group.clone ())).or_default ().add (&member.value);
+    }
+    member.groups = data.labels.groups;
+}
+
+/// takes the value out of the group's totals, dropping the group once it is empty
+fn leave (totals: &mut HashMap<(String, String), Totals>, key: (String, String), value: &BigDecimal) {
+    if let Some (group) = totals.get_mut (&key) {
+        group.remove (value);
+        if group.count == 0 {
+            totals.remove (&key);
+        }
+    }
+}
+
+/// the exact value, the command processor only accepts finite values
+fn decimal (number: &Number) -> BigDecimal {
+    number.to_decimal ().unwrap_or_default ()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inputs_schema::{Labels, OperationType};
+    use crate::number::NumberKind;
+    use crate::number::tests::{decimal, integer};
+
+    const TENANT: &str = "default";
+
+    fn labels (groups: &[&str]) -> Labels {
+        Labels { groups: groups.iter ().map (|group| String::from (*group)).collect (), ..Labels::default () }
+    }
+
+    async fn create (groups: &Groups, value: Number, value_type: ValueType, in_groups: &[&str]) -> Uuid {
+        let value_id = Uuid::new_v4 ();
+        let data = Value { value_id, value, value_type, constraints: None, labels: labels (in_groups) };
+        handle_value_created (&mut *groups.lock ().await, String::from (TENANT), data);
+        value_id
+    }
+
+    async fn update (groups: &Groups, value_id: Uuid, operation: OperationType, value: Number) {
+        handle_value_updated (&mut *groups.lock ().await, String::from (TENANT), UpdateOperation { value_id, operation, value });
+    }
+
+    async fn relabel (groups: &Groups, value_id: Uuid, in_groups: &[&str]) {
+        handle_value_relabeled (&mut *groups.lock ().await, String::from (TENANT), Relabel { value_id, labels: labels (in_groups) });
+    }
+
+    /// count, sum, min, max and mean of the group
+    async fn totals (groups: &Groups, group_id: &str) -> Option<(u64, String, String, String, String)> {
+        get (groups, TENANT, group_id).await
+            .map (|group| (group.count, group.sum.to_string (), group.min.to_string (), group.max.to_string (), group.mean.to_string ()))
+    }
+
+    fn totals_of (count: u64, sum: &str, min: &str, max: &str, mean: &str) -> Option<(u64, String, String, String, String)> {
+        Some ((count, String::from (sum), String::from (min), String::from (max), String::from (mean)))
+    }
+
+    #[tokio::test]
+    async fn counts_the_created_values_in_their_groups () {
+        let groups = init ();
+        create (&groups, Number::Integer (1), integer (), &["a"]).await;
+        create (&groups, Number::Integer (4), integer (), &["a", "b"]).await;
+        create (&groups, Number::Integer (9), integer (), &[]).await;
+
+        assert_eq!(totals (&groups, "a").await, totals_of (2, "5", "1", "4", "2.5"));
+        assert_eq!(totals (&groups, "b").await, totals_of (1, "4", "4", "4", "4"));
+        assert!(totals (&groups, "c").await.is_none ());
+        assert!(get (&groups, "other", "a").await.is_none ());
+    }
+
+    #[tokio::test]
+    async fn moves_the_totals_with_the_updates () {
+        let groups = init ();
+        let low = create (&groups, Number::Integer (1), integer (), &["a"]).await;
+        create (&groups, Number::Integer (4), integer (), &["a"]).await;
+
+        update (&groups, low, OperationType::ADD, Number::Integer (9)).await;
+        assert_eq!(totals (&groups, "a").await, totals_of (2, "14", "4", "10", "7"));
+
+        update (&groups, low, OperationType::MULTIPLY, Number::Integer (0)).await;
+        assert_eq!(totals (&groups, "a").await, totals_of (2, "4", "0", "4", "2"));
+
+        update (&groups, low, OperationType::ADD, Number::Integer (i64::MAX)).await;
+        update (&groups, low, OperationType::ADD, Number::Integer (1)).await;
+        update (&groups, Uuid::new_v4 (), OperationType::ADD, Number::Integer (1)).await;
+        assert_eq!(totals (&groups, "a").await.map (|totals| totals.3), Some (i64::MAX.to_string ()));
+    }
+
+    #[tokio::test]
+    async fn moves_a_relabeled_value_between_groups () {
+        let groups = init ();
+        let first = create (&groups, Number::Integer (1), integer (), &["a"]).await;
+        let second = create (&groups, Number::Integer (3), integer (), &["a", "b"]).await;
+
+        relabel (&groups, first, &["b", "c"]).await;
+        assert_eq!(totals (&groups, "a").await, totals_of (1, "3", "3", "3", "3"));
+        assert_eq!(totals (&groups, "b").await, totals_of (2, "4", "1", "3", "2"));
+        assert_eq!(totals (&groups, "c").await, totals_of (1, "1", "1", "1", "1"));
+
+        relabel (&groups, second, &["b"]).await;
+        assert!(totals (&groups, "a").await.is_none ());
+        assert!(!groups.lock ().await.totals.contains_key (&(String::from (TENANT), String::from ("a"))));
+
+        relabel (&groups, first, &[]).await;
+        relabel (&groups, second, &[]).await;
+        assert!(groups.lock ().await.totals.is_empty ());
+
+        relabel (&groups, second, &["a"]).await;
+        update (&groups, second, OperationType::ADD, Number::Integer (2)).await;
+        assert_eq!(totals (&groups, "a").await, totals_of (1, "5", "5", "5", "5"));
+        relabel (&groups, Uuid::new_v4 (), &["a"]).await;
+        assert_eq!(totals (&groups, "a").await.map (|totals| totals.0), Some (1));
+    }
+
+    #[tokio::test]
+    async fn counts_a_replayed_creation_once () {
+        let groups = init ();
+        let value_id = create (&groups, Number::Integer (1), integer (), &["a", "b"]).await;
+        create (&groups, Number::Integer (2), integer (), &["a"]).await;
+
+        let data = Value { value_id, value: Number::Integer (1), value_type: integer (), constraints: None, labels: labels (&["a", "b"]) };
+        handle_value_created (&mut *groups.lock ().await, String::from (TENANT), data);
+        assert_eq!(totals (&groups, "a").await, totals_of (2, "3", "1", "2", "1.5"));
+        assert_eq!(totals (&groups, "b").await, totals_of (1, "1", "1", "1", "1"));
+
+        let data = Value { value_id, value: Number::Integer (5), value_type: integer (), constraints: None, labels: labels (&["a"]) };
+        handle_value_created (&mut *groups.lock ().await, String::from (TENANT), data);
+        assert_eq!(totals (&groups, "a").await, totals_of (2, "7", "2", "5", "3.5"));
+        assert!(totals (&groups, "b").await.is_none ());
+    }
+
+    #[tokio::test]
+    async fn sums_values_of_mixed_kinds_exactly () {
+        let groups = init ();
+        create (&groups, Number::Integer (2), integer (), &["a"]).await;
+        let float = create (&groups, Number::Float (0.5), ValueType::default (), &["a"]).await;
+        let money = ValueType { kind: NumberKind::Decimal, scale: Some (2), ..ValueType::default () };
+        create (&groups, decimal ("1.25"), money, &["a"]).await;
+        assert_eq!(totals (&groups, "a").await, totals_of (3, "3.75", "0.5", "2", "1.25"));
+
+        update (&groups, float, OperationType::ADD, Number::Float (0.1)).await;
+        assert_eq!(totals (&groups, "a").await, totals_of (3, "3.85", "0.6", "2", "1.283333333333333333"));
+
+        let group = get (&groups, TENANT, "a").await.unwrap ();
+        let json = serde_json::to_value (&group).unwrap ();
+        assert_eq!(json["sum"], "3.85");
+        assert_eq!(json["min"], "0.6");
+        assert_eq!(json["max"], "2");
+    }
+}
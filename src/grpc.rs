@@ -0,0 +1,326 @@
+use crate::auth::{Auth, Credentials, Principal};
+use crate::auth;
+use crate::commands;
+use crate::config::Config;
+use crate::db::Db;
+use crate::envelope;
+use crate::errors::ApiError;
+use crate::inputs_schema::{Constraints, Expiry, Labels, OperationType, ValueInput, ValueOperationInput};
+use crate::materialized_view::Changes;
+use crate::materialized_view;
+use crate::number::{Number, NumberKind, Rounding, ValueType};
+use crate::producer::Producer;
+use crate::queries;
+use crate::rate_limit::RateLimiter;
+use crate::rate_limit;
+use chrono::{DateTime, Utc};
+use bigdecimal::BigDecimal;
+use futures::{Stream, StreamExt};
+use log::{debug, info};
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::str::FromStr;
+use tonic::metadata::MetadataMap;
+use tonic::{Code, Request, Response, Status};
+use uuid::Uuid;
+
+/// the messages and service generated from proto/values.proto
+pub mod proto {
+    tonic::include_proto!("typekafka.values.v1");
+}
+
+use proto::values_server::{Values, ValuesServer};
+
+/// rate limit route of the gRPC commands
+const ROUTE: &str = "grpc";
+
+/// the parts of the process the calls use, `None` for the ones the role does not run:
+/// GetValue and WatchValue need the local view, CreateValue and UpdateValue the producer and rate limiter
+pub struct ValuesService {
+    config: Config,
+    auth: Auth,
+    db: Option<Db>,
+    changes: Changes,
+    commands: Option<(Producer, RateLimiter)>,
+}
+
+/// serves the Values service on GRPC_PORT until the process stops
+pub async fn serve (config: Config, auth: Auth, db: Option<Db>, changes: Changes, commands: Option<(Producer, RateLimiter)>) {
+
+    let port = match config.grpc_port {
+        Some (port) => port,
+        None => return
+    };
+    let host : IpAddr = config.http_host.parse ().expect ("HTTP_HOST is not an IP address");
+    // the requests are bounded like the HTTP bodies
+    let max_message_bytes = config.http_max_body_bytes as usize;
+    let service = ValuesService { config, auth, db, changes, commands };
+
+    info!("Serving gRPC on {}:{}", host, port);
+
+    tonic::transport::Server::builder ()
+        .add_service (ValuesServer::new (service).max_decoding_message_size (max_message_bytes))
+        .serve ((host, port).into ())
+        .await
+        .expect ("Could not serve gRPC");
+}
+
+#[tonic::async_trait]
+impl Values for ValuesService {
+    async fn create_value (&self, request: Request<proto::CreateValueRequest>) -> Result<Response<proto::CreateValueResponse>, Status> {
+        let (principal, correlation_id, producer) = self.command_context (&request)?;
+        let message = request.get_ref ();
+        let input = ValueInput { value: number (message.value, &message.exact_value)?,
+                                 value_type: message.value_type.as_ref ().map (value_type).unwrap_or_default (),
+                                 constraints: message.constraints.as_ref ().map (constraints),
+                                 labels: message.labels.as_ref ().map (labels).unwrap_or_default (),
+                                 expiry: expiry (&message.expiry)? };
+
+        let value_id = commands::create (&principal, input, &correlation_id, producer, &self.config).await?;
+        Ok (Response::new (proto::CreateValueResponse { value_id: value_id.to_string (), correlation_id }))
+    }
+
+    async fn update_value (&self, request: Request<proto::UpdateValueRequest>) -> Result<Response<proto::UpdateValueResponse>, Status> {
+        let (principal, correlation_id, producer) = self.command_context (&request)?;
+        let message = request.get_ref ();
+        let value_id = parse_id (&message.value_id)?;
+        let operation = match message.operation () {
+            proto::Operation::Add => OperationType::ADD,
+            proto::Operation::Multiply => OperationType::MULTIPLY,
+            proto::Operation::Unspecified => return Err (ApiError::InvalidBody (String::from ("operation is required")).into ())
+        };
+        let input = ValueOperationInput { operation, value: number (message.value, &message.exact_value)?, expiry: expiry (&message.expiry)? };
+
+        commands::update (&principal, value_id, input, &correlation_id, producer, &self.config).await?;
+        Ok (Response::new (proto::UpdateValueResponse { correlation_id }))
+    }
+
+    async fn relabel_value (&self, request: Request<proto::RelabelValueRequest>) -> Result<Response<proto::RelabelValueResponse>, Status> {
+        let (principal, correlation_id, producer) = self.command_context (&request)?;
+        let message = request.get_ref ();
+        let value_id = parse_id (&message.value_id)?;
+
+        commands::relabel (&principal, value_id, message.labels.as_ref ().map (labels).unwrap_or_default (), &correlation_id, producer, &self.config).await?;
+        Ok (Response::new (proto::RelabelValueResponse { correlation_id }))
+    }
+
+    async fn get_value (&self, request: Request<proto::GetValueRequest>) -> Result<Response<proto::Value>, Status> {
+        let principal = self.principal (request.metadata (), auth::VALUES_READ)?;
+        let db = self.view ()?;
+        let value_id = parse_id (&request.get_ref ().value_id)?;
+
+        let value = queries::lookup (&principal, db, value_id).await?;
+        Ok (Response::new (proto::Value { value_id: value.value_id.to_string (),
+                                          value: value.value.to_f64 (),
+                                          exact_value: value.value.to_string (),
+                                          labels: Some (proto_labels (value.labels)) }))
+    }
+
+    type WatchValueStream = Pin<Box<dyn Stream<Item = Result<proto::ValueChange, Status>> + Send>>;
+
+    /// the value each time an event changes it, from the events applied to the view of this process
+    async fn watch_value (&self, request: Request<proto::WatchValueRequest>) -> Result<Response<Self::WatchValueStream>, Status> {
+        let principal = self.principal (request.metadata (), auth::VALUES_READ)?;
+        self.view ()?;
+        let value_id = parse_id (&request.get_ref ().value_id)?;
+
+        debug!("{} of tenant {} watches value {}", principal.subject, principal.tenant (), value_id);
+
+        let changes = materialized_view::watch (&self.changes, principal.tenant (), value_id)
+            .map (|change| proto::ValueChange {
+                value_id: change.value_id.to_string (),
+                value: change.value.to_f64 (),
+                event_id: change.event_id.to_string (),
+                exact_value: change.value.to_string (),
+            })
+            .map (Ok);
+        Ok (Response::new (Box::pin (changes)))
+    }
+}
+
+impl ValuesService {
+    /// the caller, from the `authorization`, `x-api-key` and `x-tenant-id` metadata, when it has the scope
+    fn principal (&self, metadata: &MetadataMap, scope: &str) -> Result<Principal, ApiError> {
+        let field = |name: &str| metadata.get (name).and_then (|value| value.to_str ().ok ());
+        let credentials = Credentials::from_headers (field ("authorization"), field ("x-api-key"));
+        let principal = self.auth.resolve (credentials, field ("x-tenant-id").map (String::from))
+            .inspect_err (|why| info!("gRPC call denied: {:?}", why))?;
+        principal.require (scope)?;
+        Ok (principal)
+    }
+
+    fn view (&self) -> Result<&Db, ApiError> {
+        self.db.as_ref ()
+            .ok_or_else (|| ApiError::Unavailable (String::from ("no materialized view in this process, query the view service")))
+    }
+
+    /// the caller of a command, after the scope and the rate limit checks, its correlation id,
+    /// given in the `x-correlation-id` metadata or a new one when it is missing or invalid, and the producer
+    fn command_context<T> (&self, request: &Request<T>) -> Result<(Principal, String, Producer), ApiError> {
+        let principal = self.principal (request.metadata (), auth::COMMANDS_WRITE)?;
+        let (producer, limiter) = match &self.commands {
+            Some ((producer, limiter)) => (producer.clone (), limiter),
+            None => return Err (ApiError::Unavailable (String::from ("this process does not serve commands")))
+        };
+        let addr : Option<SocketAddr> = request.remote_addr ();
+        rate_limit::check (limiter, &principal, addr, ROUTE)?;
+
+        let correlation_id = envelope::correlation_id (request.metadata ().get ("x-correlation-id")
+                                                       .and_then (|value| value.to_str ().ok ()));
+        Ok ((principal, correlation_id, producer))
+    }
+}
+
+fn parse_id (id: &str) -> Result<Uuid, ApiError> {
+    Uuid::parse_str (id).map_err (|_| ApiError::InvalidBody (format!("invalid id {}", id)))
+}
+
+/// the exact value when it is set, the double otherwise
+fn number (value: f64, exact_value: &Option<String>) -> Result<Number, ApiError> {
+    match exact_value {
+        None => Ok (Number::Float (value)),
+        Some (exact_value) => BigDecimal::from_str (exact_value.trim ())
+            .map (Number::Decimal)
+            .map_err (|why| ApiError::InvalidBody (format!("invalid exact_value {}: {}", exact_value, why)))
+    }
+}
+
+fn value_type (value_type: &proto::ValueType) -> ValueType {
+    ValueType {
+        kind: match value_type.kind () {
+            proto::NumberKind::Float => NumberKind::Float,
+            proto::NumberKind::Integer => NumberKind::Integer,
+            proto::NumberKind::Decimal => NumberKind::Decimal
+        },
+        scale: value_type.scale,
+        rounding: match value_type.rounding () {
+            proto::Rounding::HalfEven => Rounding::HalfEven,
+            proto::Rounding::HalfUp => Rounding::HalfUp,
+            proto::Rounding::HalfDown => Rounding::HalfDown,
+            proto::Rounding::Up => Rounding::Up,
+            proto::Rounding::Down => Rounding::Down,
+            proto::Rounding::Ceiling => Rounding::Ceiling,
+            proto::Rounding::Floor => Rounding::Floor
+        },
+    }
+}
+
+fn constraints (constraints: &proto::Constraints) -> Constraints {
+    Constraints {
+        min: constraints.min,
+        max: constraints.max,
+        non_negative: constraints.non_negative,
+        integer: constraints.integer,
+        max_step: constraints.max_step,
+    }
+}
+
+fn labels (labels: &proto::Labels) -> Labels {
+    Labels {
+        name: labels.name.clone (),
+        description: labels.description.clone (),
+        tags: labels.tags.clone ().into_iter ().collect (),
+        groups: labels.groups.iter ().cloned ().collect (),
+    }
+}
+
+fn proto_labels (labels: Labels) -> proto::Labels {
+    proto::Labels {
+        name: labels.name,
+        description: labels.description,
+        tags: labels.tags.into_iter ().collect (),
+        groups: labels.groups.into_iter ().collect (),
+    }
+}
+
+fn expiry (expiry: &Option<proto::Expiry>) -> Result<Expiry, ApiError> {
+    let expiry = match expiry {
+        Some (expiry) => expiry,
+        None => return Ok (Expiry::default ())
+    };
+    let expires_at = match &expiry.expires_at {
+        None => None,
+        Some (expires_at) => Some (DateTime::parse_from_rfc3339 (expires_at)
+                                   .map_err (|why| ApiError::InvalidBody (format!("invalid expires_at {}: {}", expires_at, why)))?
+                                   .with_timezone (&Utc))
+    };
+    Ok (Expiry { expires_at, deadline_ms: expiry.deadline_ms })
+}
+
+/// the status closest to the HTTP status of the error
+impl From<ApiError> for Status {
+    fn from (error: ApiError) -> Status {
+        let code = match error {
+            ApiError::InvalidBody (_) | ApiError::PayloadTooLarge | ApiError::UnsupportedMediaType => Code::InvalidArgument,
+            ApiError::Unprocessable (_) => Code::InvalidArgument,
+            ApiError::Unauthorized (_) => Code::Unauthenticated,
+            ApiError::Forbidden (_) => Code::PermissionDenied,
+            ApiError::NotFound (_) => Code::NotFound,
+            ApiError::MethodNotAllowed => Code::Unimplemented,
+            ApiError::Conflict (_) => Code::FailedPrecondition,
+            ApiError::TooManyRequests { .. } => Code::ResourceExhausted,
+            ApiError::BadGateway (_) | ApiError::Unavailable (_) => Code::Unavailable,
+            ApiError::Internal (_) => Code::Internal
+        };
+        Status::new (code, error.detail ().unwrap_or_else (|| String::from (error.kind ())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::number::tests::decimal;
+    use std::time::Duration;
+
+    #[test]
+    fn reads_the_exact_value_before_the_double () {
+        assert!(matches!(number (2.5, &None), Ok (Number::Float (value)) if value == 2.5));
+        assert!(matches!(number (2.5, &Some (String::from (" 12.30 "))), Ok (value) if value == decimal ("12.30")));
+        assert!(matches!(number (2.5, &Some (String::from ("twelve"))), Err (ApiError::InvalidBody (_))));
+    }
+
+    #[test]
+    fn reads_the_expiry () {
+        let read = |given| expiry (&given).map (|expiry| (expiry.expires_at, expiry.deadline_ms));
+        assert_eq!(read (None).unwrap (), (None, None));
+        let given = proto::Expiry { expires_at: Some (String::from ("2021-01-01T01:00:00+01:00")), deadline_ms: Some (500) };
+        assert_eq!(read (Some (given)).unwrap (), (Some ("2021-01-01T00:00:00Z".parse ().unwrap ()), Some (500)));
+        let invalid = proto::Expiry { expires_at: Some (String::from ("tomorrow")), deadline_ms: None };
+        assert!(matches!(expiry (&Some (invalid)), Err (ApiError::InvalidBody (_))));
+    }
+
+    #[test]
+    fn reads_the_value_type () {
+        assert_eq!(value_type (&proto::ValueType::default ()), ValueType::default ());
+        let given = proto::ValueType { kind: proto::NumberKind::Decimal as i32, scale: Some (2), rounding: proto::Rounding::Floor as i32 };
+        assert_eq!(value_type (&given), ValueType { kind: NumberKind::Decimal, scale: Some (2), rounding: Rounding::Floor });
+    }
+
+    #[test]
+    fn converts_the_labels_both_ways () {
+        let given = proto::Labels { name: Some (String::from ("savings")),
+                                    description: None,
+                                    tags: vec![(String::from ("team"), String::from ("core"))].into_iter ().collect (),
+                                    groups: vec![String::from ("b"), String::from ("a"), String::from ("a")] };
+        let read = labels (&given);
+        assert_eq!(read.name.as_deref (), Some ("savings"));
+        assert_eq!(read.tags.get ("team").map (String::as_str), Some ("core"));
+        assert_eq!(read.groups.iter ().map (String::as_str).collect::<Vec<&str>> (), vec!["a", "b"]);
+        assert_eq!(proto_labels (read), proto::Labels { groups: vec![String::from ("a"), String::from ("b")], ..given });
+    }
+
+    #[test]
+    fn maps_the_errors_to_the_closest_status () {
+        let status = |error: ApiError| { let status = Status::from (error); (status.code (), String::from (status.message ())) };
+        assert_eq!(status (ApiError::InvalidBody (String::from ("invalid id x"))), (Code::InvalidArgument, String::from ("invalid id x")));
+        assert_eq!(status (ApiError::Unprocessable (String::from ("value is not finite"))).0, Code::InvalidArgument);
+        assert_eq!(status (ApiError::PayloadTooLarge).0, Code::InvalidArgument);
+        assert_eq!(status (ApiError::Unauthorized (String::from ("no credentials"))).0, Code::Unauthenticated);
+        assert_eq!(status (ApiError::Forbidden (String::from ("missing scope"))).0, Code::PermissionDenied);
+        assert_eq!(status (ApiError::NotFound (String::from ("no value"))).0, Code::NotFound);
+        assert_eq!(status (ApiError::Conflict (String::from ("taken"))).0, Code::FailedPrecondition);
+        assert_eq!(status (ApiError::TooManyRequests { retry_after: Duration::from_secs (1) }).0, Code::ResourceExhausted);
+        assert_eq!(status (ApiError::Unavailable (String::from ("no view"))).0, Code::Unavailable);
+        assert_eq!(status (ApiError::Internal (String::from ("failed"))).0, Code::Internal);
+    }
+}
@@ -0,0 +1,148 @@
+use crate::admin::KafkaAdmin;
+use log::warn;
+use maplit::hashmap;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use warp::http::StatusCode;
+
+/// status of one consumer running in this process
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ConsumerStatus {
+    pub assigned: bool,
+    pub stopped: bool,
+    /// total lag over the assigned partitions, `None` until known
+    pub lag: Option<i64>,
+    /// lag above which the consumer is not ready, `None` disables the check
+    pub max_lag: Option<i64>,
+}
+
+impl ConsumerStatus {
+    pub fn is_ready (&self) -> bool {
+        let caught_up = match (self.max_lag, self.lag) {
+            (None, _) => true,
+            (Some (_), None) => false,
+            (Some (max_lag), Some (lag)) => lag <= max_lag
+        };
+        self.assigned && !self.stopped && caught_up
+    }
+}
+
+/// shared health state, updated by the consumers and read by the health routes
+pub type Health = Arc<Mutex<HashMap<String, ConsumerStatus>>>;
+
+pub fn init () -> Health {
+    Arc::new(Mutex::new(HashMap::new()))
+}
+
+/// marks the consumer as stopped when dropped, including when its task panics
+pub struct Registration {
+    health: Health,
+    name: String,
+}
+
+impl Drop for Registration {
+    fn drop (&mut self) {
+        update (&self.health, &self.name, |status| status.stopped = true);
+    }
+}
+
+pub fn register (health: &Health, name: &str, max_lag: Option<i64>) -> Registration {
+    let status = ConsumerStatus { max_lag, ..ConsumerStatus::default () };
+    health.lock ().expect ("Health lock poisoned").insert (String::from (name), status);
+    Registration { health: health.clone (), name: String::from (name) }
+}
+
+pub fn update<F> (health: &Health, name: &str, f: F) where F: FnOnce(&mut ConsumerStatus) {
+    if let Some (status) = health.lock ().expect ("Health lock poisoned").get_mut (name) {
+        f (status)
+    }
+}
+
+pub fn consumers (health: &Health) -> HashMap<String, ConsumerStatus> {
+    health.lock ().expect ("Health lock poisoned").clone ()
+}
+
+/// live as long as none of the consumers in this process has stopped
+pub fn is_live (health: &Health) -> bool {
+    consumers (health).values ().all (|status| !status.stopped)
+}
+
+/// fetches cluster metadata, fails if no broker answers within the timeout
+pub async fn check_broker (admin: KafkaAdmin, timeout: Duration) -> Result<usize, String> {
+    let result = tokio::task::spawn_blocking (move || {
+        admin.inner ().fetch_metadata (None, timeout)
+            .map (|metadata| metadata.brokers ().len ())
+    }).await;
+
+    match result {
+        Ok (Ok (0)) => Err (String::from ("no brokers available")),
+        Ok (Ok (brokers)) => Ok (brokers),
+        Ok (Err (why)) => {
+            warn!("Broker metadata request failed: {}", why);
+            Err (format!("{}", why))
+        },
+        Err (why) => Err (format!("{}", why))
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Readiness {
+    ready: bool,
+    broker: Result<usize, String>,
+    consumers: HashMap<String, ConsumerStatus>,
+}
+
+/// GET /health/live
+pub async fn live (health: Health) -> Result<impl warp::Reply, Infallible> {
+    let (status, code) = match is_live (&health) {
+        true => ("live", StatusCode::OK),
+        false => ("stopped", StatusCode::SERVICE_UNAVAILABLE)
+    };
+    Ok (warp::reply::with_status (warp::reply::json (&hashmap!{"status" => status}), code))
+}
+
+/// GET /health/ready
+pub async fn ready (health: Health, admin: KafkaAdmin, timeout: Duration) -> Result<impl warp::Reply, Infallible> {
+    let broker = check_broker (admin, timeout).await;
+    let consumers = consumers (&health);
+    let ready = broker.is_ok () && consumers.values ().all (|status| status.is_ready ());
+
+    let code = match ready {
+        true => StatusCode::OK,
+        false => StatusCode::SERVICE_UNAVAILABLE
+    };
+    Ok (warp::reply::with_status (warp::reply::json (&Readiness { ready, broker, consumers }), code))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn is_ready_once_assigned_and_caught_up () {
+        let status = |assigned, stopped, lag, max_lag| ConsumerStatus { assigned, stopped, lag, max_lag }.is_ready ();
+        assert!(!status (false, false, None, None));
+        assert!(status (true, false, None, None));
+        assert!(!status (true, true, None, None));
+        assert!(!status (true, false, None, Some (10)));
+        assert!(status (true, false, Some (10), Some (10)));
+        assert!(!status (true, false, Some (11), Some (10)));
+        assert!(status (true, false, Some (1000), None));
+    }
+
+    #[test]
+    fn is_not_live_once_a_consumer_stopped () {
+        let health = init ();
+        let registration = register (&health, "projection", Some (10));
+        update (&health, "projection", |status| status.assigned = true);
+        assert!(is_live (&health));
+        assert_eq!(consumers (&health)["projection"].max_lag, Some (10));
+
+        drop (registration);
+        assert!(!is_live (&health));
+        assert!(!consumers (&health)["projection"].is_ready ());
+    }
+}
@@ -1,22 +1,257 @@
+use crate::number::{Number, ValueType};
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet};
+use utoipa::ToSchema;
+use uuid::Uuid;
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema)]
 pub struct ValueInput {
-    pub value: f64,
+    pub value: Number,
+    /// a float when missing
+    #[serde(default)]
+    pub value_type: ValueType,
+    /// checked by the command processor for the value and every update, kept for the life of the value
+    pub constraints: Option<Constraints>,
+    #[serde(flatten)]
+    pub labels: Labels,
+    #[serde(flatten)]
+    pub expiry: Expiry,
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
+impl ValueInput {
+    /// checked before the command is produced
+    pub fn validate (&self) -> Result<(), String> {
+        self.value_type.validate ()?;
+        self.labels.validate ()?;
+        let value = self.value_type.round (&self.value)?;
+        if let Some (constraints) = &self.constraints {
+            constraints.validate ()?;
+            constraints.check (None, &value)
+                .map_err (|why| format!("value breaks its constraints: {}", why))?;
+        }
+        self.expiry.validate (Utc::now ())
+    }
+}
+
+/// longest name and tag key
+const MAX_NAME_LENGTH: usize = 64;
+/// longest description and tag value
+const MAX_TEXT_LENGTH: usize = 1024;
+const MAX_TAGS: usize = 32;
+const MAX_GROUPS: usize = 16;
+
+/// how a value is found besides its id, see `GET /values/by-name/:name` and `GET /values?tag=key:value`
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize, ToSchema)]
+pub struct Labels {
+    /// unique among the values of the tenant, letters, digits, `-`, `_` and `.`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// the keys are names, the values any text
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub tags: BTreeMap<String, String>,
+    /// the groups the value is counted in, see `GET /groups/:id`
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub groups: BTreeSet<String>,
+}
+
+impl Labels {
+    /// checked before the command is produced
+    pub fn validate (&self) -> Result<(), String> {
+        if let Some (name) = &self.name {
+            identifier ("name", name)?;
+        }
+        if let Some (description) = &self.description {
+            text ("description", description)?;
+        }
+        if self.tags.len () > MAX_TAGS {
+            return Err (format!("at most {} tags, got {}", MAX_TAGS, self.tags.len ()));
+        }
+        for (key, value) in &self.tags {
+            identifier ("tag key", key)?;
+            text ("tag value", value)?;
+        }
+        if self.groups.len () > MAX_GROUPS {
+            return Err (format!("at most {} groups, got {}", MAX_GROUPS, self.groups.len ()));
+        }
+        for group in &self.groups {
+            identifier ("group", group)?;
+        }
+        Ok (())
+    }
+}
+
+fn identifier (field: &str, identifier: &str) -> Result<(), String> {
+    let valid = !identifier.is_empty ()
+        && identifier.len () <= MAX_NAME_LENGTH
+        && identifier.chars ().all (|c| c.is_ascii_alphanumeric () || c == '-' || c == '_' || c == '.');
+    match valid {
+        true => Ok (()),
+        false => Err (format!("{} must be 1 to {} letters, digits, -, _ or ., got {:?}", field, MAX_NAME_LENGTH, identifier))
+    }
+}
+
+fn text (field: &str, text: &str) -> Result<(), String> {
+    match text.chars ().count () > MAX_TEXT_LENGTH {
+        true => Err (format!("{} must be at most {} characters", field, MAX_TEXT_LENGTH)),
+        false => Ok (())
+    }
+}
+
+/// the invariants of a value, an update breaking one is rejected
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize, ToSchema, async_graphql::InputObject)]
+pub struct Constraints {
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    #[serde(default)]
+    #[graphql(default)]
+    pub non_negative: bool,
+    /// the value must be a whole number
+    #[serde(default)]
+    #[graphql(default)]
+    pub integer: bool,
+    /// largest change of the value by one update, either way
+    pub max_step: Option<f64>,
+}
+
+impl Constraints {
+    /// the constraints themselves are consistent
+    pub fn validate (&self) -> Result<(), String> {
+        for bound in vec![self.min, self.max, self.max_step].into_iter ().flatten () {
+            finite (bound)?;
+        }
+        if let (Some (min), Some (max)) = (self.min, self.max) {
+            if min > max {
+                return Err (format!("min {} must not be greater than max {}", min, max));
+            }
+        }
+        match self.max_step {
+            Some (max_step) if max_step <= 0.0 => Err (format!("max_step must be positive, got {}", max_step)),
+            _ => Ok (())
+        }
+    }
+
+    /// why the value cannot become `next`, from `current` or at its creation, compared exactly
+    pub fn check (&self, current: Option<&Number>, next: &Number) -> Result<(), String> {
+        next.validate ()?;
+        if let Some (min) = self.min.filter (|min| next.compare (*min) == Some (Ordering::Less)) {
+            return Err (format!("{} is below the minimum {}", next, min));
+        }
+        if let Some (max) = self.max.filter (|max| next.compare (*max) == Some (Ordering::Greater)) {
+            return Err (format!("{} is above the maximum {}", next, max));
+        }
+        if self.non_negative && next.is_negative () {
+            return Err (format!("{} is negative", next));
+        }
+        if self.integer && !next.is_integer () {
+            return Err (format!("{} is not an integer", next));
+        }
+        let step = current.and_then (|current| next.distance (current));
+        match (step, self.max_step.and_then (|max_step| Number::Float (max_step).to_decimal ())) {
+            (Some (step), Some (max_step)) if step > max_step =>
+                Err (format!("{} changes by {}, more than the max step {}", next, step.to_plain_string (), max_step.to_plain_string ())),
+            _ => Ok (())
+        }
+    }
+}
+
+/// when the command processor rejects a command it did not process in time, see `command_processor`
+#[derive(Clone, Debug, Default, Deserialize, Serialize, ToSchema)]
+pub struct Expiry {
+    /// the command is rejected when processed after this time
+    pub expires_at: Option<DateTime<Utc>>,
+    /// the command is rejected when processed more than this many milliseconds after it was written to Kafka
+    pub deadline_ms: Option<u64>,
+}
+
+impl Expiry {
+    pub fn validate (&self, now: DateTime<Utc>) -> Result<(), String> {
+        match (self.expires_at, self.deadline_ms) {
+            (Some (expires_at), _) if expires_at <= now => Err (format!("expires_at must be in the future, got {}", expires_at)),
+            (_, Some (0)) => Err (String::from ("deadline_ms must be at least 1")),
+            _ => Ok (())
+        }
+    }
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize, ToSchema, async_graphql::Enum)]
 pub enum OperationType {
+    #[default]
     ADD,
     MULTIPLY,
 }
 
-impl Default for OperationType {
-    fn default() -> Self { OperationType::ADD }
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema)]
+pub struct ValueOperationInput {
+    pub operation: OperationType,
+    /// converted to the kind of the value by the command processor
+    pub value: Number,
+    #[serde(flatten)]
+    pub expiry: Expiry,
+}
+
+impl ValueOperationInput {
+    /// checked before the command is produced
+    pub fn validate (&self) -> Result<(), String> {
+        self.value.validate ()?;
+        self.expiry.validate (Utc::now ())
+    }
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
-pub struct ValueOperationInput {
+/// moves `amount` from the value `from` to the value `to`, see `transfer`
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema)]
+pub struct TransferInput {
+    #[schema(value_type = String, format = Uuid)]
+    pub from: Uuid,
+    #[schema(value_type = String, format = Uuid)]
+    pub to: Uuid,
+    pub amount: Number,
+}
+
+impl TransferInput {
+    /// checked before the transfer is requested
+    pub fn validate (&self) -> Result<(), String> {
+        self.amount.validate ()?;
+        if !self.amount.is_positive () {
+            return Err (format!("amount must be positive, got {}", self.amount));
+        }
+        match self.from == self.to {
+            true => Err (String::from ("from and to must be different values")),
+            false => Ok (())
+        }
+    }
+}
+
+/// an operation to apply later: once `at` a given time, or on every time of a `cron` expression, see `scheduler`
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema)]
+pub struct ScheduleInput {
     pub operation: OperationType,
-    pub value: f64,
+    pub value: Number,
+    pub at: Option<DateTime<Utc>>,
+    /// minute, hour, day of month, month and day of week in UTC, e.g. `0 0 * * *` for every day at midnight,
+    /// `0` or `7` for Sunday as in Unix cron
+    pub cron: Option<String>,
+}
+
+impl ScheduleInput {
+    /// checked before the schedule is requested, the cron expression is parsed by `scheduler::first_run`
+    pub fn validate (&self, now: DateTime<Utc>) -> Result<(), String> {
+        self.value.validate ()?;
+        match (&self.at, &self.cron) {
+            (Some (at), None) if *at <= now => Err (format!("at must be in the future, got {}", at)),
+            (Some (_), None) | (None, Some (_)) => Ok (()),
+            _ => Err (String::from ("exactly one of at and cron must be given"))
+        }
+    }
+}
+
+fn finite (value: f64) -> Result<(), String> {
+    match value.is_finite () {
+        true => Ok (()),
+        false => Err (format!("value must be a finite number, got {}", value))
+    }
 }
@@ -1,61 +1,160 @@
 mod admin;
+mod aggregate;
+mod admin_api;
 mod api;
+mod auth;
 mod command_processor;
 mod commands;
 mod commands_schema;
 mod config;
 mod consumer;
+mod context;
 mod db;
+mod envelope;
+mod errors;
 mod events_schema;
+mod graphql;
+mod group_view;
+mod grpc;
+mod health;
 mod inputs_schema;
 mod materialized_view;
+mod metrics;
+mod openapi;
+mod number;
+mod process_manager;
 mod producer;
+mod projection;
 mod queries;
+mod rate_limit;
+mod scheduler;
+mod state_topic;
+mod telemetry;
+mod transfer;
+mod value;
+mod view_client;
 
 use config::{Config, Load};
 use log::info;
 use std::env;
+use std::process;
 use std::sync::Arc;
 use tokio::runtime::Runtime;
+use transfer::Transfer;
+use value::ValueAggregate;
 
 fn main() {
 
-    let config = Arc::new (Config::load());
+    let config = match Config::load() {
+        Ok (config) => Arc::new (config),
+        Err (why) => {
+            eprintln!("{}", why);
+            process::exit (2);
+        }
+    };
 
     env::set_var("RUST_LOG", &config.log_level);
     env_logger::init();
 
     info!("{:#?}", &config);
+    info!("Running as role: {}", &config.role);
 
-    // TODO : create topics
+    let auth = match auth::init (&config) {
+        Ok (auth) => auth,
+        Err (why) => {
+            eprintln!("{}", why);
+            process::exit (2);
+        }
+    };
 
     // Create the runtime
     let rt = Runtime::new().unwrap ();
     let db = db::init ();
-    let admin = admin::init (&config.broker);
+    let admin = admin::init (&config);
+    let health = health::init ();
+    let changes = materialized_view::changes ();
+    let transfers = process_manager::init::<Transfer> ();
+    let schedules = scheduler::init ();
+    let groups = group_view::init ();
 
     // Spawn the root task
     rt.block_on(async {
 
+        telemetry::init (&config);
+
         let mut tasks = Vec::with_capacity(3);
+        let role = config.role;
+
+        // topics are provisioned in the background so the health routes are served while the brokers are down
+        let config_rc0 = Arc::clone(&config);
+        let admin_rc0 = Arc::clone (&admin);
+        tokio::spawn(async {
+            admin::run (admin_rc0, config_rc0).await;
+        });
 
-        let db_rc1 = Arc::clone (&db);
+        // every role serves the health routes
+        let views = api::Views {
+            db: match role.has_view () {
+                true => Some (Arc::clone (&db)),
+                false => None
+            },
+            changes: changes.clone (),
+            transfers: match role.runs_command_processor () {
+                true => Some (Arc::clone (&transfers)),
+                false => None
+            },
+            schedules: match role.runs_command_processor () {
+                true => Some (Arc::clone (&schedules)),
+                false => None
+            },
+            groups: match role.has_view () {
+                true => Some (Arc::clone (&groups)),
+                false => None
+            },
+        };
         let config_rc1 = Arc::clone(&config);
         let admin_rc1 = Arc::clone (&admin);
+        let health_rc1 = Arc::clone (&health);
         tasks.push (tokio::spawn(async {
-            api::run (config_rc1, db_rc1, admin_rc1).await;
+            api::run (config_rc1, views, admin_rc1, health_rc1, auth).await;
         }));
 
-        let config_rc2 = Arc::clone(&config);
-        tasks.push (tokio::spawn(async {
-            command_processor::run (config_rc2).await;
-        }));
+        if role.runs_command_processor () {
+            let config_rc2 = Arc::clone(&config);
+            let health_rc2 = Arc::clone (&health);
+            tasks.push (tokio::spawn(async {
+                command_processor::run::<ValueAggregate> (config_rc2, health_rc2).await;
+            }));
 
-        let config_rc3 = Arc::clone(&config);
-        let db_rc2 = Arc::clone (&db);
-        tasks.push (tokio::spawn(async {
-            materialized_view::run (config_rc3, db_rc2).await;
-        }));
+            // the sagas send commands and wait for their events next to the command processor
+            let config_rc4 = Arc::clone(&config);
+            let health_rc4 = Arc::clone (&health);
+            tasks.push (tokio::spawn(async {
+                process_manager::run (config_rc4, transfers, health_rc4).await;
+            }));
+
+            let config_rc5 = Arc::clone(&config);
+            let health_rc5 = Arc::clone (&health);
+            tasks.push (tokio::spawn(async {
+                scheduler::run (config_rc5, schedules, health_rc5).await;
+            }));
+        }
+
+        if role.has_view () {
+            let config_rc3 = Arc::clone(&config);
+            let db_rc2 = Arc::clone (&db);
+            let health_rc3 = Arc::clone (&health);
+            tasks.push (tokio::spawn(async {
+                materialized_view::run (config_rc3, db_rc2, changes, health_rc3).await;
+            }));
+
+            // the totals of the groups are projected next to the view, from the same events
+            let config_rc6 = Arc::clone(&config);
+            let health_rc6 = Arc::clone (&health);
+            tasks.push (tokio::spawn(async {
+                group_view::run (config_rc6, groups, health_rc6).await;
+            }));
+        }
 
         for t in tasks {
             t.await.expect ("Ooops!");
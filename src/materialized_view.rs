@@ -1,97 +1,149 @@
-use crate::commands_schema::{Value, UpdateOperation};
+use crate::aggregate::{AggregateEvent, Projection};
+use crate::commands_schema::{Relabel, Value, UpdateOperation};
 use crate::config::Config;
-use crate::consumer;
-use crate::db::Db;
+use crate::db::{Db, Record};
 use crate::db;
 use crate::events_schema::Event;
-use crate::inputs_schema::OperationType ;
-use log::{debug, info, warn, error};
-use maplit::hashmap;
-use rdkafka::consumer::{CommitMode, Consumer};
-use rdkafka::message::Message;
-use rdkafka::topic_partition_list::{TopicPartitionList, Offset};
+use crate::health::Health;
+use crate::number::Number;
+use crate::projection;
+use crate::value;
+use futures::{future, stream, Stream, StreamExt};
+use log::{warn, error};
 use std::sync::Arc;
+use tokio::sync::broadcast;
+use uuid::Uuid;
+
+/// a value as of an event applied to the view
+#[derive(Clone, Debug)]
+pub struct ValueChanged {
+    pub tenant: String,
+    pub value_id: Uuid,
+    pub value: Number,
+    /// id of the applied event
+    pub event_id: Uuid,
+}
 
-pub async fn run (config : Arc<Config>, db: Db) {
-
-    let Config { broker, events_group_id, events_topic, .. } = &*config;
-    let consumer = consumer::init (String::from (broker), String::from (events_group_id));
-
-    consumer.subscribe(&[&events_topic])
-        .expect("Can't subscribe to the specified topic");
-
-    // NOTE : uses last offset stored in kafka
-    let topic_map = hashmap!{
-        (String::from (events_topic), 0) => Offset::Stored
-    };
-
-    let tpl : TopicPartitionList = TopicPartitionList::from_topic_map (&topic_map).unwrap ();
-    consumer.assign (&tpl).expect ("Could not set topic partition list");
-
-    loop {
-
-        match consumer.recv().await {
-            // NOTE: panics if topic does not exist
-            Err(why) => panic!("Failed to read message from {:?} : {}", &tpl, why),
-            Ok(m) => {
-
-                match m.payload_view::<str>() {
-                    None => warn!("Empty payload"),
-                    Some(Ok(payload)) => {
+/// the values changed by the applied events, for the GraphQL subscriptions
+pub type Changes = broadcast::Sender<ValueChanged>;
 
-                        debug!("payload: {}", payload);
+/// changes not yet received by a subscriber when this many more are sent are dropped for it
+const CHANGES_CAPACITY: usize = 1024;
 
-                        // TODO : read avro
-                        match serde_json::from_str::<Event>(payload) {
-                            Ok (event) => {
-                                info!("Received event: {:#?}, partition: {}, offset: {}, timestamp: {:?}", event, m.partition(), m.offset(), m.timestamp());
+pub fn changes () -> Changes {
+    broadcast::channel (CHANGES_CAPACITY).0
+}
 
-                                // run validation and emit events
-                                match event {
-                                    Event::ValueCreated { data, .. } => handle_value_created (db.clone (), data).await,
-                                    Event::ValueUpdated { data, .. } => handle_value_updated (db.clone (), data).await
-                                };
+/// the changes of a value of the tenant sent from now on, shared by the GraphQL and gRPC subscriptions,
+/// a subscriber too slow to keep up skips the changes it missed
+pub fn watch (changes: &Changes, tenant: &str, value_id: Uuid) -> impl Stream<Item = ValueChanged> {
+    let tenant = String::from (tenant);
+    stream::unfold (changes.subscribe (), |mut receiver| async move {
+        loop {
+            match receiver.recv ().await {
+                Ok (change) => return Some ((change, receiver)),
+                Err (broadcast::error::RecvError::Lagged (skipped)) => warn!("Subscriber lagging, skipped {} changes", skipped),
+                Err (broadcast::error::RecvError::Closed) => return None
+            }
+        }
+    })
+    .filter (move |change| future::ready (change.tenant == tenant && change.value_id == value_id))
+}
 
-                            },
-                            Err (why) => error!("Could not deserialize : {:?}", why)
-                        };
+pub async fn run (config : Arc<Config>, db: Db, changes: Changes, health: Health) {
+    let group_id = config.events_group_id.clone ();
+    projection::run (config, &group_id, ValueView { db, changes }, health).await;
+}
 
-                    },
-                    Some(Err(e)) => error!("Error while deserializing payload: {:?}", e)
-                };
+/// the values by tenant and id, the changes are sent to the subscribers
+struct ValueView {
+    db: Db,
+    changes: Changes,
+}
 
-                match consumer.commit_message(&m, CommitMode::Async) {
-                    Err(why) => error!("Failed to commit message offset: {}", why),
-                    Ok (_) => debug!("Commited message offset: {}", m.offset ())
+impl Projection for ValueView {
+    type Event = Event;
+
+    const NAME: &'static str = "materialized-view";
+
+    async fn apply (&self, event: Event) {
+        let event_id = event.id ();
+        let (tenant, value_id, value) = match event {
+            Event::ValueCreated { tenant, data, .. } => {
+                let value_id = data.value_id;
+                let value = handle_value_created (&self.db, &tenant, data).await;
+                (tenant, value_id, value)
+            },
+            Event::ValueUpdated { tenant, data, .. } => {
+                let value_id = data.value_id;
+                let value = match handle_value_updated (&self.db, &tenant, data).await {
+                    Some (value) => value,
+                    None => return
                 };
-
-            }
+                (tenant, value_id, value)
+            },
+            // the value did not change
+            Event::ValueRelabeled { tenant, data, .. } => return handle_value_relabeled (&self.db, &tenant, data).await,
+            Event::CommandRejected { .. } => return
         };
 
+        // fails only when nobody subscribed
+        let _ = self.changes.send (ValueChanged { tenant, value_id, value, event_id });
     }
-
 }
 
-async fn handle_value_created (db: Db, data : Value) {
-    let Value { value_id, value } = data;
-    db::insert (&db, value_id, value).await;
+/// returns the new value
+async fn handle_value_created (db: &Db, tenant: &str, data : Value) -> Number {
+    let Value { value_id, value, value_type, labels, .. } = data;
+    db::insert (db, tenant, value_id, Record { value: value.clone (), value_type, labels }).await;
+    value
 }
 
-async fn handle_value_updated (db: Db, data : UpdateOperation) {
-
-    let UpdateOperation { value_id, operation, value } = data;
-
-    match operation {
-        OperationType::ADD => {
-            let current_value = db::get (&db, &value_id).await.unwrap ();
-            let new_value = current_value + value;
-            db::insert (&db, value_id, new_value).await;
-        },
-        OperationType::MULTIPLY => {
-            let current_value = db::get (&db, &value_id).await.unwrap ();
-            let new_value = current_value * value;
-            db::insert (&db, value_id, new_value).await;
+/// returns the new value, `None` when the value is unknown or the update cannot be applied, which the command processor prevents
+async fn handle_value_updated (db: &Db, tenant: &str, data : UpdateOperation) -> Option<Number> {
+    let record = match db::get (db, tenant, &data.value_id).await {
+        Some (record) => record,
+        None => {
+            error!("Update of unknown value {}", data.value_id);
+            return None
+        }
+    };
+    match value::update (&record.value_type, &record.value, &data) {
+        Ok (new_value) => {
+            db::insert (db, tenant, data.value_id, Record { value: new_value.clone (), ..record }).await;
+            Some (new_value)
         },
+        Err (why) => {
+            error!("Could not update value {}: {}", data.value_id, why);
+            None
+        }
     }
+}
+
+/// replaces the labels of the value
+async fn handle_value_relabeled (db: &Db, tenant: &str, data : Relabel) {
+    let record = match db::get (db, tenant, &data.value_id).await {
+        Some (record) => record,
+        None => {
+            error!("Relabel of unknown value {}", data.value_id);
+            return
+        }
+    };
+    db::insert (db, tenant, data.value_id, Record { labels: data.labels, ..record }).await;
+}
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inputs_schema::{Labels, OperationType};
+
+    #[tokio::test]
+    async fn skips_the_events_of_unknown_values () {
+        let db = db::init ();
+        let value_id = Uuid::new_v4 ();
+        let update = UpdateOperation { value_id, operation: OperationType::ADD, value: Number::Integer (1) };
+        assert!(handle_value_updated (&db, "default", update).await.is_none ());
+        handle_value_relabeled (&db, "default", Relabel { value_id, labels: Labels::default () }).await;
+        assert!(db::get (&db, "default", &value_id).await.is_none ());
+    }
 }
@@ -0,0 +1,172 @@
+use lazy_static::lazy_static;
+use prometheus::{Encoder, HistogramVec, IntCounterVec, IntGaugeVec, TextEncoder};
+use prometheus::{register_histogram_vec, register_int_counter_vec, register_int_gauge_vec};
+use rdkafka::statistics::Statistics;
+use std::convert::Infallible;
+use std::time::Instant;
+use warp::http::StatusCode;
+use warp::log::Info;
+
+lazy_static! {
+    pub static ref COMMANDS_RECEIVED: IntCounterVec = register_int_counter_vec!(
+        "commands_received_total", "Commands read from the commands topic", &["type"]).unwrap ();
+    pub static ref COMMANDS_ACCEPTED: IntCounterVec = register_int_counter_vec!(
+        "commands_accepted_total", "Commands that passed validation", &["type"]).unwrap ();
+    pub static ref COMMANDS_REJECTED: IntCounterVec = register_int_counter_vec!(
+        "commands_rejected_total", "Commands that failed validation", &["type"]).unwrap ();
+    pub static ref COMMANDS_EXPIRED: IntCounterVec = register_int_counter_vec!(
+        "commands_expired_total", "Commands rejected because they were processed after their expiry", &["type"]).unwrap ();
+    pub static ref EVENTS_PRODUCED: IntCounterVec = register_int_counter_vec!(
+        "events_produced_total", "Events written to the events topic", &["type"]).unwrap ();
+    pub static ref EVENTS_APPLIED: IntCounterVec = register_int_counter_vec!(
+        "events_applied_total", "Events applied to the materialized view", &["type"]).unwrap ();
+    pub static ref SAGA_OUTCOMES: IntCounterVec = register_int_counter_vec!(
+        "saga_command_outcomes_total", "Outcomes of the commands sent by sagas", &["outcome"]).unwrap ();
+    pub static ref SCHEDULED_COMMANDS: IntCounterVec = register_int_counter_vec!(
+        "scheduled_commands_total", "Commands sent by the scheduler", &["result"]).unwrap ();
+    pub static ref DESERIALIZATION_ERRORS: IntCounterVec = register_int_counter_vec!(
+        "deserialization_errors_total", "Messages that could not be deserialized", &["component"]).unwrap ();
+    pub static ref HTTP_REQUEST_DURATION: HistogramVec = register_histogram_vec!(
+        "http_request_duration_seconds", "HTTP request latency", &["method", "route", "status"]).unwrap ();
+    pub static ref HTTP_RATE_LIMITED: IntCounterVec = register_int_counter_vec!(
+        "http_rate_limited_total", "Requests refused by the rate limiter", &["route"]).unwrap ();
+    pub static ref PRODUCER_SEND_DURATION: HistogramVec = register_histogram_vec!(
+        "producer_send_duration_seconds", "Time until a produced message is acknowledged", &["topic", "result"]).unwrap ();
+    pub static ref CONSUMER_LAG: IntGaugeVec = register_int_gauge_vec!(
+        "consumer_lag", "Messages behind the high watermark", &["consumer", "topic", "partition"]).unwrap ();
+    pub static ref KAFKA_MESSAGES_QUEUED: IntGaugeVec = register_int_gauge_vec!(
+        "kafka_client_messages_queued", "Messages waiting in librdkafka queues", &["client"]).unwrap ();
+    pub static ref KAFKA_REPLY_QUEUE: IntGaugeVec = register_int_gauge_vec!(
+        "kafka_client_reply_queue", "Operations waiting to be served by poll", &["client"]).unwrap ();
+    pub static ref KAFKA_REQUESTS_SENT: IntGaugeVec = register_int_gauge_vec!(
+        "kafka_client_requests_sent", "Requests sent to the brokers", &["client"]).unwrap ();
+    pub static ref KAFKA_BYTES_SENT: IntGaugeVec = register_int_gauge_vec!(
+        "kafka_client_bytes_sent", "Bytes sent to the brokers", &["client"]).unwrap ();
+    pub static ref KAFKA_RESPONSES_RECEIVED: IntGaugeVec = register_int_gauge_vec!(
+        "kafka_client_responses_received", "Responses received from the brokers", &["client"]).unwrap ();
+    pub static ref KAFKA_BYTES_RECEIVED: IntGaugeVec = register_int_gauge_vec!(
+        "kafka_client_bytes_received", "Bytes received from the brokers", &["client"]).unwrap ();
+    pub static ref KAFKA_MESSAGES_CONSUMED: IntGaugeVec = register_int_gauge_vec!(
+        "kafka_client_messages_consumed", "Messages consumed from the brokers", &["client"]).unwrap ();
+    pub static ref KAFKA_BROKER_RTT: IntGaugeVec = register_int_gauge_vec!(
+        "kafka_broker_rtt_avg_microseconds", "Average broker round trip time", &["client", "broker"]).unwrap ();
+}
+
+/// records the statistics emitted by librdkafka every `statistics.interval.ms`
+pub fn record_statistics (consumer: &str, statistics: &Statistics) {
+    let client = statistics.name.as_str ();
+
+    KAFKA_MESSAGES_QUEUED.with_label_values (&[client]).set (statistics.msg_cnt as i64);
+    KAFKA_REPLY_QUEUE.with_label_values (&[client]).set (statistics.replyq);
+    KAFKA_REQUESTS_SENT.with_label_values (&[client]).set (statistics.tx);
+    KAFKA_BYTES_SENT.with_label_values (&[client]).set (statistics.tx_bytes);
+    KAFKA_RESPONSES_RECEIVED.with_label_values (&[client]).set (statistics.rx);
+    KAFKA_BYTES_RECEIVED.with_label_values (&[client]).set (statistics.rx_bytes);
+    KAFKA_MESSAGES_CONSUMED.with_label_values (&[client]).set (statistics.rxmsgs);
+
+    for (name, broker) in statistics.brokers.iter () {
+        if let Some (rtt) = &broker.rtt {
+            KAFKA_BROKER_RTT.with_label_values (&[client, name]).set (rtt.avg);
+        }
+    }
+
+    for topic in statistics.topics.values () {
+        // partition -1 is librdkafka's internal unassigned partition
+        for partition in topic.partitions.values ().filter (|p| p.partition >= 0 && p.desired && p.consumer_lag >= 0) {
+            CONSUMER_LAG.with_label_values (&[consumer, &topic.topic, &partition.partition.to_string ()])
+                .set (partition.consumer_lag);
+        }
+    }
+}
+
+/// label of the requests whose path is none of the routes
+const UNMATCHED: &str = "unmatched";
+
+/// records the latency of a served HTTP request, labelled with its route so that the label cardinality is bounded,
+/// `paths` are the path templates of the routes served, see `api::routes`
+pub fn record_request (paths: &[String], info: Info) {
+    HTTP_REQUEST_DURATION
+        .with_label_values (&[info.method ().as_str (), route (paths, info.path ()), info.status ().as_str ()])
+        .observe (info.elapsed ().as_secs_f64 ());
+}
+
+/// the path template of the route of the path, `unmatched` when there is none:
+/// a `{param}` segment matches any segment and a template ending with `/` any path below it,
+/// a literal segment wins over a parameter from the left, `/values/by-name/{name}` over `/values/{id}/labels`
+fn route<'a> (paths: &'a [String], path: &str) -> &'a str {
+    let segments : Vec<&str> = path.trim_end_matches ('/').split ('/').collect ();
+    paths.iter ()
+        .filter (|template| {
+            let pattern : Vec<&str> = template.trim_end_matches ('/').split ('/').collect ();
+            match template.ends_with ('/') {
+                true => segments.len () >= pattern.len () && matches (&pattern, &segments[..pattern.len ()]),
+                false => matches (&pattern, &segments)
+            }
+        })
+        .max_by_key (|template| template.split ('/').map (|segment| !segment.starts_with ('{')).collect::<Vec<bool>> ())
+        .map (|template| template.as_str ())
+        .unwrap_or (UNMATCHED)
+}
+
+fn matches (pattern: &[&str], segments: &[&str]) -> bool {
+    pattern.len () == segments.len ()
+        && pattern.iter ().zip (segments).all (|(pattern, segment)| pattern.starts_with ('{') || pattern == segment)
+}
+
+/// GET /metrics
+pub async fn gather () -> Result<impl warp::Reply, Infallible> {
+    let encoder = TextEncoder::new ();
+    let mut buffer = Vec::new ();
+
+    match encoder.encode (&prometheus::gather (), &mut buffer) {
+        Ok (_) => Ok (warp::reply::with_status (
+            warp::reply::with_header (String::from_utf8 (buffer).unwrap_or_default (), "content-type", encoder.format_type ()),
+            StatusCode::OK)),
+        Err (why) => Ok (warp::reply::with_status (
+            warp::reply::with_header (format!("{}", why), "content-type", "text/plain"),
+            StatusCode::INTERNAL_SERVER_ERROR))
+    }
+}
+
+/// records the time taken by a producer send started at `started`
+pub fn record_send (topic: &str, started: Instant, ok: bool) {
+    let result = match ok {
+        true => "ok",
+        false => "error"
+    };
+    PRODUCER_SEND_DURATION.with_label_values (&[topic, result])
+        .observe (started.elapsed ().as_secs_f64 ());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::openapi::ApiDoc;
+    use utoipa::OpenApi;
+
+    fn paths () -> Vec<String> {
+        ApiDoc::openapi ().paths.paths.keys ().cloned ().collect ()
+    }
+
+    #[test]
+    fn labels_the_requests_with_their_route () {
+        let paths = paths ();
+        assert_eq!(route (&paths, "/values"), "/values");
+        assert_eq!(route (&paths, "/values/2753b941-eb10-497c-b58c-2b3dec1eeeef"), "/values/{id}");
+        assert_eq!(route (&paths, "/values/by-name/savings"), "/values/by-name/{name}");
+        assert_eq!(route (&paths, "/values/by-name/labels"), "/values/by-name/{name}");
+        assert_eq!(route (&paths, "/groups/team-core"), "/groups/{id}");
+        assert_eq!(route (&paths, "/admin/groups/events-processors/offsets"), "/admin/groups/{id}/offsets");
+        assert_eq!(route (&paths, "/docs"), "/docs/");
+        assert_eq!(route (&paths, "/docs/swagger-ui.css"), "/docs/");
+    }
+
+    #[test]
+    fn labels_the_other_requests_unmatched () {
+        let paths = paths ();
+        for path in ["/", "/nope", "/values/a/b/c", "/groups", "/admin/secrets", "/metrics/x"] {
+            assert_eq!(route (&paths, path), UNMATCHED, "{}", path);
+        }
+        assert_eq!(route (&[], "/values"), UNMATCHED);
+    }
+}
@@ -0,0 +1,398 @@
+use crate::inputs_schema::OperationType;
+use async_graphql::{InputValueError, InputValueResult, Scalar, ScalarType};
+use bigdecimal::{BigDecimal, RoundingMode, Signed, ToPrimitive, Zero};
+use serde::de::{self, Deserializer, Visitor};
+use serde::{Deserialize, Serialize, Serializer};
+use std::cmp::Ordering;
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+use utoipa::openapi::schema::{ObjectBuilder, OneOfBuilder, Schema, Type};
+use utoipa::openapi::RefOr;
+use utoipa::ToSchema;
+
+/// digits after the decimal point of a decimal value, at most
+pub const MAX_SCALE: u32 = 64;
+
+/// digits of a decimal, at most, so an operation cannot grow a value without bound
+pub const MAX_DIGITS: u64 = 1000;
+
+/// a number of a value or an operation, a JSON number or, for an exact decimal, a string like `"0.10"`
+#[derive(Clone, Debug, PartialEq)]
+pub enum Number {
+    Float (f64),
+    Integer (i64),
+    Decimal (BigDecimal),
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize, ToSchema, async_graphql::Enum)]
+#[serde(rename_all = "snake_case")]
+pub enum NumberKind {
+    /// a 64 bit floating point number
+    #[default]
+    Float,
+    /// a 64 bit signed integer, an update overflowing it is rejected
+    Integer,
+    /// an exact decimal rounded to its scale after every update
+    Decimal,
+}
+
+/// how a decimal is rounded to its scale
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize, ToSchema, async_graphql::Enum)]
+#[serde(rename_all = "snake_case")]
+pub enum Rounding {
+    #[default]
+    HalfEven,
+    HalfUp,
+    HalfDown,
+    /// away from zero
+    Up,
+    /// towards zero
+    Down,
+    Ceiling,
+    Floor,
+}
+
+/// the kind of number of a value, chosen at its creation
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize, ToSchema, async_graphql::InputObject)]
+pub struct ValueType {
+    #[serde(default)]
+    #[graphql(default)]
+    pub kind: NumberKind,
+    /// digits after the decimal point, required for a decimal
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scale: Option<u32>,
+    #[serde(default)]
+    #[graphql(default)]
+    pub rounding: Rounding,
+}
+
+impl ValueType {
+    /// checked before the value is created
+    pub fn validate (&self) -> Result<(), String> {
+        match (self.kind, self.scale) {
+            (NumberKind::Decimal, None) => Err (String::from ("a decimal value needs a scale")),
+            (NumberKind::Decimal, Some (scale)) if scale > MAX_SCALE => Err (format!("scale must be at most {}, got {}", MAX_SCALE, scale)),
+            (NumberKind::Decimal, Some (_)) => Ok (()),
+            (_, Some (_)) => Err (String::from ("only a decimal value has a scale")),
+            (_, None) => Ok (())
+        }
+    }
+
+    /// the number as this kind, without rounding, fails when it cannot be represented
+    pub fn exact (&self, number: &Number) -> Result<Number, String> {
+        number.validate ()?;
+        match (self.kind, number) {
+            (NumberKind::Float, Number::Float (float)) => Ok (Number::Float (*float)),
+            (NumberKind::Float, Number::Integer (integer)) => Ok (Number::Float (*integer as f64)),
+            (NumberKind::Float, Number::Decimal (decimal)) => decimal.to_f64 ()
+                .filter (|float| float.is_finite ())
+                .map (Number::Float)
+                .ok_or_else (|| format!("{} is not a finite float", number)),
+            (NumberKind::Integer, Number::Integer (integer)) => Ok (Number::Integer (*integer)),
+            (NumberKind::Integer, _) => number.to_decimal ()
+                .filter (|decimal| decimal.is_integer ())
+                .and_then (|decimal| decimal.to_i64 ())
+                .map (Number::Integer)
+                .ok_or_else (|| format!("{} is not a 64 bit integer", number)),
+            (NumberKind::Decimal, _) => number.to_decimal ()
+                .map (Number::Decimal)
+                .ok_or_else (|| format!("{} is not a decimal", number))
+        }
+    }
+
+    /// the number as this kind, a decimal rounded to the scale
+    pub fn round (&self, number: &Number) -> Result<Number, String> {
+        match (self.exact (number)?, self.scale) {
+            (Number::Decimal (decimal), Some (scale)) => Ok (Number::Decimal (decimal.with_scale_round (scale as i64, self.rounding.into ()))),
+            (exact, _) => Ok (exact)
+        }
+    }
+
+    /// `current` after the operation, fails when the operand or the result cannot be represented
+    pub fn apply (&self, current: &Number, operation: OperationType, operand: &Number) -> Result<Number, String> {
+        let result = match (self.exact (current)?, self.exact (operand)?) {
+            (Number::Float (current), Number::Float (operand)) => match operation {
+                OperationType::ADD => Number::Float (current + operand),
+                OperationType::MULTIPLY => Number::Float (current * operand)
+            },
+            (Number::Integer (current), Number::Integer (operand)) => match operation {
+                OperationType::ADD => current.checked_add (operand),
+                OperationType::MULTIPLY => current.checked_mul (operand)
+            }.map (Number::Integer).ok_or_else (|| String::from ("the result overflows a 64 bit integer"))?,
+            (Number::Decimal (current), Number::Decimal (operand)) => match operation {
+                OperationType::ADD => Number::Decimal (current + operand),
+                OperationType::MULTIPLY => Number::Decimal (current * operand)
+            },
+            _ => return Err (format!("{} and {} are not of the same kind", current, operand))
+        };
+        self.round (&result)
+    }
+}
+
+impl From<Rounding> for RoundingMode {
+    fn from (rounding: Rounding) -> RoundingMode {
+        match rounding {
+            Rounding::HalfEven => RoundingMode::HalfEven,
+            Rounding::HalfUp => RoundingMode::HalfUp,
+            Rounding::HalfDown => RoundingMode::HalfDown,
+            Rounding::Up => RoundingMode::Up,
+            Rounding::Down => RoundingMode::Down,
+            Rounding::Ceiling => RoundingMode::Ceiling,
+            Rounding::Floor => RoundingMode::Floor
+        }
+    }
+}
+
+impl Number {
+    /// a float must be finite, a decimal at most `MAX_DIGITS` long
+    pub fn validate (&self) -> Result<(), String> {
+        match self {
+            Number::Float (float) if !float.is_finite () => Err (format!("value must be a finite number, got {}", float)),
+            Number::Decimal (decimal) if decimal.digits () > MAX_DIGITS || decimal.fractional_digit_count ().unsigned_abs () > MAX_DIGITS =>
+                Err (format!("a decimal must have at most {} digits", MAX_DIGITS)),
+            _ => Ok (())
+        }
+    }
+
+    /// the closest float, for the clients reading floats
+    pub fn to_f64 (&self) -> f64 {
+        match self {
+            Number::Float (float) => *float,
+            Number::Integer (integer) => *integer as f64,
+            Number::Decimal (decimal) => decimal.to_f64 ().unwrap_or (f64::NAN)
+        }
+    }
+
+    /// the number as an exact decimal, a float as its shortest representation, `None` when it is not finite
+    pub fn to_decimal (&self) -> Option<BigDecimal> {
+        match self {
+            Number::Float (float) if float.is_finite () => BigDecimal::from_str (&float.to_string ()).ok (),
+            Number::Float (_) => None,
+            Number::Integer (integer) => Some (BigDecimal::from (*integer)),
+            Number::Decimal (decimal) => Some (decimal.clone ())
+        }
+    }
+
+    pub fn is_integer (&self) -> bool {
+        self.to_decimal ().is_some_and (|decimal| decimal.is_integer ())
+    }
+
+    pub fn is_negative (&self) -> bool {
+        self.to_decimal ().is_some_and (|decimal| decimal.is_negative ())
+    }
+
+    pub fn is_positive (&self) -> bool {
+        self.to_decimal ().is_some_and (|decimal| decimal.is_positive () && !decimal.is_zero ())
+    }
+
+    /// the opposite number, an integer saturates
+    pub fn negate (&self) -> Number {
+        match self {
+            Number::Float (float) => Number::Float (-float),
+            Number::Integer (integer) => Number::Integer (integer.saturating_neg ()),
+            Number::Decimal (decimal) => Number::Decimal (-decimal)
+        }
+    }
+
+    /// compares with a bound, `None` when either is not finite
+    pub fn compare (&self, bound: f64) -> Option<Ordering> {
+        let bound = Number::Float (bound).to_decimal ()?;
+        self.to_decimal ().map (|decimal| decimal.cmp (&bound))
+    }
+
+    /// the absolute difference with `other`, exactly
+    pub fn distance (&self, other: &Number) -> Option<BigDecimal> {
+        Some ((self.to_decimal ()? - other.to_decimal ()?).abs ())
+    }
+}
+
+impl fmt::Display for Number {
+    fn fmt (&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Number::Float (float) => write!(f, "{}", float),
+            Number::Integer (integer) => write!(f, "{}", integer),
+            Number::Decimal (decimal) => write!(f, "{}", decimal.to_plain_string ())
+        }
+    }
+}
+
+impl Serialize for Number {
+    fn serialize<S: Serializer> (&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Number::Float (float) => serializer.serialize_f64 (*float),
+            Number::Integer (integer) => serializer.serialize_i64 (*integer),
+            Number::Decimal (decimal) => serializer.serialize_str (&decimal.to_plain_string ())
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Number {
+    fn deserialize<D: Deserializer<'de>> (deserializer: D) -> Result<Number, D::Error> {
+        deserializer.deserialize_any (NumberVisitor)
+    }
+}
+
+struct NumberVisitor;
+
+impl<'de> Visitor<'de> for NumberVisitor {
+    type Value = Number;
+
+    fn expecting (&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str ("a number, or a decimal as a string")
+    }
+
+    fn visit_i64<E: de::Error> (self, integer: i64) -> Result<Number, E> {
+        Ok (Number::Integer (integer))
+    }
+
+    fn visit_u64<E: de::Error> (self, integer: u64) -> Result<Number, E> {
+        match i64::try_from (integer) {
+            Ok (integer) => Ok (Number::Integer (integer)),
+            Err (_) => Ok (Number::Float (integer as f64))
+        }
+    }
+
+    fn visit_f64<E: de::Error> (self, float: f64) -> Result<Number, E> {
+        Ok (Number::Float (float))
+    }
+
+    fn visit_str<E: de::Error> (self, decimal: &str) -> Result<Number, E> {
+        BigDecimal::from_str (decimal.trim ())
+            .map (Number::Decimal)
+            .map_err (|why| E::custom (format!("invalid decimal {}: {}", decimal, why)))
+    }
+}
+
+impl utoipa::PartialSchema for Number {
+    fn schema () -> RefOr<Schema> {
+        OneOfBuilder::new ()
+            .item (ObjectBuilder::new ().schema_type (Type::Number))
+            .item (ObjectBuilder::new ().schema_type (Type::String).examples (vec!["12.30"]))
+            .description (Some ("a number, or an exact decimal as a string"))
+            .into ()
+    }
+}
+
+impl ToSchema for Number {}
+
+/// a number, or an exact decimal as a string
+#[Scalar(name = "Number")]
+impl ScalarType for Number {
+    fn parse (value: async_graphql::Value) -> InputValueResult<Number> {
+        match &value {
+            async_graphql::Value::Number (number) => match (number.as_i64 (), number.as_f64 ()) {
+                (Some (integer), _) => Ok (Number::Integer (integer)),
+                (None, Some (float)) => Ok (Number::Float (float)),
+                (None, None) => Err (InputValueError::expected_type (value))
+            },
+            async_graphql::Value::String (decimal) => BigDecimal::from_str (decimal.trim ())
+                .map (Number::Decimal)
+                .map_err (|why| InputValueError::custom (format!("invalid decimal {}: {}", decimal, why))),
+            _ => Err (InputValueError::expected_type (value))
+        }
+    }
+
+    fn to_value (&self) -> async_graphql::Value {
+        match self {
+            Number::Float (float) => serde_json::Number::from_f64 (*float)
+                .map (async_graphql::Value::Number)
+                .unwrap_or (async_graphql::Value::Null),
+            Number::Integer (integer) => async_graphql::Value::Number ((*integer).into ()),
+            Number::Decimal (decimal) => async_graphql::Value::String (decimal.to_plain_string ())
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    pub(crate) fn decimal (decimal: &str) -> Number {
+        Number::Decimal (BigDecimal::from_str (decimal).unwrap ())
+    }
+
+    pub(crate) fn integer () -> ValueType {
+        ValueType { kind: NumberKind::Integer, ..ValueType::default () }
+    }
+
+    fn decimal_type (scale: u32, rounding: Rounding) -> ValueType {
+        ValueType { kind: NumberKind::Decimal, scale: Some (scale), rounding }
+    }
+
+    #[test]
+    fn adds_a_tenth_exactly () {
+        let value_type = decimal_type (2, Rounding::HalfEven);
+        let mut value = decimal ("0");
+        for _ in 0..10 {
+            value = value_type.apply (&value, OperationType::ADD, &decimal ("0.1")).unwrap ();
+        }
+        assert_eq!(value.to_string (), "1.00");
+
+        let float_type = ValueType::default ();
+        let mut float = Number::Float (0.0);
+        for _ in 0..10 {
+            float = float_type.apply (&float, OperationType::ADD, &Number::Float (0.1)).unwrap ();
+        }
+        assert_ne!(float, Number::Float (1.0));
+    }
+
+    #[test]
+    fn rounds_to_the_scale () {
+        let cases = [
+            (Rounding::HalfEven, ["0.12", "0.14", "-0.12"]),
+            (Rounding::HalfUp, ["0.13", "0.14", "-0.13"]),
+            (Rounding::HalfDown, ["0.12", "0.13", "-0.12"]),
+            (Rounding::Up, ["0.13", "0.14", "-0.13"]),
+            (Rounding::Down, ["0.12", "0.13", "-0.12"]),
+            (Rounding::Ceiling, ["0.13", "0.14", "-0.12"]),
+            (Rounding::Floor, ["0.12", "0.13", "-0.13"]),
+        ];
+        for (rounding, expected) in cases {
+            let value_type = decimal_type (2, rounding);
+            for (number, expected) in ["0.125", "0.135", "-0.125"].iter ().zip (expected) {
+                assert_eq!(value_type.round (&decimal (number)).unwrap ().to_string (), expected, "{} {:?}", number, rounding);
+            }
+        }
+        assert_eq!(decimal_type (3, Rounding::HalfEven).round (&Number::Integer (2)).unwrap ().to_string (), "2.000");
+    }
+
+    #[test]
+    fn rejects_an_integer_overflow () {
+        let value_type = integer ();
+        assert!(value_type.apply (&Number::Integer (i64::MAX), OperationType::ADD, &Number::Integer (1)).is_err ());
+        assert!(value_type.apply (&Number::Integer (i64::MIN), OperationType::ADD, &Number::Integer (-1)).is_err ());
+        assert!(value_type.apply (&Number::Integer (i64::MAX), OperationType::MULTIPLY, &Number::Integer (2)).is_err ());
+        assert_eq!(value_type.apply (&Number::Integer (i64::MAX - 1), OperationType::ADD, &Number::Integer (1)), Ok (Number::Integer (i64::MAX)));
+        assert!(value_type.apply (&Number::Integer (1), OperationType::ADD, &Number::Float (0.5)).is_err ());
+    }
+
+    #[test]
+    fn rejects_too_many_digits () {
+        let digits = MAX_DIGITS as usize;
+        assert!(decimal (&"1".repeat (digits)).validate ().is_ok ());
+        assert!(decimal (&"1".repeat (digits + 1)).validate ().is_err ());
+        assert!(decimal (&format!("0.{}", "1".repeat (digits + 1))).validate ().is_err ());
+        assert!(decimal ("1e1000000000").validate ().is_err ());
+        assert!(decimal ("1e-1000000000").validate ().is_err ());
+
+        let value_type = decimal_type (0, Rounding::HalfEven);
+        assert!(value_type.exact (&decimal ("1e1000000000")).is_err ());
+        let large = decimal (&format!("1{}", "0".repeat (600)));
+        assert!(value_type.apply (&large, OperationType::MULTIPLY, &large).is_err ());
+    }
+
+    #[test]
+    fn round_trips_decimals_as_json_strings () {
+        let number = decimal ("0.10");
+        let json = serde_json::to_string (&number).unwrap ();
+        assert_eq!(json, r#""0.10""#);
+        let parsed: Number = serde_json::from_str (&json).unwrap ();
+        assert_eq!(parsed.to_string (), "0.10");
+
+        assert_eq!(serde_json::from_str::<Number> ("12").unwrap (), Number::Integer (12));
+        assert_eq!(serde_json::from_str::<Number> ("1.5").unwrap (), Number::Float (1.5));
+        assert_eq!(serde_json::from_str::<Number> (r#"" -2.50 ""#).unwrap ().to_string (), "-2.50");
+        assert!(serde_json::from_str::<Number> (r#""ten""#).is_err ());
+    }
+}
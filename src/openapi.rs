@@ -0,0 +1,194 @@
+use crate::admin_api::{GroupLag, OffsetReset, PartitionDescription, PartitionLag, PartitionOffset, TopicDescription, TopicSummary};
+use crate::api;
+use crate::commands_schema::Value as ValueBody;
+use crate::errors::{ApiError, Problem};
+use crate::group_view::Group;
+use crate::inputs_schema::{Labels, OperationType, ScheduleInput, TransferInput, ValueInput, ValueOperationInput};
+use crate::scheduler::{Schedule, ScheduleStatus};
+use crate::transfer::{TransferStatus, TransferView};
+use std::sync::Arc;
+use utoipa::openapi::security::{ApiKey, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityScheme};
+use utoipa::{Modify, OpenApi};
+use warp::http::Uri;
+use warp::hyper::{Body, Response};
+use warp::path::{FullPath, Tail};
+
+/// OpenAPI 3 document of the HTTP API, built from the `#[utoipa::path]` of the routes in `api`
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "type-kafka", description = "Commands, events and materialized views over Kafka"),
+    paths(api::create_value, api::update_value, api::relabel_value, api::query_value, api::query_values, api::query_value_by_name, api::query_group, api::create_transfer, api::query_transfer,
+           api::schedule_value, api::query_schedules, api::cancel_schedule, api::graphql,
+           api::list_topics, api::describe_topic, api::groups_lag, api::group_lag, api::reset_offsets,
+           api::live, api::ready, api::metrics, api::openapi_json, api::docs),
+    components(schemas(ValueInput, ValueOperationInput, Labels, OperationType, ValueBody, Group, TransferInput, TransferView, TransferStatus,
+                         ScheduleInput, Schedule, ScheduleStatus, Problem,
+                         TopicSummary, TopicDescription, PartitionDescription, GroupLag, PartitionLag,
+                         OffsetReset, PartitionOffset),
+                responses(Problem)),
+    modifiers(&SecuritySchemes),
+    tags((name = "values", description = "commands and queries of the values"),
+          (name = "transfers", description = "transfers between values, run as sagas"),
+          (name = "schedules", description = "commands sent later, once or on a cron schedule"),
+          (name = "graphql", description = "queries, mutations and subscriptions of the values, see the schema"),
+          (name = "admin", description = "topics and consumer groups, see the README"),
+          (name = "health", description = "health checks and metrics"),
+          (name = "docs", description = "this document and its UI"))
+)]
+pub struct ApiDoc;
+
+/// the credentials accepted by `auth`
+struct SecuritySchemes;
+
+impl Modify for SecuritySchemes {
+    fn modify (&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with (Default::default);
+        components.add_security_scheme ("bearer",
+                                        SecurityScheme::Http (HttpBuilder::new ()
+                                                              .scheme (HttpAuthScheme::Bearer)
+                                                              .description (Some ("JWT or API key"))
+                                                              .build ()));
+        components.add_security_scheme ("api_key", SecurityScheme::ApiKey (ApiKey::Header (ApiKeyValue::new ("X-Api-Key"))));
+    }
+}
+
+pub fn spec () -> utoipa::openapi::OpenApi {
+    let mut spec = ApiDoc::openapi ();
+    spec.info.version = String::from (env!("CARGO_PKG_VERSION"));
+    // the package has no license, utoipa would document an empty one
+    spec.info.license = None;
+    spec
+}
+
+/// GET /docs/** the Swagger UI files, `/docs` is redirected to `/docs/` for the relative links of the UI
+pub async fn serve_docs (
+    full_path: FullPath,
+    tail: Tail,
+    config: Arc<utoipa_swagger_ui::Config<'static>>
+) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+
+    if full_path.as_str () == "/docs" {
+        return Ok (Box::new (warp::redirect::found (Uri::from_static ("/docs/"))));
+    }
+
+    match utoipa_swagger_ui::serve (tail.as_str (), config) {
+        Ok (Some (file)) => Ok (Box::new (Response::builder ()
+                                          .header ("content-type", file.content_type)
+                                          .body (Body::from (file.bytes.into_owned ()))
+                                          .expect ("Could not build response"))),
+        Ok (None) => Err (warp::reject::not_found ()),
+        Err (why) => Err (warp::reject::custom (ApiError::Internal (why.to_string ())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth;
+    use crate::config::tests::config;
+    use crate::{admin, db, errors, group_view, health, materialized_view, process_manager, scheduler};
+    use std::collections::BTreeSet;
+    use utoipa::openapi::PathItem;
+    use utoipa::openapi::path::HttpMethod;
+    use warp::http::Method;
+    use warp::Filter;
+
+    const ADMIN_TOKEN: &str = "openapi-test";
+
+    /// the routes of the `all` role and their operations, Kafka calls time out quickly since there is no broker
+    fn routes () -> (api::Routes, Vec<api::Operation>) {
+        let config = config (&[&format!("ADMIN_TOKEN={}", ADMIN_TOKEN), "ADMIN_TIMEOUT_MS=50", "HEALTH_BROKER_TIMEOUT_MS=50", "RATE_LIMIT_PER_SECOND=0"]);
+        let auth = auth::init (&config).expect ("valid auth");
+        let views = api::Views { db: Some (db::init ()), changes: materialized_view::changes (), transfers: Some (process_manager::init ()),
+                                 schedules: Some (scheduler::init ()), groups: Some (group_view::init ()) };
+        api::routes (&config, views, api::commands (&config), admin::init (&config), health::init (), auth)
+    }
+
+    fn operations_of (item: &PathItem) -> Vec<(Method, bool)> {
+        vec![(Method::GET, item.get.is_some ()),
+             (Method::PUT, item.put.is_some ()),
+             (Method::POST, item.post.is_some ()),
+             (Method::DELETE, item.delete.is_some ()),
+             (Method::PATCH, item.patch.is_some ())]
+    }
+
+    fn method (method: &HttpMethod) -> Method {
+        match method {
+            HttpMethod::Get => Method::GET,
+            HttpMethod::Put => Method::PUT,
+            HttpMethod::Post => Method::POST,
+            HttpMethod::Delete => Method::DELETE,
+            HttpMethod::Patch => Method::PATCH,
+            HttpMethod::Options => Method::OPTIONS,
+            HttpMethod::Head => Method::HEAD,
+            HttpMethod::Trace => Method::TRACE
+        }
+    }
+
+    /// `/values/{id}` as `/values/<uuid>`
+    fn concrete (path: &str) -> String {
+        path.split ('/')
+            .map (|segment| match segment.starts_with ('{') {
+                true => "0b6d2a52-1e39-4a8e-9e4b-7a6f3a0b8c11",
+                false => segment
+            })
+            .collect::<Vec<&str>> ()
+            .join ("/")
+    }
+
+    /// whether the request is matched by none of the routes, the 404 of an unknown value does not count
+    async fn unmatched (routes: &api::Routes, method: Method, path: &str) -> bool {
+        let routes = routes.clone ().recover (errors::recover);
+        let response = warp::test::request ()
+            .method (method.as_str ())
+            .path (path)
+            .header ("x-api-key", ADMIN_TOKEN)
+            .reply (&routes)
+            .await;
+        let unknown = serde_json::to_vec (&ApiError::NotFound (String::from ("no such resource")).problem ()).unwrap ();
+        response.status () == 405 || (response.status () == 404 && response.body ().as_ref () == unknown.as_slice ())
+    }
+
+    /// the routes served are the ones documented, and their filters serve the documented paths and methods only
+    #[tokio::test]
+    async fn routes_match_the_spec () {
+        let spec = spec ();
+        let (routes, operations) = routes ();
+        let mut diverging = Vec::new ();
+
+        let served : BTreeSet<(String, String)> = operations.iter ()
+            .flat_map (|(path, methods)| methods.iter ().map (move |m| (path.clone (), method (m).to_string ())))
+            .collect ();
+        let documented : BTreeSet<(String, String)> = spec.paths.paths.iter ()
+            .flat_map (|(path, item)| operations_of (item).into_iter ()
+                       .filter (|(_, documented)| *documented)
+                       .map (move |(method, _)| (path.clone (), method.to_string ())))
+            .collect ();
+        for (path, method) in served.difference (&documented) {
+            diverging.push (format!("{} {} is served but not documented", method, path));
+        }
+        for (path, method) in documented.difference (&served) {
+            diverging.push (format!("{} {} is documented but not served", method, path));
+        }
+
+        for (path, item) in &spec.paths.paths {
+            let path = concrete (path);
+            for (method, documented) in operations_of (item) {
+                match (documented, unmatched (&routes, method.clone (), &path).await) {
+                    (true, true) => diverging.push (format!("{} {} is documented but its filter does not match it", method, path)),
+                    (false, false) => diverging.push (format!("{} {} is matched by a filter but not documented", method, path)),
+                    _ => ()
+                }
+            }
+        }
+
+        assert!(diverging.is_empty (), "routes and /openapi.json diverge:\n{}", diverging.join ("\n"));
+    }
+
+    #[tokio::test]
+    async fn unknown_routes_are_unmatched () {
+        let (routes, _) = routes ();
+        assert!(unmatched (&routes, Method::GET, "/nothing").await);
+        assert!(unmatched (&routes, Method::DELETE, "/values").await);
+    }
+}
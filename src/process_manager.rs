@@ -0,0 +1,477 @@
+use crate::aggregate::{AggregateCommand, AggregateEvent};
+use crate::commands;
+use crate::commands_schema::Command;
+use crate::config::Config;
+use crate::consumer::CustomConsumer;
+use crate::consumer;
+use crate::envelope::Envelope;
+use crate::errors::ApiError;
+use crate::events_schema::Event;
+use crate::health::Health;
+use crate::health;
+use crate::inputs_schema::Expiry;
+use crate::metrics;
+use crate::producer::Producer;
+use crate::producer;
+use crate::state_topic;
+use chrono::{DateTime, Utc};
+use log::{debug, info, warn, error};
+use rdkafka::consumer::{CommitMode, Consumer};
+use rdkafka::message::Message;
+use rdkafka::topic_partition_list::{TopicPartitionList, Offset};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+/// how often the deadlines of the sagas are checked
+const TIMEOUT_CHECK_INTERVAL: Duration = Duration::from_secs (1);
+
+/// outcome of a command sent by a saga
+#[derive(Clone, Debug, PartialEq)]
+pub enum Outcome {
+    /// the command processor accepted the command and wrote its events
+    Applied,
+    /// the command processor rejected the command, with the reason
+    Rejected (String),
+    /// no outcome within SAGA_STEP_TIMEOUT_MS, the command may still be applied later
+    TimedOut,
+}
+
+impl Outcome {
+    /// label of the outcome in the metrics
+    fn name (&self) -> &'static str {
+        match self {
+            Outcome::Applied => "applied",
+            Outcome::Rejected (_) => "rejected",
+            Outcome::TimedOut => "timed_out"
+        }
+    }
+}
+
+/// a workflow over several values, driven by the outcomes of the commands it sends,
+/// its state is written to the sagas topic after every step, see `run`
+pub trait Saga: Serialize + DeserializeOwned + Clone + Debug + Send + Sync {
+    /// the commands that start the saga
+    fn start (&mut self, tenant: &str) -> Vec<Command>;
+
+    /// the commands that follow the outcome of the saga's command `command_id`, to go on or to compensate,
+    /// a command that timed out can still have an outcome later
+    fn react (&mut self, tenant: &str, command_id: Uuid, outcome: Outcome) -> Vec<Command>;
+
+    /// whether the saga is done once the commands it waits for have an outcome
+    fn is_finished (&self) -> bool;
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SagaStatus {
+    /// written by the API, not yet started by the process manager
+    Requested,
+    Running,
+    Finished,
+}
+
+/// the durable state of a saga, the message value of the sagas topic, keyed by the saga id
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SagaRecord<S> {
+    pub id: Uuid,
+    pub tenant: String,
+    /// user who started the saga, its commands are sent on its behalf
+    pub user_id: String,
+    pub correlation_id: String,
+    pub status: SagaStatus,
+    /// commands sent whose outcome is not known yet
+    pub pending: Vec<Uuid>,
+    /// commands that timed out, their late outcome is still given to the saga
+    pub timed_out: Vec<Uuid>,
+    /// when the pending commands time out
+    pub deadline: Option<DateTime<Utc>>,
+    pub updated_at: DateTime<Utc>,
+    pub saga: S,
+}
+
+impl<S: Saga> SagaRecord<S> {
+    /// a saga to be started by the process manager
+    pub fn new (tenant: &str, user_id: &str, correlation_id: &str, saga: S) -> SagaRecord<S> {
+        SagaRecord {
+            id: Uuid::new_v4 (),
+            tenant: String::from (tenant),
+            user_id: String::from (user_id),
+            correlation_id: String::from (correlation_id),
+            status: SagaStatus::Requested,
+            pending: Vec::new (),
+            timed_out: Vec::new (),
+            deadline: None,
+            updated_at: Utc::now (),
+            saga,
+        }
+    }
+}
+
+/// the sagas started by the process manager of this process, by id
+pub type Sagas<S> = Arc<Mutex<HashMap<Uuid, SagaRecord<S>>>>;
+
+pub fn init<S> () -> Sagas<S> {
+    Arc::new (Mutex::new (HashMap::new ()))
+}
+
+/// the tenant's saga, `None` for the sagas of other tenants
+pub async fn get<S: Clone> (sagas: &Sagas<S>, tenant: &str, id: &Uuid) -> Option<SagaRecord<S>> {
+    let sagas = sagas.lock ().await;
+    sagas.get (id).filter (|record| record.tenant == tenant).cloned ()
+}
+
+/// writes a new saga to the sagas topic, the process manager starts it when it reads it,
+/// fails when Kafka does not acknowledge the record
+pub async fn request<S: Saga> (record: &SagaRecord<S>, producer: Producer, config: &Config) -> Result<(), ApiError> {
+    info!("Requesting saga {} of tenant {} for {}", record.id, record.tenant, record.user_id);
+    write (record, producer, config).await
+}
+
+/// runs the sagas of the sagas topic until the process stops:
+/// the records are read from the beginning to restore the sagas, the events give the outcomes of their commands,
+/// the commands without outcome past their deadline time out
+pub async fn run<S: Saga> (config: Arc<Config>, sagas: Sagas<S>, health: Health) {
+
+    let _registration = health::register (&health, "process-manager", None);
+    let consumer = consumer::init (&config, &config.sagas_group_id, "process-manager", health.clone ());
+    let mut manager = ProcessManager { producer: producer::init (&config), config, sagas, waiting: HashMap::new () };
+
+    let restored_until = manager.restore (&consumer).await;
+
+    // the sagas topic from where the restore stopped, to start the new sagas, and the events from the group's offsets
+    let mut topic_map : HashMap<(String, i32), Offset> = manager.config.events_topics ().into_iter ()
+        .map (|topic| ((topic, 0), Offset::Stored))
+        .collect ();
+    topic_map.insert ((manager.config.sagas_topic.clone (), 0), Offset::Offset (restored_until));
+
+    let tpl : TopicPartitionList = TopicPartitionList::from_topic_map (&topic_map).unwrap ();
+    consumer.assign (&tpl).expect ("Could not set topic partition list");
+    consumer::confirm_assignment (&consumer, &tpl, &manager.config, &health, "process-manager").await;
+
+    manager.resume ().await;
+
+    let mut deadlines = tokio::time::interval (TIMEOUT_CHECK_INTERVAL);
+
+    loop {
+        tokio::select! {
+            _ = deadlines.tick () => manager.time_out ().await,
+            message = consumer.recv () => match message {
+                // NOTE: librdkafka recovers from broker errors on its own, only fatal errors stop the consumer
+                Err (why) => match consumer.client ().fatal_error () {
+                    Some ((code, reason)) => panic!("Fatal error reading from {:?} : {:?} {}", &tpl, code, reason),
+                    None => warn!("Failed to read message from {:?} : {}", &tpl, why)
+                },
+                Ok (m) => {
+                    match m.topic () == manager.config.sagas_topic {
+                        true => if let Some (record) = state_topic::parse::<SagaRecord<S>> (&m, "process-manager") {
+                            manager.requested (record).await;
+                        },
+                        false => if let Some (event) = state_topic::parse::<Event> (&m, "process-manager") {
+                            manager.outcome (event).await;
+                        }
+                    };
+
+                    match consumer.commit_message (&m, CommitMode::Async) {
+                        Err (why) => error!("Failed to commit message offset: {}", why),
+                        Ok (_) => debug!("Commited message offset: {}", m.offset ())
+                    };
+                }
+            }
+        }
+    }
+}
+
+struct ProcessManager<S> {
+    config: Arc<Config>,
+    sagas: Sagas<S>,
+    producer: Producer,
+    /// saga of each command waiting for its outcome
+    waiting: HashMap<Uuid, Uuid>,
+}
+
+impl<S: Saga> ProcessManager<S> {
+
+    /// reads the sagas topic up to its end, returns the offset the live consumer continues from
+    async fn restore (&mut self, consumer: &CustomConsumer) -> i64 {
+
+        let (records, restored_until) = state_topic::restore::<SagaRecord<S>> (consumer, &self.config.sagas_topic, &self.config, "process-manager").await;
+        self.load (records).await;
+        restored_until
+    }
+
+    /// keeps the last record of each saga, and waits again for the commands without outcome
+    async fn load (&mut self, records: Vec<SagaRecord<S>>) {
+        let mut sagas = self.sagas.lock ().await;
+        for record in records {
+            sagas.insert (record.id, record);
+        }
+        for record in sagas.values () {
+            for command_id in record.pending.iter ().chain (record.timed_out.iter ()) {
+                self.waiting.insert (*command_id, record.id);
+            }
+        }
+        info!("Restored {} sagas", sagas.len ());
+    }
+
+    /// starts the sagas requested while no process manager was running
+    async fn resume (&mut self) {
+        let requested : Vec<SagaRecord<S>> = self.sagas.lock ().await.values ()
+            .filter (|record| record.status == SagaStatus::Requested)
+            .cloned ()
+            .collect ();
+        for record in requested {
+            self.start (record).await;
+        }
+    }
+
+    /// starts a saga written by the API, the other records of the topic are the process manager's own
+    async fn requested (&mut self, record: SagaRecord<S>) {
+        let known = self.sagas.lock ().await.contains_key (&record.id);
+        if record.status == SagaStatus::Requested && !known {
+            self.start (record).await;
+        }
+    }
+
+    async fn start (&mut self, mut record: SagaRecord<S>) {
+        info!("Starting saga {} of tenant {}: {:?}", record.id, record.tenant, record.saga);
+        let commands = record.saga.start (&record.tenant);
+        self.advance (record, commands).await;
+    }
+
+    /// gives the outcome of the event's command to the saga that sent it, if any
+    async fn outcome (&mut self, event: Event) {
+        let command_id = event.parent ();
+        let saga_id = match self.waiting.remove (&command_id) {
+            Some (saga_id) => saga_id,
+            None => return
+        };
+        let mut record = match self.sagas.lock ().await.get (&saga_id) {
+            Some (record) => record.clone (),
+            None => return
+        };
+
+        let outcome = match event {
+            Event::CommandRejected { data, .. } => Outcome::Rejected (data.reason),
+            _ => Outcome::Applied
+        };
+        info!("Command {} of saga {}: {:?}", command_id, saga_id, outcome);
+        metrics::SAGA_OUTCOMES.with_label_values (&[outcome.name ()]).inc ();
+
+        record.pending.retain (|id| *id != command_id);
+        record.timed_out.retain (|id| *id != command_id);
+        let commands = record.saga.react (&record.tenant, command_id, outcome);
+        self.advance (record, commands).await;
+    }
+
+    /// the pending commands of the sagas past their deadline time out
+    async fn time_out (&mut self) {
+        let now = Utc::now ();
+        let expired : Vec<SagaRecord<S>> = self.sagas.lock ().await.values ()
+            .filter (|record| record.deadline.is_some_and (|deadline| deadline <= now))
+            .cloned ()
+            .collect ();
+
+        for mut record in expired {
+            let timed_out = std::mem::take (&mut record.pending);
+            warn!("Commands {:?} of saga {} timed out", timed_out, record.id);
+
+            let mut commands = Vec::new ();
+            for command_id in &timed_out {
+                metrics::SAGA_OUTCOMES.with_label_values (&[Outcome::TimedOut.name ()]).inc ();
+                commands.extend (record.saga.react (&record.tenant, *command_id, Outcome::TimedOut));
+            }
+            record.timed_out.extend (timed_out);
+            record.deadline = None;
+            self.advance (record, commands).await;
+        }
+    }
+
+    /// records the step of the saga and sends its commands,
+    /// the record is written first so a command lost by a crash times out instead of being forgotten
+    async fn advance (&mut self, mut record: SagaRecord<S>, commands: Vec<Command>) {
+
+        let now = Utc::now ();
+        for command in &commands {
+            record.pending.push (command.id ());
+            self.waiting.insert (command.id (), record.id);
+        }
+        if !commands.is_empty () {
+            record.deadline = Some (now + chrono::Duration::milliseconds (self.config.saga_step_timeout_ms as i64));
+        }
+        if record.pending.is_empty () {
+            record.deadline = None;
+        }
+        record.status = match record.pending.is_empty () && record.saga.is_finished () {
+            true => SagaStatus::Finished,
+            false => SagaStatus::Running
+        };
+        record.updated_at = now;
+
+        self.sagas.lock ().await.insert (record.id, record.clone ());
+
+        if let Err (why) = write (&record, self.producer.clone (), &self.config).await {
+            error!("Could not record saga {}, its commands are not sent and will time out: {:?}", record.id, why);
+            return;
+        }
+        if record.status == SagaStatus::Finished {
+            info!("Saga {} finished: {:?}", record.id, record.saga);
+        }
+
+        // the commands do not expire, a late outcome is given to the saga after its timeout
+        for command in &commands {
+            if let Err (why) = commands::send (command, &Expiry::default (), &record.correlation_id, &record.user_id, self.producer.clone (), &self.config).await {
+                warn!("Could not send command {} of saga {}, it will time out: {:?}", command.id (), record.id, why);
+            }
+        }
+    }
+}
+
+/// writes the saga's record to the sagas topic, keyed by the saga id
+async fn write<S: Saga> (record: &SagaRecord<S>, producer: Producer, config: &Config) -> Result<(), ApiError> {
+    let envelope = Envelope {
+        user_id: Some (record.user_id.clone ()),
+        tenant_id: Some (record.tenant.clone ()),
+        ..Envelope::new ("SagaRecord", config, &record.correlation_id)
+    };
+    state_topic::write (&config.sagas_topic, &record.id.to_string (), record, envelope, producer).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands_schema::UpdateOperation;
+    use crate::config::tests::config;
+    use crate::events_schema::Rejection;
+    use crate::inputs_schema::OperationType;
+    use crate::number::Number;
+
+    /// adds to a value, and records the outcomes it is given
+    #[derive(Clone, Debug, Default, Deserialize, Serialize)]
+    struct Adding {
+        value_id: Uuid,
+        outcomes: Vec<String>,
+    }
+
+    impl Saga for Adding {
+        fn start (&mut self, tenant: &str) -> Vec<Command> {
+            vec![Command::UpdateValue {id: Uuid::new_v4 (),
+                                       tenant: String::from (tenant),
+                                       data: UpdateOperation {value_id: self.value_id, operation: OperationType::ADD, value: Number::Integer (1)}}]
+        }
+
+        fn react (&mut self, _tenant: &str, _command_id: Uuid, outcome: Outcome) -> Vec<Command> {
+            self.outcomes.push (String::from (outcome.name ()));
+            Vec::new ()
+        }
+
+        fn is_finished (&self) -> bool {
+            !self.outcomes.is_empty ()
+        }
+    }
+
+    /// a process manager whose writes to the sagas topic fail quickly, the sagas are still updated in memory
+    fn manager () -> ProcessManager<Adding> {
+        let config = Arc::new (config (&["KAFKA_MESSAGE_TIMEOUT_MS=100"]));
+        ProcessManager { producer: producer::init (&config), config, sagas: init (), waiting: HashMap::new () }
+    }
+
+    async fn saga (manager: &ProcessManager<Adding>, id: Uuid) -> SagaRecord<Adding> {
+        manager.sagas.lock ().await.get (&id).cloned ().unwrap ()
+    }
+
+    fn rejected (command_id: Uuid) -> Event {
+        Event::CommandRejected {id: Uuid::new_v4 (),
+                                parent: command_id,
+                                tenant: String::from ("acme"),
+                                data: Rejection {value_id: Uuid::new_v4 (), command: String::from ("UpdateValue"), reason: String::from ("nope")}}
+    }
+
+    #[tokio::test]
+    async fn advances_a_saga_with_the_outcomes_of_its_commands () {
+        let mut manager = manager ();
+        let record = SagaRecord::new ("acme", "alice", "correlation", Adding::default ());
+        let id = record.id;
+
+        manager.requested (record).await;
+        let started = saga (&manager, id).await;
+        assert_eq!(started.status, SagaStatus::Running);
+        assert_eq!(started.pending.len (), 1);
+        assert!(started.deadline.is_some ());
+        assert_eq!(manager.waiting.get (&started.pending[0]), Some (&id));
+
+        manager.outcome (rejected (Uuid::new_v4 ())).await;
+        assert_eq!(saga (&manager, id).await.status, SagaStatus::Running);
+
+        manager.outcome (rejected (started.pending[0])).await;
+        let finished = saga (&manager, id).await;
+        assert_eq!(finished.status, SagaStatus::Finished);
+        assert!(finished.pending.is_empty ());
+        assert_eq!(finished.deadline, None);
+        assert_eq!(finished.saga.outcomes, vec!["rejected"]);
+        assert!(manager.waiting.is_empty ());
+    }
+
+    #[tokio::test]
+    async fn times_out_the_commands_past_their_deadline () {
+        let mut manager = manager ();
+        let record = SagaRecord::new ("acme", "alice", "correlation", Adding::default ());
+        let id = record.id;
+        manager.requested (record).await;
+
+        manager.time_out ().await;
+        assert_eq!(saga (&manager, id).await.saga.outcomes.len (), 0);
+
+        let command_id = {
+            let mut sagas = manager.sagas.lock ().await;
+            let record = sagas.get_mut (&id).unwrap ();
+            record.deadline = Some (Utc::now () - chrono::Duration::seconds (1));
+            record.pending[0]
+        };
+        manager.time_out ().await;
+        let timed_out = saga (&manager, id).await;
+        assert_eq!(timed_out.saga.outcomes, vec!["timed_out"]);
+        assert!(timed_out.pending.is_empty ());
+        assert_eq!(timed_out.timed_out, vec![command_id]);
+        assert_eq!(timed_out.deadline, None);
+        assert_eq!(timed_out.status, SagaStatus::Finished);
+
+        // the late outcome is still given to the saga
+        manager.outcome (rejected (command_id)).await;
+        let late = saga (&manager, id).await;
+        assert_eq!(late.saga.outcomes, vec!["timed_out", "rejected"]);
+        assert!(late.timed_out.is_empty ());
+    }
+
+    #[tokio::test]
+    async fn restores_the_commands_waiting_for_their_outcome () {
+        let mut manager = manager ();
+        let (pending, timed_out) = (Uuid::new_v4 (), Uuid::new_v4 ());
+        let mut first = SagaRecord::new ("acme", "alice", "correlation", Adding::default ());
+        first.status = SagaStatus::Running;
+        first.pending = vec![pending];
+        let mut second = SagaRecord::new ("acme", "alice", "correlation", Adding::default ());
+        second.status = SagaStatus::Running;
+        second.timed_out = vec![timed_out];
+        let requested = SagaRecord::new ("acme", "alice", "correlation", Adding::default ());
+        let (first_id, second_id, requested_id) = (first.id, second.id, requested.id);
+
+        // the older record of the first saga is replaced by the last one
+        let older = SagaRecord { pending: Vec::new (), ..first.clone () };
+        manager.load (vec![older, first, second, requested]).await;
+        assert_eq!(manager.waiting.get (&pending), Some (&first_id));
+        assert_eq!(manager.waiting.get (&timed_out), Some (&second_id));
+
+        manager.outcome (rejected (timed_out)).await;
+        assert_eq!(saga (&manager, second_id).await.saga.outcomes, vec!["rejected"]);
+
+        manager.resume ().await;
+        assert_eq!(saga (&manager, requested_id).await.status, SagaStatus::Running);
+        assert_eq!(saga (&manager, first_id).await.pending, vec![pending]);
+    }
+}
@@ -1,23 +1,29 @@
 use crate::config::{Config};
+use crate::context::CustomContext;
+use crate::context;
 use log::info;
-use rdkafka::config::ClientConfig;
 use rdkafka::producer::FutureProducer;
 use rdkafka::util::get_rdkafka_version;
 use std::sync::Arc;
 use tokio::sync::Mutex;
 
 // allow having one producer shared across threads
-pub type Producer = Arc<Mutex<FutureProducer>>;
+pub type Producer = Arc<Mutex<FutureProducer<CustomContext>>>;
 
 pub fn init(config : &Config) -> Producer {
 
     let (_, version) = get_rdkafka_version();
     info!("librdkafka version: {}", version);
 
-    let producer: FutureProducer = ClientConfig::new()
-        .set("bootstrap.servers", &config.broker)
-        .set("message.timeout.ms", "5000")
-        .create()
+    let mut client_config = context::client_config (config);
+    client_config
+        .set("message.timeout.ms", config.message_timeout_ms.to_string ())
+        .set("statistics.interval.ms", config.statistics_interval_ms.to_string ()); // reports the queue and request metrics
+
+    context::apply_properties (&mut client_config, config, &config.kafka_producer);
+
+    let producer: FutureProducer<CustomContext> = client_config
+        .create_with_context(context::init ("producer", config, None))
         .expect("Producer creation error");
 
     Arc::new(Mutex::new(producer))
@@ -0,0 +1,107 @@
+use crate::aggregate::{AggregateEvent, Projection};
+use crate::config::Config;
+use crate::consumer;
+use crate::envelope::Envelope;
+use crate::health::Health;
+use crate::health;
+use crate::metrics;
+use crate::telemetry;
+use log::{debug, info, warn, error};
+use rdkafka::consumer::{CommitMode, Consumer};
+use rdkafka::message::Message;
+use rdkafka::topic_partition_list::{TopicPartitionList, Offset};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tracing::Instrument;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+/// applies the events of the events topics to the projection, in order, until the process stops,
+/// each projection consumes with its own `group_id`
+pub async fn run<P: Projection> (config : Arc<Config>, group_id: &str, projection: P, health: Health) {
+
+    let Config { health_max_view_lag, .. } = &*config;
+    let events_topics = config.events_topics ();
+    let _registration = health::register (&health, P::NAME, Some (*health_max_view_lag));
+    let consumer = consumer::init (&config, group_id, P::NAME, health.clone ());
+
+    let topics : Vec<&str> = events_topics.iter ().map (|topic| topic.as_str ()).collect ();
+    consumer.subscribe(&topics)
+        .expect("Can't subscribe to the specified topic");
+
+    // NOTE : uses last offset stored in kafka
+    let topic_map : HashMap<(String, i32), Offset> = events_topics.iter ()
+        .map (|topic| ((topic.clone (), 0), Offset::Stored))
+        .collect ();
+
+    let tpl : TopicPartitionList = TopicPartitionList::from_topic_map (&topic_map).unwrap ();
+    consumer.assign (&tpl).expect ("Could not set topic partition list");
+    consumer::confirm_assignment (&consumer, &tpl, &config, &health, P::NAME).await;
+
+    loop {
+
+        match consumer.recv().await {
+            // NOTE: librdkafka recovers from broker errors on its own, only fatal errors stop the consumer
+            Err(why) => match consumer.client().fatal_error() {
+                Some ((code, reason)) => panic!("Fatal error reading from {:?} : {:?} {}", &tpl, code, reason),
+                None => warn!("Failed to read message from {:?} : {}", &tpl, why)
+            },
+            Ok(m) => {
+
+                match m.payload_view::<str>() {
+                    None => warn!("Empty payload"),
+                    Some(Ok(payload)) => {
+
+                        debug!("payload: {}", payload);
+
+                        // TODO : read avro
+                        match serde_json::from_str::<P::Event>(payload) {
+                            Ok (event) => {
+                                info!("Received event: {:#?}, partition: {}, offset: {}, timestamp: {:?}", event, m.partition(), m.offset(), m.timestamp());
+
+                                metrics::EVENTS_APPLIED.with_label_values (&[event.name ()]).inc ();
+
+                                let correlation_id = match Envelope::from_headers (m.headers ()) {
+                                    Ok (envelope) => {
+                                        debug!("Event envelope: {:?}", envelope);
+                                        envelope.correlation_id
+                                    },
+                                    // events written before envelopes were introduced have no headers
+                                    Err (why) => {
+                                        warn!("Event {} without valid envelope: {}", event.id (), why);
+                                        event.parent ().to_string ()
+                                    }
+                                };
+                                let span = tracing::info_span!("apply_event",
+                                                               event = event.name (),
+                                                               event_id = %event.id (),
+                                                               aggregate_id = %event.aggregate_id (),
+                                                               correlation_id = %correlation_id);
+                                span.set_parent (telemetry::extract (m.headers ()));
+
+                                projection.apply (event).instrument (span).await;
+
+                            },
+                            Err (why) => {
+                                error!("Could not deserialize : {:?}", why);
+                                metrics::DESERIALIZATION_ERRORS.with_label_values (&[P::NAME]).inc ();
+                            }
+                        };
+
+                    },
+                    Some(Err(e)) => {
+                        error!("Error while deserializing payload: {:?}", e);
+                        metrics::DESERIALIZATION_ERRORS.with_label_values (&[P::NAME]).inc ();
+                    }
+                };
+
+                match consumer.commit_message(&m, CommitMode::Async) {
+                    Err(why) => error!("Failed to commit message offset: {}", why),
+                    Ok (_) => debug!("Commited message offset: {}", m.offset ())
+                };
+
+            }
+        };
+
+    }
+
+}
@@ -1,27 +1,167 @@
+use crate::auth::Principal;
 use crate::commands_schema::{Value};
-use crate::db::Db;
+use crate::db::{Db, Record};
 use crate::db;
+use crate::errors::ApiError;
+use crate::group_view::Groups;
+use crate::group_view;
+use crate::process_manager;
+use crate::scheduler::Schedules;
+use crate::scheduler;
+use crate::transfer::{TransferView, Transfers};
+use crate::view_client::ViewClient;
+use crate::view_client;
 use log::info;
-use std::convert::Infallible;
+use serde::Deserialize;
 use uuid::Uuid;
+use warp::http::{HeaderMap, Response};
+use hyper::Body;
+use warp::path::FullPath;
 
 pub async fn get_value(
     value_id: Uuid,
+    principal: Principal,
     db: Db
-) -> Result<impl warp::Reply, Infallible> {
-
-    info!("Querying value id {} ", value_id);
-
-    match db::get (&db, &value_id).await {
-        None => Ok(warp::reply::with_status(warp::reply::json (&format!("No value with id {} exists", &value_id)),
-                                            warp::http::StatusCode::NO_CONTENT)),
-        Some (value) => {
-            let body = Value {value_id : value_id,
-                              value : value};
-            Ok(warp::reply::with_status(warp::reply::json(&body),
-                                        warp::http::StatusCode::ACCEPTED))
+) -> Result<impl warp::Reply, warp::Rejection> {
+
+    let body = lookup (&principal, &db, value_id).await
+        .map_err (warp::reject::custom)?;
+    Ok(warp::reply::json(&body))
+}
+
+/// GET /transfers/:id, from the process manager of this process
+pub async fn get_transfer(
+    transfer_id: Uuid,
+    principal: Principal,
+    transfers: Transfers
+) -> Result<impl warp::Reply, warp::Rejection> {
+
+    info!("Querying transfer id {} for {} of tenant {}", transfer_id, principal.subject, principal.tenant ());
+
+    // transfers of other tenants are reported as missing
+    match process_manager::get (&transfers, principal.tenant (), &transfer_id).await {
+        None => Err(warp::reject::custom (ApiError::NotFound (format!("No transfer with id {} exists", &transfer_id)))),
+        Some (record) => Ok(warp::reply::json(&TransferView::from (record)))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ValueQuery {
+    /// only the values with this tag, as `key:value`
+    pub tag: Option<String>,
+}
+
+/// GET /values, the tenant's values ordered by id
+pub async fn get_values(
+    principal: Principal,
+    query: ValueQuery,
+    db: Db
+) -> Result<impl warp::Reply, warp::Rejection> {
+
+    info!("Querying values {:?} for {} of tenant {}", query, principal.subject, principal.tenant ());
+
+    let tag = match &query.tag {
+        None => None,
+        Some (tag) => match tag.split_once (':') {
+            Some (tag) => Some (tag),
+            None => return Err (warp::reject::custom (ApiError::InvalidBody (format!("tag must be key:value, got {}", tag))))
         }
+    };
+
+    let values : Vec<Value> = db::list (&db, principal.tenant ()).await.into_iter ()
+        .filter (|(_, record)| tag.is_none_or (|(key, value)| record.labels.tags.get (key).map (String::as_str) == Some (value)))
+        .map (|(value_id, record)| to_value (value_id, record))
+        .collect ();
+    Ok(warp::reply::json(&values))
+}
+
+/// GET /values/by-name/:name
+pub async fn get_value_by_name(
+    name: String,
+    principal: Principal,
+    db: Db
+) -> Result<impl warp::Reply, warp::Rejection> {
+
+    info!("Querying value named {} for {} of tenant {}", name, principal.subject, principal.tenant ());
 
+    match db::find_by_name (&db, principal.tenant (), &name).await {
+        None => Err(warp::reject::custom (ApiError::NotFound (format!("No value named {} exists", &name)))),
+        Some ((value_id, record)) => Ok(warp::reply::json(&to_value (value_id, record)))
     }
+}
+
+/// GET /groups/:id, from the group view of this process
+pub async fn get_group(
+    group_id: String,
+    principal: Principal,
+    groups: Groups
+) -> Result<impl warp::Reply, warp::Rejection> {
+
+    info!("Querying group {} for {} of tenant {}", group_id, principal.subject, principal.tenant ());
 
+    match group_view::get (&groups, principal.tenant (), &group_id).await {
+        None => Err(warp::reject::custom (ApiError::NotFound (format!("No value is in group {}", &group_id)))),
+        Some (group) => Ok(warp::reply::json(&group))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ScheduleQuery {
+    /// only the schedules of this value
+    pub value_id: Option<Uuid>,
+}
+
+/// GET /schedules, from the scheduler of this process
+pub async fn get_schedules(
+    principal: Principal,
+    query: ScheduleQuery,
+    schedules: Schedules
+) -> Result<impl warp::Reply, warp::Rejection> {
+
+    info!("Querying schedules {:?} for {} of tenant {}", query, principal.subject, principal.tenant ());
+
+    Ok(warp::reply::json(&scheduler::list (&schedules, principal.tenant (), query.value_id).await))
+}
+
+/// the value in the local view, shared by the REST and gRPC APIs
+pub async fn lookup (principal: &Principal, db: &Db, value_id: Uuid) -> Result<Value, ApiError> {
+
+    info!("Querying value id {} for {} of tenant {}", value_id, principal.subject, principal.tenant ());
+
+    // values of other tenants are reported as missing
+    match db::get (db, principal.tenant (), &value_id).await {
+        None => Err (ApiError::NotFound (format!("No value with id {} exists", &value_id))),
+        Some (record) => Ok (to_value (value_id, record))
+    }
+}
+
+fn to_value (value_id: Uuid, record: Record) -> Value {
+    Value {value_id,
+           value: record.value,
+           value_type: record.value_type,
+           constraints: None,
+           labels: record.labels}
+}
+
+/// relays a query to the materialized view service, used when this process has no local view
+pub async fn get_remote(
+    principal: Principal,
+    path: FullPath,
+    query: String,
+    headers: HeaderMap,
+    client: ViewClient,
+    view_url: String
+) -> Result<Response<Body>, warp::Rejection> {
+
+    let path_and_query = match query.is_empty () {
+        true => String::from (path.as_str ()),
+        false => format!("{}?{}", path.as_str (), query)
+    };
+
+    info!("Forwarding query {} for {} to view service {}", path_and_query, principal.subject, view_url);
+
+    match view_client::get (&client, &view_url, &path_and_query, &headers).await {
+        Ok (response) => Ok (response),
+        Err (why) => Err (warp::reject::custom (ApiError::BadGateway (why)))
+    }
 }
@@ -0,0 +1,179 @@
+use crate::auth::Principal;
+use crate::config::Config;
+use crate::errors::ApiError;
+use crate::metrics;
+use futures::future;
+use log::{debug, info};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use warp::Filter;
+
+/// at most this many clients are tracked, the least recently seen are forgotten beyond
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+/// clients left after a pruning, so that it runs at most once per 1000 new clients
+const PRUNED_CLIENTS: usize = MAX_TRACKED_CLIENTS * 9 / 10;
+
+/// token bucket of one client
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// per client token buckets, disabled when the rate is 0
+#[derive(Clone)]
+pub struct RateLimiter {
+    /// tokens added per second
+    rate: f64,
+    /// size of the bucket, the requests a client can send at once
+    burst: f64,
+    buckets: Arc<Mutex<HashMap<String, Bucket>>>,
+}
+
+impl RateLimiter {
+    pub fn new (rate: f64, burst: f64) -> RateLimiter {
+        RateLimiter { rate, burst, buckets: Arc::new (Mutex::new (HashMap::new ())) }
+    }
+
+    pub fn is_enabled (&self) -> bool {
+        self.rate > 0.0
+    }
+
+    /// takes a token from the client's bucket at `now`, or tells how long until the next one
+    pub fn check (&self, client: &str, now: Instant) -> Result<(), Duration> {
+        if !self.is_enabled () {
+            return Ok (());
+        }
+
+        let mut buckets = self.buckets.lock ().expect ("Rate limiter lock poisoned");
+
+        if buckets.len () >= MAX_TRACKED_CLIENTS && !buckets.contains_key (client) {
+            self.prune (&mut buckets, now);
+        }
+
+        let bucket = buckets.entry (String::from (client))
+            .or_insert (Bucket { tokens: self.burst, updated: now });
+        bucket.tokens = (bucket.tokens + now.duration_since (bucket.updated).as_secs_f64 () * self.rate).min (self.burst);
+        bucket.updated = now;
+
+        match bucket.tokens >= 1.0 {
+            true => {
+                bucket.tokens -= 1.0;
+                Ok (())
+            },
+            false => Err (Duration::from_secs_f64 ((1.0 - bucket.tokens) / self.rate))
+        }
+    }
+
+    /// forgets the clients whose bucket refilled, then the least recently seen ones,
+    /// which start over with a full bucket
+    fn prune (&self, buckets: &mut HashMap<String, Bucket>, now: Instant) {
+        let (rate, burst) = (self.rate, self.burst);
+        buckets.retain (|_, bucket| bucket.tokens + now.duration_since (bucket.updated).as_secs_f64 () * rate < burst);
+
+        if buckets.len () > PRUNED_CLIENTS {
+            let mut seen: Vec<(Instant, String)> = buckets.iter ()
+                .map (|(client, bucket)| (bucket.updated, client.clone ()))
+                .collect ();
+            seen.sort_unstable ();
+            let excess = buckets.len () - PRUNED_CLIENTS;
+            for (_, client) in seen.into_iter ().take (excess) {
+                buckets.remove (&client);
+            }
+        }
+        debug!("Pruned the rate limiter buckets, {} clients tracked", buckets.len ());
+    }
+}
+
+pub fn init (config: &Config) -> RateLimiter {
+    let limiter = RateLimiter::new (config.rate_limit_per_second, config.rate_limit_burst);
+    match limiter.is_enabled () {
+        true => info!("Rate limiting commands to {}/s per client, bursts of {}", config.rate_limit_per_second, config.rate_limit_burst),
+        false => info!("Rate limiting is disabled")
+    };
+    limiter
+}
+
+/// limits the requests of the principals extracted by `principals`, per subject,
+/// or per remote address for anonymous requests
+pub fn limited<F> (
+    principals: F,
+    limiter: RateLimiter,
+    route: &'static str
+) -> impl Filter<Extract = (Principal,), Error = warp::Rejection> + Clone
+where F: Filter<Extract = (Principal,), Error = warp::Rejection> + Clone {
+    principals
+        .and (warp::addr::remote ())
+        .and_then (move |principal: Principal, addr: Option<SocketAddr>| {
+            future::ready (check (&limiter, &principal, addr, route).map (|_| principal).map_err (warp::reject::custom))
+        })
+}
+
+/// takes a token from the bucket of the principal, or of the remote address for anonymous requests
+pub fn check (limiter: &RateLimiter, principal: &Principal, addr: Option<SocketAddr>, route: &str) -> Result<(), ApiError> {
+    let client = match (principal.subject.as_str (), addr) {
+        ("anonymous", Some (addr)) => format!("ip:{}", addr.ip ()),
+        (subject, _) => format!("subject:{}", subject)
+    };
+
+    limiter.check (&client, Instant::now ()).map_err (|retry_after| {
+        debug!("Rate limited {} on {}, retry after {:?}", client, route, retry_after);
+        metrics::HTTP_RATE_LIMITED.with_label_values (&[route]).inc ();
+        ApiError::TooManyRequests { retry_after }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refills_the_bucket_over_time () {
+        let limiter = RateLimiter::new (10.0, 2.0);
+        let start = Instant::now ();
+        assert!(limiter.check ("client", start).is_ok ());
+        assert!(limiter.check ("client", start).is_ok ());
+        assert_eq!(limiter.check ("client", start), Err (Duration::from_millis (100)));
+        assert_eq!(limiter.check ("client", start + Duration::from_millis (40)), Err (Duration::from_millis (60)));
+        assert!(limiter.check ("other", start).is_ok ());
+
+        assert!(limiter.check ("client", start + Duration::from_millis (150)).is_ok ());
+        assert!(limiter.check ("client", start + Duration::from_millis (150)).is_err ());
+        assert!(limiter.check ("client", start + Duration::from_secs (10)).is_ok ());
+        assert!(limiter.check ("client", start + Duration::from_secs (10)).is_ok ());
+        assert!(limiter.check ("client", start + Duration::from_secs (10)).is_err ());
+    }
+
+    #[test]
+    fn is_disabled_with_a_rate_of_zero () {
+        let limiter = RateLimiter::new (0.0, 0.0);
+        let now = Instant::now ();
+        assert!((0..10).all (|_| limiter.check ("client", now).is_ok ()));
+        assert!(limiter.buckets.lock ().unwrap ().is_empty ());
+    }
+
+    #[test]
+    fn tracks_a_bounded_number_of_clients () {
+        let limiter = RateLimiter::new (0.001, 1.0);
+        let start = Instant::now ();
+        let at = |millis: usize| start + Duration::from_millis (millis as u64);
+        for client in 0..MAX_TRACKED_CLIENTS {
+            assert!(limiter.check (&client.to_string (), at (client)).is_ok ());
+        }
+        assert_eq!(limiter.buckets.lock ().unwrap ().len (), MAX_TRACKED_CLIENTS);
+
+        assert!(limiter.check ("last", at (MAX_TRACKED_CLIENTS)).is_ok ());
+        let buckets = limiter.buckets.lock ().unwrap ();
+        assert_eq!(buckets.len (), PRUNED_CLIENTS + 1);
+        assert!(!buckets.contains_key ("0"));
+        assert!(!buckets.contains_key (&(MAX_TRACKED_CLIENTS - PRUNED_CLIENTS - 1).to_string ()));
+        assert!(buckets.contains_key (&(MAX_TRACKED_CLIENTS - PRUNED_CLIENTS).to_string ()));
+        assert!(buckets.contains_key ("last"));
+        drop (buckets);
+        assert!(limiter.check ("last", at (MAX_TRACKED_CLIENTS)).is_err ());
+        assert!(limiter.check (&(MAX_TRACKED_CLIENTS - 1).to_string (), at (MAX_TRACKED_CLIENTS)).is_err ());
+    }
+}
@@ -0,0 +1,477 @@
+use crate::commands;
+use crate::commands_schema::{Command, UpdateOperation};
+use crate::config::Config;
+use crate::consumer::CustomConsumer;
+use crate::consumer;
+use crate::envelope::Envelope;
+use crate::errors::ApiError;
+use crate::health::Health;
+use crate::health;
+use crate::inputs_schema::{Expiry, OperationType, ScheduleInput};
+use crate::metrics;
+use crate::number::Number;
+use crate::producer::Producer;
+use crate::producer;
+use crate::state_topic;
+use chrono::{DateTime, Utc};
+use log::{debug, info, warn, error};
+use rdkafka::consumer::{CommitMode, Consumer};
+use rdkafka::message::Message;
+use rdkafka::topic_partition_list::{TopicPartitionList, Offset};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+/// how often the due schedules are looked for
+const TICK_INTERVAL: Duration = Duration::from_secs (1);
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ScheduleStatus {
+    Active,
+    /// a one-off schedule whose command was sent
+    Done,
+    Cancelled,
+}
+
+/// an UpdateValue command sent later, once or on every time of a cron expression
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema)]
+pub struct Schedule {
+    #[schema(value_type = String, format = Uuid)]
+    pub schedule_id: Uuid,
+    #[schema(value_type = String, format = Uuid)]
+    pub value_id: Uuid,
+    pub operation: OperationType,
+    pub value: Number,
+    /// cron expression of a recurring schedule, in UTC
+    pub cron: Option<String>,
+    /// when the command is sent next, none once the schedule is done or cancelled
+    pub next_run: Option<DateTime<Utc>>,
+    pub last_run: Option<DateTime<Utc>>,
+    /// commands sent so far
+    pub runs: u64,
+    pub status: ScheduleStatus,
+    pub created_at: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(ignore)]
+    pub owner: Option<Owner>,
+}
+
+/// who a schedule belongs to, written to the schedules topic but not returned by the API
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Owner {
+    pub tenant: String,
+    /// user who created the schedule, its commands are sent on its behalf
+    pub user_id: String,
+    pub correlation_id: String,
+}
+
+/// the messages of the schedules topic, keyed by `<tenant>/<schedule id>` so a tenant cannot compact away
+/// the schedules of another: the API writes the requests, the scheduler records the schedules after each change
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "action")]
+pub enum ScheduleMessage {
+    ScheduleRequested { tenant: String, schedule: Schedule },
+    CancelRequested { tenant: String, schedule_id: Uuid, user_id: String },
+    ScheduleRecorded { tenant: String, schedule: Schedule },
+}
+
+impl ScheduleMessage {
+    fn name (&self) -> &'static str {
+        match self {
+            ScheduleMessage::ScheduleRequested { .. } => "ScheduleRequested",
+            ScheduleMessage::CancelRequested { .. } => "CancelRequested",
+            ScheduleMessage::ScheduleRecorded { .. } => "ScheduleRecorded"
+        }
+    }
+
+    fn key (&self) -> String {
+        match self {
+            ScheduleMessage::ScheduleRequested { tenant, schedule } => format!("{}/{}", tenant, schedule.schedule_id),
+            ScheduleMessage::CancelRequested { tenant, schedule_id, .. } => format!("{}/{}", tenant, schedule_id),
+            ScheduleMessage::ScheduleRecorded { tenant, schedule } => format!("{}/{}", tenant, schedule.schedule_id)
+        }
+    }
+}
+
+/// the schedules of the scheduler of this process, by id
+pub type Schedules = Arc<Mutex<HashMap<Uuid, Schedule>>>;
+
+pub fn init () -> Schedules {
+    Arc::new (Mutex::new (HashMap::new ()))
+}
+
+/// the tenant's schedules, of one value when `value_id` is given, ordered by creation
+pub async fn list (schedules: &Schedules, tenant: &str, value_id: Option<Uuid>) -> Vec<Schedule> {
+    let schedules = schedules.lock ().await;
+    let mut listed : Vec<Schedule> = schedules.values ()
+        .filter (|schedule| schedule.owner.as_ref ().is_some_and (|owner| owner.tenant == tenant))
+        .filter (|schedule| value_id.is_none_or (|value_id| schedule.value_id == value_id))
+        .cloned ()
+        .map (|schedule| Schedule { owner: None, ..schedule })
+        .collect ();
+    listed.sort_by_key (|schedule| (schedule.created_at, schedule.schedule_id));
+    listed
+}
+
+/// names of the days of the week, from Sunday, 0 in Unix cron
+const DAYS: [&str; 7] = ["SUN", "MON", "TUE", "WED", "THU", "FRI", "SAT"];
+
+/// a parsed cron expression, the union of two schedules of the cron crate when it restricts
+/// both the day of month and the day of week: the cron crate runs on the days matching both,
+/// Unix cron on the days matching either
+pub struct Cron (Vec<cron::Schedule>);
+
+impl Cron {
+    /// the first time of the expression after `after`
+    pub fn next_after (&self, after: &DateTime<Utc>) -> Option<DateTime<Utc>> {
+        self.0.iter ()
+            .filter_map (|schedule| schedule.after (after).next ())
+            .min ()
+    }
+}
+
+/// the cron expression, five fields in UTC: minute, hour, day of month, month and day of week,
+/// the days of the week as in Unix cron, 0 or 7 for Sunday
+pub fn parse_cron (expression: &str) -> Result<Cron, String> {
+    let invalid = |why: String| format!("invalid cron expression {}: {}", expression, why);
+    match expression.split_whitespace ().collect::<Vec<&str>> ().as_slice () {
+        [minute, hour, day, month, weekday] => {
+            // the cron crate has a seconds field and numbers the days of the week from 1 for Sunday
+            let days_of_week = day_of_week (weekday).map_err (invalid)?;
+            let schedule = |day: &str, weekday: &str| cron::Schedule::from_str (&format!("0 {} {} {} {} {}", minute, hour, day, month, weekday))
+                .map_err (|why| invalid (why.to_string ()));
+            // as in Unix cron, the days match both fields when either starts with `*`
+            let restricted = |field: &str| !field.starts_with ('*') && field != "?";
+            match restricted (day) && restricted (weekday) {
+                true => Ok (Cron (vec![schedule (day, "*")?, schedule ("*", &days_of_week)?])),
+                false => Ok (Cron (vec![schedule (day, &days_of_week)?]))
+            }
+        },
+        _ => Err (invalid (String::from ("expected minute, hour, day of month, month and day of week")))
+    }
+}
+
+/// the Unix day of week field as a list of the days of the cron crate, 1 for Sunday to 7 for Saturday
+fn day_of_week (field: &str) -> Result<String, String> {
+    if field == "*" || field == "?" {
+        return Ok (String::from (field));
+    }
+    let mut days = [false; 7];
+    for item in field.split (',') {
+        let (range, step) = match item.split_once ('/') {
+            Some ((range, step)) => (range, step.parse::<usize> ().ok ().filter (|step| *step > 0)
+                                     .ok_or_else (|| format!("invalid step in day of week {}", item))?),
+            None => (item, 1)
+        };
+        let (first, last) = match range.split_once ('-') {
+            _ if range == "*" => (0, 6),
+            Some ((first, last)) => (weekday (first)?, weekday (last)?),
+            None if step > 1 => (weekday (range)?, 6),
+            None => (weekday (range)?, weekday (range)?)
+        };
+        if first > last {
+            return Err (format!("invalid range in day of week {}", item));
+        }
+        for day in (first..=last).step_by (step) {
+            days[day % 7] = true;
+        }
+    }
+    Ok (days.iter ().enumerate ()
+        .filter (|(_, selected)| **selected)
+        .map (|(day, _)| (day + 1).to_string ())
+        .collect::<Vec<String>> ()
+        .join (","))
+}
+
+/// 0 to 7 for Sunday to Sunday, or the name of the day, as in Unix cron
+fn weekday (day: &str) -> Result<usize, String> {
+    match DAYS.iter ().position (|name| name.eq_ignore_ascii_case (day)) {
+        Some (day) => Ok (day),
+        None => day.parse::<usize> ().ok ()
+            .filter (|day| *day <= 7)
+            .ok_or_else (|| format!("invalid day of week {}, expected 0 to 7 or SUN to SAT", day))
+    }
+}
+
+/// the first time of the cron expression after `after`
+pub fn next_run (cron: &str, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    parse_cron (cron).ok ().and_then (|schedule| schedule.next_after (&after))
+}
+
+/// when a schedule of the input is first due, fails on an invalid cron expression
+pub fn first_run (input: &ScheduleInput, now: DateTime<Utc>) -> Result<DateTime<Utc>, String> {
+    match (&input.at, &input.cron) {
+        (Some (at), _) => Ok (*at),
+        (None, Some (cron)) => parse_cron (cron)?.next_after (&now)
+            .ok_or_else (|| format!("cron expression {} has no future run", cron)),
+        (None, None) => Err (String::from ("exactly one of at and cron must be given"))
+    }
+}
+
+/// writes the message to the schedules topic, fails when Kafka does not acknowledge it
+pub async fn write (message: &ScheduleMessage, user_id: &str, correlation_id: &str, producer: Producer, config: &Config) -> Result<(), ApiError> {
+    let tenant = match message {
+        ScheduleMessage::ScheduleRequested { tenant, .. } => tenant,
+        ScheduleMessage::CancelRequested { tenant, .. } => tenant,
+        ScheduleMessage::ScheduleRecorded { tenant, .. } => tenant
+    };
+    let envelope = Envelope {
+        user_id: Some (String::from (user_id)),
+        tenant_id: Some (tenant.clone ()),
+        ..Envelope::new (message.name (), config, correlation_id)
+    };
+    state_topic::write (&config.schedules_topic, &message.key (), message, envelope, producer).await
+}
+
+/// sends the commands of the schedules when they are due, until the process stops:
+/// the schedules topic is read from the beginning to restore the schedules, then for the new requests,
+/// a schedule due while no scheduler ran is sent once when the scheduler starts
+pub async fn run (config: Arc<Config>, schedules: Schedules, health: Health) {
+
+    let _registration = health::register (&health, "scheduler", None);
+    let consumer = consumer::init (&config, &config.schedules_group_id, "scheduler", health.clone ());
+    let mut scheduler = Scheduler { producer: producer::init (&config), config, schedules };
+
+    let restored_until = scheduler.restore (&consumer).await;
+
+    let mut tpl = TopicPartitionList::new ();
+    tpl.add_partition_offset (&scheduler.config.schedules_topic, 0, Offset::Offset (restored_until))
+        .expect ("Could not set topic partition list");
+    consumer.assign (&tpl).expect ("Could not set topic partition list");
+    consumer::confirm_assignment (&consumer, &tpl, &scheduler.config, &health, "scheduler").await;
+
+    let mut ticks = tokio::time::interval (TICK_INTERVAL);
+
+    loop {
+        tokio::select! {
+            _ = ticks.tick () => scheduler.send_due ().await,
+            message = consumer.recv () => match message {
+                // NOTE: librdkafka recovers from broker errors on its own, only fatal errors stop the consumer
+                Err (why) => match consumer.client ().fatal_error () {
+                    Some ((code, reason)) => panic!("Fatal error reading from {:?} : {:?} {}", &tpl, code, reason),
+                    None => warn!("Failed to read message from {:?} : {}", &tpl, why)
+                },
+                Ok (m) => {
+                    if let Some (message) = state_topic::parse::<ScheduleMessage> (&m, "scheduler") {
+                        scheduler.requested (message).await;
+                    }
+
+                    match consumer.commit_message (&m, CommitMode::Async) {
+                        Err (why) => error!("Failed to commit message offset: {}", why),
+                        Ok (_) => debug!("Commited message offset: {}", m.offset ())
+                    };
+                }
+            }
+        }
+    }
+}
+
+struct Scheduler {
+    config: Arc<Config>,
+    schedules: Schedules,
+    producer: Producer,
+}
+
+impl Scheduler {
+
+    /// reads the schedules topic up to its end, returns the offset the live consumer continues from,
+    /// the cancellations not yet recorded are recorded
+    async fn restore (&mut self, consumer: &CustomConsumer) -> i64 {
+
+        let (messages, restored_until) = state_topic::restore::<ScheduleMessage> (consumer, &self.config.schedules_topic, &self.config, "scheduler").await;
+
+        let mut unrecorded = HashSet::new ();
+        {
+            let mut schedules = self.schedules.lock ().await;
+            for message in messages {
+                match message {
+                    ScheduleMessage::ScheduleRequested { schedule, .. } => {
+                        schedules.entry (schedule.schedule_id).or_insert (schedule);
+                    },
+                    ScheduleMessage::ScheduleRecorded { schedule, .. } => {
+                        unrecorded.remove (&schedule.schedule_id);
+                        schedules.insert (schedule.schedule_id, schedule);
+                    },
+                    ScheduleMessage::CancelRequested { tenant, schedule_id, .. } => {
+                        if let Some (schedule) = schedules.get_mut (&schedule_id).filter (|schedule| is_owned_by (schedule, &tenant)) {
+                            cancel (schedule);
+                            unrecorded.insert (schedule_id);
+                        }
+                    }
+                }
+            }
+            info!("Restored {} schedules", schedules.len ());
+        }
+
+        for schedule_id in unrecorded {
+            if let Some (schedule) = self.schedules.lock ().await.get (&schedule_id).cloned () {
+                self.record (&schedule).await;
+            }
+        }
+        restored_until
+    }
+
+    /// applies a request of the API, the recorded schedules are the scheduler's own
+    async fn requested (&mut self, message: ScheduleMessage) {
+        match message {
+            ScheduleMessage::ScheduleRequested { schedule, .. } => {
+                info!("Scheduled {:?}", schedule);
+                self.schedules.lock ().await.entry (schedule.schedule_id).or_insert (schedule);
+            },
+            ScheduleMessage::CancelRequested { tenant, schedule_id, user_id } => {
+                let cancelled = match self.schedules.lock ().await.get_mut (&schedule_id) {
+                    Some (schedule) if is_owned_by (schedule, &tenant) => {
+                        cancel (schedule);
+                        Some (schedule.clone ())
+                    },
+                    _ => None
+                };
+                match cancelled {
+                    Some (schedule) => {
+                        info!("Schedule {} cancelled by {}", schedule_id, user_id);
+                        self.record (&schedule).await;
+                    },
+                    None => warn!("{} of tenant {} cannot cancel the unknown schedule {}", user_id, tenant, schedule_id)
+                }
+            },
+            ScheduleMessage::ScheduleRecorded { .. } => ()
+        }
+    }
+
+    /// sends the commands of the due schedules, each schedule is recorded before its command is sent,
+    /// so a crash loses a run rather than sending it twice
+    async fn send_due (&mut self) {
+        let now = Utc::now ();
+        let due : Vec<Schedule> = self.schedules.lock ().await.values ()
+            .filter (|schedule| schedule.status == ScheduleStatus::Active && schedule.next_run.is_some_and (|next_run| next_run <= now))
+            .cloned ()
+            .collect ();
+
+        for mut schedule in due {
+            let owner = match schedule.owner.clone () {
+                Some (owner) => owner,
+                None => continue
+            };
+
+            schedule.runs += 1;
+            schedule.last_run = Some (now);
+            schedule.next_run = schedule.cron.as_deref ().and_then (|cron| next_run (cron, now));
+            if schedule.next_run.is_none () {
+                schedule.status = ScheduleStatus::Done;
+            }
+
+            // retried on the next tick
+            if !self.record (&schedule).await {
+                continue;
+            }
+            self.schedules.lock ().await.insert (schedule.schedule_id, schedule.clone ());
+
+            let command = Command::UpdateValue {id: Uuid::new_v4 (),
+                                                tenant: owner.tenant.clone (),
+                                                data: UpdateOperation {value_id: schedule.value_id,
+                                                                       operation: schedule.operation,
+                                                                       value: schedule.value.clone ()}};
+            info!("Sending run {} of schedule {}", schedule.runs, schedule.schedule_id);
+            let result = commands::send (&command, &Expiry::default (), &owner.correlation_id, &owner.user_id, self.producer.clone (), &self.config).await;
+            metrics::SCHEDULED_COMMANDS.with_label_values (&[if result.is_ok () { "sent" } else { "failed" }]).inc ();
+            if let Err (why) = result {
+                error!("Could not send run {} of schedule {}, it is skipped: {:?}", schedule.runs, schedule.schedule_id, why);
+            }
+        }
+    }
+
+    /// writes the schedule to the schedules topic, returns whether it was acknowledged
+    async fn record (&self, schedule: &Schedule) -> bool {
+        let owner = match &schedule.owner {
+            Some (owner) => owner,
+            None => return false
+        };
+        let message = ScheduleMessage::ScheduleRecorded { tenant: owner.tenant.clone (), schedule: schedule.clone () };
+        match write (&message, &owner.user_id, &owner.correlation_id, self.producer.clone (), &self.config).await {
+            Ok (()) => true,
+            Err (why) => {
+                error!("Could not record schedule {}: {:?}", schedule.schedule_id, why);
+                false
+            }
+        }
+    }
+}
+
+fn is_owned_by (schedule: &Schedule, tenant: &str) -> bool {
+    schedule.owner.as_ref ().is_some_and (|owner| owner.tenant == tenant)
+}
+
+fn cancel (schedule: &mut Schedule) {
+    schedule.status = ScheduleStatus::Cancelled;
+    schedule.next_run = None;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Datelike, TimeZone, Weekday};
+
+    /// a Saturday
+    fn saturday () -> DateTime<Utc> {
+        Utc.with_ymd_and_hms (2026, 10, 17, 12, 0, 0).unwrap ()
+    }
+
+    fn runs (cron: &str, runs: usize) -> Vec<DateTime<Utc>> {
+        let cron = parse_cron (cron).unwrap ();
+        let mut after = saturday ();
+        (0..runs).map (|_| {
+            after = cron.next_after (&after).unwrap ();
+            after
+        }).collect ()
+    }
+
+    fn weekdays (cron: &str, runs: usize) -> Vec<Weekday> {
+        self::runs (cron, runs).iter ().map (|run| run.weekday ()).collect ()
+    }
+
+    #[test]
+    fn numbers_the_days_of_week_from_sunday () {
+        use Weekday::*;
+        assert_eq!(weekdays ("0 9 * * 1-5", 5), vec![Mon, Tue, Wed, Thu, Fri]);
+        assert_eq!(weekdays ("0 0 * * 0", 2), vec![Sun, Sun]);
+        assert_eq!(weekdays ("0 0 * * 7", 1), vec![Sun]);
+        assert_eq!(weekdays ("0 0 * * 5-7", 3), vec![Sun, Fri, Sat]);
+        assert_eq!(weekdays ("0 0 * * MON-FRI", 5), vec![Mon, Tue, Wed, Thu, Fri]);
+        assert_eq!(weekdays ("0 0 * * */2", 4), vec![Sun, Tue, Thu, Sat]);
+        assert_eq!(weekdays ("0 0 * * 1,3", 2), vec![Mon, Wed]);
+        assert_eq!(weekdays ("0 0 * * *", 2), vec![Sun, Mon]);
+    }
+
+    #[test]
+    fn runs_on_the_days_of_month_or_of_week () {
+        let days = |cron| runs (cron, 4).iter ().map (|run| (run.month (), run.day ())).collect::<Vec<(u32, u32)>> ();
+        // Mondays and the first of the month
+        assert_eq!(days ("0 0 1 * MON"), vec![(10, 19), (10, 26), (11, 1), (11, 2)]);
+        assert_eq!(days ("0 0 1,20 * 0"), vec![(10, 18), (10, 20), (10, 25), (11, 1)]);
+        // a day field starting with `*` makes the days match both fields: the Mondays on the 1st, 11th, 21st or 31st
+        assert_eq!(days ("0 0 */10 * 1"), vec![(12, 21), (1, 11), (2, 1), (3, 1)]);
+        assert_eq!(days ("0 0 1 * *"), vec![(11, 1), (12, 1), (1, 1), (2, 1)]);
+    }
+
+    #[test]
+    fn rejects_invalid_expressions () {
+        for cron in ["0 0 * * 8", "0 0 * * 5-1", "0 0 * * */0", "0 0 * * FUNDAY", "0 0 * *", "0 0 0 * * * *", "61 0 * * *"] {
+            assert!(parse_cron (cron).is_err (), "{} should be invalid", cron);
+        }
+    }
+
+    #[test]
+    fn next_run_is_after_the_given_time () {
+        let after = saturday ();
+        assert_eq!(next_run ("0 9 * * 1-5", after), Some (Utc.with_ymd_and_hms (2026, 10, 19, 9, 0, 0).unwrap ()));
+        assert_eq!(next_run ("30 12 * * *", after), Some (Utc.with_ymd_and_hms (2026, 10, 17, 12, 30, 0).unwrap ()));
+        assert_eq!(next_run ("0 12 * * 6", after), Some (Utc.with_ymd_and_hms (2026, 10, 24, 12, 0, 0).unwrap ()));
+        assert_eq!(next_run ("not cron", after), None);
+    }
+}
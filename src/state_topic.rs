@@ -0,0 +1,103 @@
+use crate::config::Config;
+use crate::consumer::CustomConsumer;
+use crate::envelope::Envelope;
+use crate::errors::ApiError;
+use crate::metrics;
+use crate::producer::Producer;
+use log::{debug, info, warn, error};
+use rdkafka::consumer::Consumer;
+use rdkafka::message::{BorrowedMessage, Message};
+use rdkafka::producer::FutureRecord;
+use rdkafka::topic_partition_list::{TopicPartitionList, Offset};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::time::{Duration, Instant};
+
+/// wait between two attempts to read the end of the topic
+const RETRY_INTERVAL: Duration = Duration::from_secs (1);
+
+/// reads the records of a compacted topic from its beginning up to its current end,
+/// returns them in order with the offset to continue from, only partition 0 is read
+pub async fn restore<T: DeserializeOwned> (consumer: &CustomConsumer, topic: &str, config: &Config, component: &str) -> (Vec<T>, i64) {
+
+    let timeout = Duration::from_millis (config.message_timeout_ms);
+    let (low_watermark, high_watermark) = loop {
+        match tokio::task::block_in_place (|| consumer.fetch_watermarks (topic, 0, timeout)) {
+            Ok (watermarks) => break watermarks,
+            Err (why) => {
+                warn!("Could not read the end of {}, retrying: {}", topic, why);
+                tokio::time::sleep (RETRY_INTERVAL).await;
+            }
+        }
+    };
+
+    let mut records = Vec::new ();
+    if high_watermark > low_watermark {
+        let mut tpl = TopicPartitionList::new ();
+        tpl.add_partition_offset (topic, 0, Offset::Beginning).expect ("Could not set topic partition list");
+        consumer.assign (&tpl).expect ("Could not set topic partition list");
+
+        loop {
+            match consumer.recv ().await {
+                Err (why) => warn!("Failed to read message from {} : {}", topic, why),
+                Ok (m) => {
+                    records.extend (parse::<T> (&m, component));
+                    // compaction leaves gaps in the offsets
+                    if m.offset () + 1 >= high_watermark {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    info!("Read {} records from {}", records.len (), topic);
+    (records, high_watermark)
+}
+
+/// the message's JSON payload, `None` when it cannot be read
+pub fn parse<T: DeserializeOwned> (m: &BorrowedMessage, component: &str) -> Option<T> {
+    match m.payload_view::<str> () {
+        None => {
+            warn!("Empty payload in {} at offset {}", m.topic (), m.offset ());
+            None
+        },
+        Some (Ok (payload)) => match serde_json::from_str::<T> (payload) {
+            Ok (message) => Some (message),
+            Err (why) => {
+                error!("Could not deserialize {} at offset {}: {:?}", m.topic (), m.offset (), why);
+                metrics::DESERIALIZATION_ERRORS.with_label_values (&[component]).inc ();
+                None
+            }
+        },
+        Some (Err (why)) => {
+            error!("Error while deserializing payload: {:?}", why);
+            metrics::DESERIALIZATION_ERRORS.with_label_values (&[component]).inc ();
+            None
+        }
+    }
+}
+
+/// writes the record to the topic with its envelope in the headers,
+/// fails when Kafka does not acknowledge the record
+pub async fn write<T: Serialize> (topic: &str, key: &str, record: &T, envelope: Envelope, producer: Producer) -> Result<(), ApiError> {
+
+    let payload = serde_json::to_string (record).expect ("Could not serialize record");
+
+    let producer = producer.lock ().await;
+    let started = Instant::now ();
+    let result = producer.send (FutureRecord::to (topic)
+                                .payload (&payload)
+                                .key (key)
+                                .headers (envelope.to_headers ()),
+                                Duration::from_secs (0)).await;
+    metrics::record_send (topic, started, result.is_ok ());
+
+    match result {
+        Ok (_) => {
+            debug!("Wrote {} {} to topic {}", envelope.message_type, key, topic);
+            Ok (())
+        },
+        Err ((why, _)) => Err (why.into ())
+    }
+}
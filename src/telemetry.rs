@@ -0,0 +1,98 @@
+use crate::config::Config;
+use crate::envelope;
+use opentelemetry::global;
+use opentelemetry::propagation::Extractor;
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry::{Context, KeyValue};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::{self as sdktrace, TracerProvider};
+use opentelemetry_sdk::Resource;
+use rdkafka::message::{BorrowedHeaders, Headers, OwnedHeaders};
+use std::collections::HashMap;
+use std::fs::File;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::layer::SubscriberExt;
+
+/// installs the span exporter selected by `TRACING_EXPORTER`: `none`, `file` or `otlp`
+/// must be called from within the tokio runtime
+pub fn init (config: &Config) {
+
+    global::set_text_map_propagator (TraceContextPropagator::new ());
+
+    let trace_config = sdktrace::config ()
+        .with_resource (Resource::new (vec![KeyValue::new ("service.name", envelope::service_name (config))]));
+
+    let tracer = match config.tracing_exporter.as_str () {
+        // spans are still created so trace context is propagated to downstream services
+        "none" => install (TracerProvider::builder ().with_config (trace_config).build ()),
+        "file" => {
+            let file = File::create (&config.tracing_file)
+                .unwrap_or_else (|why| panic!("Could not create trace file {}: {}", &config.tracing_file, why));
+            let exporter = opentelemetry_stdout::SpanExporter::builder ()
+                .with_writer (file)
+                .build ();
+            install (TracerProvider::builder ()
+                     .with_simple_exporter (exporter)
+                     .with_config (trace_config)
+                     .build ())
+        },
+        "otlp" => opentelemetry_otlp::new_pipeline ()
+            .tracing ()
+            .with_exporter (opentelemetry_otlp::new_exporter ()
+                            .tonic ()
+                            .with_endpoint (&config.otlp_endpoint))
+            .with_trace_config (trace_config)
+            .install_batch (opentelemetry_sdk::runtime::Tokio)
+            .expect ("Could not install OTLP exporter"),
+        // validated when loading the config
+        other => panic!("Unknown TRACING_EXPORTER {}", other)
+    };
+
+    let subscriber = tracing_subscriber::registry ()
+        .with (tracing_opentelemetry::layer ().with_tracer (tracer));
+
+    tracing::subscriber::set_global_default (subscriber)
+        .expect ("Could not install tracing subscriber");
+}
+
+fn install (provider: TracerProvider) -> sdktrace::Tracer {
+    let tracer = provider.tracer ("type-kafka");
+    global::set_tracer_provider (provider);
+    tracer
+}
+
+/// adds the trace context of the current span to the message headers
+pub fn inject (headers: OwnedHeaders) -> OwnedHeaders {
+    let context = tracing::Span::current ().context ();
+    let mut carrier = HashMap::<String, String>::new ();
+
+    global::get_text_map_propagator (|propagator| propagator.inject_context (&context, &mut carrier));
+
+    carrier.iter ().fold (headers, |headers, (key, value)| envelope::add (headers, key, value))
+}
+
+/// reads the trace context propagated in the message headers
+pub fn extract (headers: Option<&BorrowedHeaders>) -> Context {
+    match headers {
+        None => Context::new (),
+        Some (headers) => global::get_text_map_propagator (|propagator| propagator.extract (&HeaderExtractor (headers)))
+    }
+}
+
+struct HeaderExtractor<'a> (&'a BorrowedHeaders);
+
+impl<'a> Extractor for HeaderExtractor<'a> {
+    fn get (&self, key: &str) -> Option<&str> {
+        self.0.iter ()
+            .find (|header| header.key == key)
+            .and_then (|header| header.value)
+            .and_then (|value| std::str::from_utf8 (value).ok ())
+    }
+
+    fn keys (&self) -> Vec<&str> {
+        self.0.iter ()
+            .map (|header| header.key)
+            .collect ()
+    }
+}
@@ -0,0 +1,286 @@
+use crate::commands_schema::{Command, UpdateOperation};
+use crate::inputs_schema::{OperationType, TransferInput};
+use crate::number::Number;
+use crate::process_manager::{Outcome, Saga, SagaRecord, Sagas};
+use chrono::{DateTime, Utc};
+use log::error;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+/// the transfers run by the process manager of this process
+pub type Transfers = Sagas<Transfer>;
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum TransferStatus {
+    /// subtracting the amount from `from`
+    Debiting,
+    /// adding the amount to `to`
+    Crediting,
+    Completed,
+    /// giving the amount back to `from`
+    Compensating,
+    /// the amount was given back to `from`
+    Compensated,
+    /// nothing was moved, or a compensation failed and the values need a manual fix
+    Failed,
+}
+
+/// moves an amount from one value to another: debits `from`, then credits `to`,
+/// the debit is refunded when the credit is rejected or times out
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Transfer {
+    from: Uuid,
+    to: Uuid,
+    amount: Number,
+    status: TransferStatus,
+    /// why the transfer was compensated or failed
+    reason: Option<String>,
+    debit: Option<Uuid>,
+    credit: Option<Uuid>,
+    refund: Option<Uuid>,
+    /// takes back a credit applied after it timed out and the debit was refunded
+    reversal: Option<Uuid>,
+}
+
+/// which of the transfer's commands a command is
+enum Step {
+    Debit,
+    Credit,
+    Refund,
+    Reversal,
+}
+
+impl Transfer {
+    pub fn new (input: TransferInput) -> Transfer {
+        Transfer {
+            from: input.from,
+            to: input.to,
+            amount: input.amount,
+            status: TransferStatus::Debiting,
+            reason: None,
+            debit: None,
+            credit: None,
+            refund: None,
+            reversal: None,
+        }
+    }
+
+    fn step (&self, command_id: Uuid) -> Option<Step> {
+        vec![(self.debit, Step::Debit), (self.credit, Step::Credit), (self.refund, Step::Refund), (self.reversal, Step::Reversal)]
+            .into_iter ()
+            .find (|(id, _)| *id == Some (command_id))
+            .map (|(_, step)| step)
+    }
+
+    fn fail (&mut self, reason: String) {
+        self.status = TransferStatus::Failed;
+        self.reason = Some (reason);
+    }
+
+    fn refund (&mut self, tenant: &str) -> Vec<Command> {
+        self.status = TransferStatus::Compensating;
+        let (id, command) = add (tenant, self.from, self.amount.clone ());
+        self.refund = Some (id);
+        vec![command]
+    }
+}
+
+impl Saga for Transfer {
+    fn start (&mut self, tenant: &str) -> Vec<Command> {
+        let (id, command) = add (tenant, self.from, self.amount.negate ());
+        self.debit = Some (id);
+        vec![command]
+    }
+
+    fn react (&mut self, tenant: &str, command_id: Uuid, outcome: Outcome) -> Vec<Command> {
+        let step = match self.step (command_id) {
+            Some (step) => step,
+            None => return Vec::new ()
+        };
+
+        match (step, outcome) {
+            (Step::Debit, Outcome::Applied) if self.status == TransferStatus::Debiting => {
+                self.status = TransferStatus::Crediting;
+                let (id, command) = add (tenant, self.to, self.amount.clone ());
+                self.credit = Some (id);
+                vec![command]
+            },
+            // the transfer already failed when the debit timed out
+            (Step::Debit, Outcome::Applied) => self.refund (tenant),
+            (Step::Debit, Outcome::Rejected (reason)) => {
+                self.fail (format!("debit of {} rejected: {}", self.from, reason));
+                Vec::new ()
+            },
+            (Step::Debit, Outcome::TimedOut) => {
+                self.fail (format!("debit of {} timed out", self.from));
+                Vec::new ()
+            },
+            (Step::Credit, Outcome::Applied) if self.status == TransferStatus::Crediting => {
+                self.status = TransferStatus::Completed;
+                Vec::new ()
+            },
+            // the debit is already refunded, the credit is taken back
+            (Step::Credit, Outcome::Applied) => {
+                let (id, command) = add (tenant, self.to, self.amount.negate ());
+                self.reversal = Some (id);
+                vec![command]
+            },
+            (Step::Credit, Outcome::Rejected (reason)) if self.status == TransferStatus::Crediting => {
+                self.reason = Some (format!("credit of {} rejected: {}", self.to, reason));
+                self.refund (tenant)
+            },
+            // the debit is already refunded since the credit timed out, nothing was credited
+            (Step::Credit, Outcome::Rejected (reason)) => {
+                self.reason = Some (format!("credit of {} rejected: {}", self.to, reason));
+                Vec::new ()
+            },
+            (Step::Credit, Outcome::TimedOut) if self.status == TransferStatus::Crediting => {
+                self.reason = Some (format!("credit of {} timed out", self.to));
+                self.refund (tenant)
+            },
+            (Step::Credit, Outcome::TimedOut) => Vec::new (),
+            (Step::Refund, Outcome::Applied) => {
+                self.status = TransferStatus::Compensated;
+                Vec::new ()
+            },
+            (Step::Refund, outcome) => {
+                error!("Refund of {} to {} failed: {:?}", self.amount, self.from, outcome);
+                self.fail (format!("refund of {} to {} failed: {:?}", self.amount, self.from, outcome));
+                Vec::new ()
+            },
+            (Step::Reversal, Outcome::Applied) => Vec::new (),
+            (Step::Reversal, outcome) => {
+                error!("Reversal of {} from {} failed: {:?}", self.amount, self.to, outcome);
+                self.fail (format!("reversal of {} from {} failed: {:?}", self.amount, self.to, outcome));
+                Vec::new ()
+            }
+        }
+    }
+
+    fn is_finished (&self) -> bool {
+        matches!(self.status, TransferStatus::Completed | TransferStatus::Compensated | TransferStatus::Failed)
+    }
+}
+
+/// an UpdateValue command adding `amount` to the value, with its id
+fn add (tenant: &str, value_id: Uuid, amount: Number) -> (Uuid, Command) {
+    let id = Uuid::new_v4 ();
+    (id, Command::UpdateValue {id,
+                               tenant: String::from (tenant),
+                               data: UpdateOperation {value_id,
+                                                      operation: OperationType::ADD,
+                                                      value: amount}})
+}
+
+/// a transfer as returned by GET /transfers/:id
+#[derive(Debug, Serialize, ToSchema)]
+pub struct TransferView {
+    #[schema(value_type = String, format = Uuid)]
+    transfer_id: Uuid,
+    #[schema(value_type = String, format = Uuid)]
+    from: Uuid,
+    #[schema(value_type = String, format = Uuid)]
+    to: Uuid,
+    amount: Number,
+    status: TransferStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+    updated_at: DateTime<Utc>,
+}
+
+impl From<SagaRecord<Transfer>> for TransferView {
+    fn from (record: SagaRecord<Transfer>) -> TransferView {
+        let Transfer { from, to, amount, status, reason, .. } = record.saga;
+        TransferView { transfer_id: record.id, from, to, amount, status, reason, updated_at: record.updated_at }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TENANT: &str = "default";
+
+    fn transfer () -> Transfer {
+        Transfer::new (TransferInput { from: Uuid::new_v4 (), to: Uuid::new_v4 (), amount: Number::Integer (10) })
+    }
+
+    /// the value and the amount the command adds to it
+    fn added (command: &Command) -> (Uuid, Number) {
+        match command {
+            Command::UpdateValue { data, .. } => (data.value_id, data.value.clone ()),
+            other => panic!("unexpected command {:?}", other)
+        }
+    }
+
+    /// a transfer whose debit was applied and whose credit was sent
+    fn crediting () -> Transfer {
+        let mut transfer = transfer ();
+        transfer.start (TENANT);
+        let debit = transfer.debit.unwrap ();
+        assert_eq!(transfer.react (TENANT, debit, Outcome::Applied).len (), 1);
+        assert_eq!(transfer.status, TransferStatus::Crediting);
+        transfer
+    }
+
+    #[test]
+    fn completes_when_the_credit_is_applied () {
+        let mut transfer = crediting ();
+        let credit = transfer.credit.unwrap ();
+        assert!(transfer.react (TENANT, credit, Outcome::Applied).is_empty ());
+        assert_eq!(transfer.status, TransferStatus::Completed);
+    }
+
+    #[test]
+    fn refunds_a_late_debit () {
+        let mut transfer = transfer ();
+        transfer.start (TENANT);
+        let debit = transfer.debit.unwrap ();
+        assert!(transfer.react (TENANT, debit, Outcome::TimedOut).is_empty ());
+        assert_eq!(transfer.status, TransferStatus::Failed);
+
+        let commands = transfer.react (TENANT, debit, Outcome::Applied);
+        assert_eq!(commands.iter ().map (added).collect::<Vec<_>> (), vec![(transfer.from, Number::Integer (10))]);
+        assert_eq!(transfer.status, TransferStatus::Compensating);
+        assert!(transfer.credit.is_none ());
+    }
+
+    #[test]
+    fn reverses_a_late_credit_applied () {
+        let mut transfer = crediting ();
+        let credit = transfer.credit.unwrap ();
+        let refund = transfer.react (TENANT, credit, Outcome::TimedOut);
+        assert_eq!(refund.iter ().map (added).collect::<Vec<_>> (), vec![(transfer.from, Number::Integer (10))]);
+
+        let reversal = transfer.react (TENANT, credit, Outcome::Applied);
+        assert_eq!(reversal.iter ().map (added).collect::<Vec<_>> (), vec![(transfer.to, Number::Integer (-10))]);
+        assert_eq!(transfer.status, TransferStatus::Compensating);
+    }
+
+    #[test]
+    fn does_not_refund_twice_for_a_late_credit_rejected () {
+        let mut transfer = crediting ();
+        let credit = transfer.credit.unwrap ();
+        assert_eq!(transfer.react (TENANT, credit, Outcome::TimedOut).len (), 1);
+        let refund = transfer.refund;
+
+        assert!(transfer.react (TENANT, credit, Outcome::Rejected (String::from ("too late"))).is_empty ());
+        assert_eq!(transfer.refund, refund);
+        assert_eq!(transfer.status, TransferStatus::Compensating);
+        assert!(transfer.reason.as_deref ().is_some_and (|reason| reason.contains ("too late")));
+
+        assert!(transfer.react (TENANT, refund.unwrap (), Outcome::Applied).is_empty ());
+        assert_eq!(transfer.status, TransferStatus::Compensated);
+    }
+
+    #[test]
+    fn refunds_a_credit_rejected_in_time () {
+        let mut transfer = crediting ();
+        let credit = transfer.credit.unwrap ();
+        let refund = transfer.react (TENANT, credit, Outcome::Rejected (String::from ("constraints")));
+        assert_eq!(refund.iter ().map (added).collect::<Vec<_>> (), vec![(transfer.from, Number::Integer (10))]);
+        assert_eq!(transfer.status, TransferStatus::Compensating);
+    }
+}
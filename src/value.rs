@@ -0,0 +1,210 @@
+use crate::aggregate::{Aggregate, AggregateCommand};
+use crate::commands_schema::{Command, UpdateOperation, Value};
+use crate::events_schema::{Event, Rejection};
+use crate::inputs_schema::Constraints;
+use crate::number::{Number, ValueType};
+use uuid::Uuid;
+
+/// a number created once and then added to or multiplied,
+/// `None` until it is created
+#[derive(Debug, Default)]
+pub struct ValueAggregate {
+    value: Option<Number>,
+    /// given at creation, the kind of the value and of the operands of its updates
+    value_type: ValueType,
+    /// given at creation, checked for every update
+    constraints: Constraints,
+    /// unique among the values of the tenant, given at creation or by a relabel
+    name: Option<String>,
+}
+
+impl Aggregate for ValueAggregate {
+    type Command = Command;
+    type Event = Event;
+
+    /// a tenant cannot update the values of other tenants, they do not exist for it,
+    /// a value is created and updated only within its constraints
+    fn handle (&self, command: &Command) -> Result<Vec<Event>, String> {
+        match (command, &self.value) {
+            (Command::CreateValue { data, .. }, Some (_)) => Err (format!("value with id {} already exists", data.value_id)),
+            (Command::UpdateValue { data, .. }, None) => Err (format!("value with id {} does not exist", data.value_id)),
+            (Command::RelabelValue { data, .. }, None) => Err (format!("value with id {} does not exist", data.value_id)),
+            (Command::CreateValue { id, tenant, data }, None) => {
+                // the event has the value as stored, rounded to its type
+                let value = data.value_type.validate ()
+                    .and_then (|_| data.labels.validate ())
+                    .and_then (|_| data.value_type.round (&data.value))
+                    .map_err (|why| format!("value with id {} cannot be created: {}", data.value_id, why))?;
+                if let Some (constraints) = &data.constraints {
+                    constraints.validate ()
+                        .and_then (|_| constraints.check (None, &value))
+                        .map_err (|why| format!("value with id {} breaks its constraints: {}", data.value_id, why))?;
+                }
+                Ok (vec![Event::ValueCreated {id: Uuid::new_v4 (),
+                                              parent: *id,
+                                              tenant: tenant.clone (),
+                                              data: Value {value, ..data.clone ()}}])
+            },
+            (Command::UpdateValue { id, tenant, data }, Some (current)) => {
+                let next = update (&self.value_type, current, data)
+                    .map_err (|why| format!("value with id {} cannot be updated: {}", data.value_id, why))?;
+                self.constraints.check (Some (current), &next)
+                    .map_err (|why| format!("value with id {} would break its constraints: {}", data.value_id, why))?;
+                Ok (vec![Event::ValueUpdated {id: Uuid::new_v4 (),
+                                              parent: *id,
+                                              tenant: tenant.clone (),
+                                              data: data.clone ()}])
+            },
+            (Command::RelabelValue { id, tenant, data }, Some (_)) => {
+                data.labels.validate ()
+                    .map_err (|why| format!("value with id {} cannot be relabeled: {}", data.value_id, why))?;
+                Ok (vec![Event::ValueRelabeled {id: Uuid::new_v4 (),
+                                                parent: *id,
+                                                tenant: tenant.clone (),
+                                                data: data.clone ()}])
+            }
+        }
+    }
+
+    fn apply (&mut self, event: &Event) {
+        match event {
+            Event::ValueCreated { data, .. } => {
+                self.value = Some (data.value.clone ());
+                self.value_type = data.value_type;
+                self.constraints = data.constraints.clone ().unwrap_or_default ();
+                self.name = data.labels.name.clone ();
+            },
+            Event::ValueUpdated { data, .. } => {
+                self.value = match &self.value {
+                    Some (current) => update (&self.value_type, current, data).ok ().or_else (|| Some (current.clone ())),
+                    None => None
+                };
+            },
+            Event::ValueRelabeled { data, .. } => self.name = data.labels.name.clone (),
+            Event::CommandRejected { .. } => ()
+        };
+    }
+
+    fn rejected (command: &Command, reason: String) -> Event {
+        Event::CommandRejected {id: Uuid::new_v4 (),
+                                parent: command.id (),
+                                tenant: String::from (command.tenant ()),
+                                data: Rejection {value_id: command.aggregate_id (),
+                                                 command: String::from (command.name ()),
+                                                 reason}}
+    }
+
+    fn unique_name (command: &Command) -> Option<&str> {
+        match command {
+            Command::CreateValue { data, .. } => data.labels.name.as_deref (),
+            Command::RelabelValue { data, .. } => data.labels.name.as_deref (),
+            Command::UpdateValue { .. } => None
+        }
+    }
+
+    fn name (&self) -> Option<&str> {
+        self.name.as_deref ()
+    }
+}
+
+/// the value after the operation, in the kind of the value, shared by the aggregate and the materialized view
+pub fn update (value_type: &ValueType, current: &Number, operation: &UpdateOperation) -> Result<Number, String> {
+    value_type.apply (current, operation.operation, &operation.value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inputs_schema::{Labels, OperationType};
+    use crate::number::NumberKind;
+    use crate::number::tests::integer;
+
+    const VALUE_ID: Uuid = Uuid::nil ();
+
+    fn create (value: Number, value_type: ValueType, constraints: Constraints) -> Command {
+        Command::CreateValue {id: Uuid::new_v4 (),
+                              tenant: String::from ("default"),
+                              data: Value {value_id: VALUE_ID, value, value_type, constraints: Some (constraints), labels: Labels::default ()}}
+    }
+
+    fn add (value: Number) -> Command {
+        Command::UpdateValue {id: Uuid::new_v4 (),
+                              tenant: String::from ("default"),
+                              data: UpdateOperation {value_id: VALUE_ID, operation: OperationType::ADD, value}}
+    }
+
+    /// the aggregate after the creation, which must be accepted
+    fn created (value: Number, value_type: ValueType, constraints: Constraints) -> ValueAggregate {
+        let mut aggregate = ValueAggregate::default ();
+        for event in aggregate.handle (&create (value, value_type, constraints)).unwrap () {
+            aggregate.apply (&event);
+        }
+        aggregate
+    }
+
+    fn rejection (aggregate: &ValueAggregate, command: &Command) -> String {
+        aggregate.handle (command).unwrap_err ()
+    }
+
+    #[test]
+    fn checks_the_minimum () {
+        let constraints = Constraints { min: Some (0.0), ..Constraints::default () };
+        assert!(rejection (&ValueAggregate::default (), &create (Number::Integer (-1), integer (), constraints.clone ())).contains ("below the minimum"));
+
+        let aggregate = created (Number::Integer (0), integer (), constraints);
+        assert!(rejection (&aggregate, &add (Number::Integer (-1))).contains ("below the minimum"));
+        assert!(aggregate.handle (&add (Number::Integer (1))).is_ok ());
+    }
+
+    #[test]
+    fn checks_the_maximum () {
+        let constraints = Constraints { max: Some (10.5), ..Constraints::default () };
+        assert!(rejection (&ValueAggregate::default (), &create (Number::Float (10.6), ValueType::default (), constraints.clone ())).contains ("above the maximum"));
+
+        let aggregate = created (Number::Float (10.0), ValueType::default (), constraints);
+        assert!(rejection (&aggregate, &add (Number::Float (0.6))).contains ("above the maximum"));
+        assert!(aggregate.handle (&add (Number::Float (0.5))).is_ok ());
+    }
+
+    #[test]
+    fn checks_that_the_value_is_not_negative () {
+        let constraints = Constraints { non_negative: true, ..Constraints::default () };
+        assert!(rejection (&ValueAggregate::default (), &create (Number::Integer (-1), integer (), constraints.clone ())).contains ("is negative"));
+
+        let aggregate = created (Number::Integer (5), integer (), constraints);
+        assert!(rejection (&aggregate, &add (Number::Integer (-6))).contains ("is negative"));
+        assert!(aggregate.handle (&add (Number::Integer (-5))).is_ok ());
+    }
+
+    #[test]
+    fn checks_that_the_value_is_an_integer () {
+        let constraints = Constraints { integer: true, ..Constraints::default () };
+        assert!(rejection (&ValueAggregate::default (), &create (Number::Float (1.5), ValueType::default (), constraints.clone ())).contains ("not an integer"));
+
+        let aggregate = created (Number::Float (1.0), ValueType::default (), constraints);
+        assert!(rejection (&aggregate, &add (Number::Float (0.5))).contains ("not an integer"));
+        assert!(aggregate.handle (&add (Number::Float (2.0))).is_ok ());
+    }
+
+    #[test]
+    fn checks_the_max_step () {
+        let constraints = Constraints { max_step: Some (5.0), ..Constraints::default () };
+        // there is no step at the creation
+        let aggregate = created (Number::Integer (100), integer (), constraints.clone ());
+        assert!(rejection (&aggregate, &add (Number::Integer (6))).contains ("more than the max step"));
+        assert!(rejection (&aggregate, &add (Number::Integer (-6))).contains ("more than the max step"));
+        assert!(aggregate.handle (&add (Number::Integer (-5))).is_ok ());
+
+        let invalid = Constraints { max_step: Some (0.0), ..Constraints::default () };
+        assert!(rejection (&ValueAggregate::default (), &create (Number::Integer (0), integer (), invalid)).contains ("max_step must be positive"));
+    }
+
+    #[test]
+    fn checks_the_constraints_after_rounding () {
+        let constraints = Constraints { max: Some (1.0), ..Constraints::default () };
+        let money = ValueType { kind: NumberKind::Decimal, scale: Some (1), ..ValueType::default () };
+        let aggregate = created (Number::Float (1.04), money, constraints);
+        assert_eq!(aggregate.value.as_ref ().map (|value| value.to_string ()), Some (String::from ("1.0")));
+        assert!(rejection (&aggregate, &add (Number::Float (0.06))).contains ("above the maximum"));
+    }
+}
@@ -0,0 +1,32 @@
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, HeaderMap, Request, Response, Uri};
+
+/// request headers relayed to the view service, so it authenticates the original caller and tenant
+const FORWARDED_HEADERS: [&str; 3] = ["authorization", "x-api-key", "x-tenant-id"];
+
+/// HTTP client for querying a remote materialized view service
+pub type ViewClient = Client<HttpConnector>;
+
+pub fn init () -> ViewClient {
+    Client::new ()
+}
+
+/// forwards a GET request for `path_and_query` to the view service at `base_url`
+pub async fn get (client: &ViewClient, base_url: &str, path_and_query: &str, headers: &HeaderMap) -> Result<Response<Body>, String> {
+
+    let uri = format!("{}{}", base_url.trim_end_matches('/'), path_and_query)
+        .parse::<Uri> ()
+        .map_err (|why| format!("invalid view service url {}: {}", base_url, why))?;
+
+    let mut request = Request::get (uri);
+    for name in FORWARDED_HEADERS {
+        if let Some (value) = headers.get (name) {
+            request = request.header (name, value);
+        }
+    }
+    let request = request.body (Body::empty ())
+        .map_err (|why| format!("invalid view service request: {}", why))?;
+
+    client.request (request).await
+        .map_err (|why| format!("view service unavailable: {}", why))
+}